@@ -0,0 +1,117 @@
+//! Microbenchmarks for the wire protocol, serializing to and parsing from memory so protocol
+//! changes can be measured without network noise.
+//!
+//! Run with `cargo bench --bench protocol`.
+
+use std::{hint::black_box, io::Cursor};
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use rust_server_benchmarks::protocol::{
+    Deserialize, Request, Response, Serialize, SleepDistribution, Status, Work,
+};
+
+/// One of each kind of work, with the payload sizes the echo benchmarks use.
+fn works() -> Vec<(&'static str, Work)> {
+    vec![
+        ("constant", Work::Constant),
+        ("busy", Work::Busy { amt: 1000 }),
+        ("sleep", Work::Sleep { micros: 10 }),
+        (
+            "random_sleep",
+            Work::RandomSleep {
+                mean_micros: 10,
+                dist: SleepDistribution::Lognormal,
+                seed: 7,
+            },
+        ),
+        ("echo_0", Work::Echo { bytes: 0 }),
+        ("echo_4k", Work::Echo { bytes: 4096 }),
+        ("echo_64k", Work::Echo { bytes: 65536 }),
+        ("matmul", Work::Matmul { dim: 16 }),
+        (
+            "mem_chase",
+            Work::MemChase {
+                size: 1 << 20,
+                hops: 100,
+            },
+        ),
+        (
+            "file_read",
+            Work::FileRead {
+                path_id: 0,
+                bytes: 4096,
+            },
+        ),
+    ]
+}
+
+fn request(work: Work, checksum: bool) -> Request {
+    Request::new(1_000_000, 7, work, checksum)
+}
+
+fn response(work: Work) -> Response {
+    Response {
+        client_send_time: 1_000_000,
+        req_id: 7,
+        queue_depth: 3,
+        server_process_ns: 500,
+        payload_len: work.payload_len(),
+        checksum: 0,
+        body: Vec::new(),
+        status: Status::Ok,
+    }
+}
+
+fn bench_request(c: &mut Criterion) {
+    let mut group = c.benchmark_group("request");
+    for (name, work) in works() {
+        let mut buf = Cursor::new(Vec::with_capacity(64));
+        group.bench_with_input(BenchmarkId::new("round_trip", name), &work, |b, &work| {
+            b.iter(|| {
+                buf.set_position(0);
+                request(black_box(work), false).serialize(&mut buf).unwrap();
+                buf.set_position(0);
+                black_box(Request::deserialize(&mut buf).unwrap())
+            })
+        });
+    }
+    group.bench_function("new_with_checksum", |b| {
+        b.iter(|| request(black_box(Work::Constant), true))
+    });
+    group.finish();
+}
+
+fn bench_response(c: &mut Criterion) {
+    let mut group = c.benchmark_group("response");
+    for (name, work) in works() {
+        let mut buf = Cursor::new(Vec::with_capacity(64 + work.payload_len() as usize));
+        group.bench_with_input(BenchmarkId::new("round_trip", name), &work, |b, &work| {
+            b.iter(|| {
+                buf.set_position(0);
+                response(black_box(work)).serialize(&mut buf).unwrap();
+                buf.set_position(0);
+                black_box(Response::deserialize(&mut buf).unwrap())
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_work(c: &mut Criterion) {
+    let mut group = c.benchmark_group("work");
+    for (name, work) in works() {
+        let mut buf = Cursor::new(Vec::with_capacity(32));
+        group.bench_with_input(BenchmarkId::new("round_trip", name), &work, |b, &work| {
+            b.iter(|| {
+                buf.set_position(0);
+                black_box(work).serialize(&mut buf).unwrap();
+                buf.set_position(0);
+                black_box(Work::deserialize(&mut buf).unwrap())
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_request, bench_response, bench_work);
+criterion_main!(benches);
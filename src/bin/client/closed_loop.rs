@@ -1,72 +1,470 @@
 use std::{
-    net::{SocketAddrV4, TcpStream},
-    sync::Arc,
+    collections::VecDeque,
+    io::{self, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        Arc, Barrier,
+        atomic::{AtomicUsize, Ordering},
+    },
     time::{Duration, Instant},
 };
 
+use crate::{
+    counters::{self, Counters, InFlight},
+    split::{client_share, seeded_rng},
+    stream::{Retry, Stream, Transport},
+    timestamp::{self, TimestampReader},
+};
+use crossbeam_channel::Sender;
+use rand::rngs::StdRng;
 use rust_server_benchmarks::{
+    buffers::BufferSizes,
     get_time,
-    protocol::{Deserialize, LatencyRecord, Request, Response, Serialize, Work},
+    mix::Mix,
+    protocol::{Deserialize, LatencyRecord, RampRecord, Request, Response, Serialize, Work},
+    proxy,
 };
+use rustls::ClientConfig;
+
+/// Starts the clients one at a time instead of all at once, to find the server's saturation point
+/// in a single run.
+pub struct Ramp {
+    /// How long each number of clients runs for before the next client starts. The run lasts
+    /// this long times the number of clients.
+    pub interval: Duration,
+
+    /// Where the latency records are also sent, tagged with the number of clients running when
+    /// their request was sent.
+    pub tx: Sender<RampRecord>,
+}
+
+/// Counts a failed request, and the rest of the pipeline (which won't be answered either), if `e`
+/// is a timeout or means the server reset or closed the connection. It returns whether it was one.
+fn record_failure(counters: &Counters, e: &io::Error, outstanding: &VecDeque<Outstanding>) -> bool {
+    let failed = 1 + outstanding.len();
+    if counters::is_timeout(e) {
+        counters.timeouts.fetch_add(failed, Ordering::SeqCst);
+        true
+    } else {
+        counters.record_error(e, failed)
+    }
+}
+
+/// A request waiting for a response.
+struct Outstanding<'a> {
+    req_id: u64,
+    work: Work,
+
+    /// Whether it was sent after the warmup.
+    warm: bool,
+
+    /// The checksum it was sent with.
+    checksum: u32,
+
+    /// The number of clients running when it was sent.
+    clients: usize,
+
+    /// The index of the connection it was sent on.
+    conn: usize,
+
+    /// Counts it as in flight until it is answered or given up on.
+    inflight: InFlight<'a>,
+}
 
 pub struct Config {
     /// The address of the server.
-    pub addr: SocketAddrV4,
+    pub addr: SocketAddr,
 
     /// The duration of time for which each client runs.
     pub runtime: Duration,
 
-    /// The work the server must do for the client.
-    pub work: Work,
+    /// How long requests are sent before the runtime starts. Requests sent during the warmup are
+    /// excluded from the latency records and request counts.
+    pub warmup: Duration,
+
+    /// The work the server must do for each request.
+    pub work: Mix,
 
     /// The number of clients that are concurrently run.
     pub num_clients: usize,
+
+    /// Skip timestamping and latency records, only counting completed requests.
+    pub throughput_only: bool,
+
+    /// Use kernel timestamps for when requests are sent and responses arrive.
+    pub kernel_timestamps: bool,
+
+    /// The number of unrecorded requests each client sends before recording
+    /// starts. Recording only starts once every client has connected and
+    /// finished its warmup requests.
+    pub warmup_requests: usize,
+
+    /// How long a client waits for a response before giving up on the server.
+    pub request_timeout: Option<Duration>,
+
+    /// Send a PROXY protocol header of this version at the start of each connection.
+    pub proxy_protocol: Option<proxy::Version>,
+
+    /// Connect over TLS with this configuration.
+    pub tls: Option<Arc<ClientConfig>>,
+
+    /// The transport to connect over.
+    pub transport: Transport,
+
+    /// The path of the server's socket, when connecting over a Unix domain socket.
+    pub uds_path: PathBuf,
+
+    /// The number of connections each client opens and sends its requests on in turn.
+    pub connections_per_client: usize,
+
+    /// The number of requests each client keeps outstanding. Responses are matched to requests
+    /// by order.
+    pub pipeline_depth: usize,
+
+    /// How refused connections are retried.
+    pub retry: Retry,
+
+    /// Seeds every random choice (sampled work, Poisson arrivals), so the run can be reproduced.
+    pub seed: u64,
+
+    /// Leave Nagle's algorithm enabled on connections instead of setting `TCP_NODELAY`.
+    pub nagle: bool,
+
+    /// The send and receive buffer sizes to request for every connection.
+    pub buffers: BufferSizes,
+
+    /// Send a checksum with every request and count responses that report it corrupted.
+    pub verify_checksums: bool,
+
+    /// The body every request carries, which the server must echo back unchanged. Empty for
+    /// requests without one.
+    pub payload: Arc<[u8]>,
+
+    /// Stop once the clients have collectively sent this many requests after the warmup, instead
+    /// of after `runtime`. Each client sends its share.
+    pub total_requests: Option<usize>,
+
+    /// Start the clients one at a time. The last client starts `runtime` minus one interval
+    /// after the first, and they all stop together.
+    pub ramp: Option<Ramp>,
 }
 
 impl Config {
-    /// Runs the closed loop request generator and returns the latency records
-    /// collected from all clients.
-    pub fn run(self) -> Vec<LatencyRecord> {
+    /// Runs the closed loop request generator, sending the latency records
+    /// collected from all clients to `tx`. It returns the number of requests sent.
+    pub fn run(self, tx: Sender<LatencyRecord>, counters: &Arc<Counters>) -> usize {
         let cfg = Arc::new(self);
+        let barrier = Arc::new(Barrier::new(cfg.num_clients));
+
+        // The number of clients currently running
+        let running = Arc::new(AtomicUsize::new(0));
+        let start = Instant::now();
 
         let handles = (0..cfg.num_clients)
-            .map(|_| {
+            .map(|id| {
                 let cfg_clone = cfg.clone();
-                std::thread::spawn(move || cfg_clone._run_client())
+                let tx = tx.clone();
+                let counters = counters.clone();
+                let barrier = barrier.clone();
+                let running = running.clone();
+                std::thread::spawn(move || {
+                    if let Some(ramp) = &cfg_clone.ramp {
+                        let client_start = start + ramp.interval * id as u32;
+                        std::thread::sleep(client_start.saturating_duration_since(Instant::now()));
+                    }
+                    running.fetch_add(1, Ordering::SeqCst);
+                    let sent = cfg_clone._run_client(id, tx, &counters, &barrier, &running);
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    sent
+                })
             })
             .collect::<Vec<_>>();
 
         handles
             .into_iter()
-            .map(|handle| handle.join().unwrap())
-            .flatten()
-            .collect::<Vec<_>>()
+            .enumerate()
+            .map(|(id, handle)| match handle.join().unwrap() {
+                Ok(sent) => sent,
+                Err(e) => {
+                    eprintln!("client {id} stopped: {e}");
+                    0
+                }
+            })
+            .sum()
     }
 
-    /// Runs an individual client.
-    fn _run_client(&self) -> Vec<LatencyRecord> {
+    /// Runs an individual client. It returns the number of requests sent, or an error if the
+    /// client couldn't go on. Requests failed by timeouts or the server closing the connection
+    /// are counted instead, and end the client early.
+    fn _run_client(
+        &self,
+        id: usize,
+        tx: Sender<LatencyRecord>,
+        counters: &Counters,
+        barrier: &Barrier,
+        running: &AtomicUsize,
+    ) -> io::Result<usize> {
+        // Connect to the server
+        let mut streams = Vec::with_capacity(self.connections_per_client);
+        for _ in 0..self.connections_per_client {
+            match Stream::connect(
+                self.addr,
+                &self.uds_path,
+                self.transport,
+                self.request_timeout,
+                self.proxy_protocol,
+                self.tls.as_ref(),
+                self.retry,
+                self.nagle,
+                self.buffers,
+            ) {
+                Ok((stream, connect_time)) => {
+                    counters.connection_opened(connect_time);
+                    streams.push(stream);
+                }
+                Err(e) => {
+                    // Still reach the barrier, so the other clients aren't stuck at it
+                    if self.warmup_requests > 0 {
+                        barrier.wait();
+                    }
+                    for _ in &streams {
+                        counters.connection_closed();
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut rng = seeded_rng(self.seed, 2 * id);
+
+        // The id of the next request sent by the client (on any connection)
+        let mut next_id = 0;
+
+        if self.warmup_requests > 0 {
+            let warmed_up = self._warm_up(&mut streams, &mut rng, &mut next_id, counters);
+
+            // Wait for every connection to be established and warmed up (even if this one gave
+            // up, so the others aren't stuck at the barrier)
+            barrier.wait();
+            if !matches!(warmed_up, Ok(true)) {
+                for _ in &streams {
+                    counters.connection_closed();
+                }
+                return warmed_up.map(|_| 0);
+            }
+        }
+
         let client_start = Instant::now();
 
-        // Connect to the server
-        let mut stream = TcpStream::connect(self.addr).unwrap();
-        stream.set_nodelay(true).unwrap();
+        // Ramped clients start one interval after each other, but all stop together
+        let runtime = match &self.ramp {
+            Some(ramp) => self.runtime.saturating_sub(ramp.interval * id as u32),
+            None => self.runtime,
+        };
+
+        let kernel_timestamps = self.kernel_timestamps && !self.throughput_only;
+        if kernel_timestamps {
+            for stream in &streams {
+                timestamp::enable(stream.tcp())?;
+            }
+        }
+
+        let mut requests_sent = 0;
+        let mut completed = 0;
+
+        // The number of requests this client may send after the warmup, and has sent so far
+        let share = self
+            .total_requests
+            .map(|total| client_share(total, self.num_clients, id));
+        let mut warm_sent = 0;
 
-        let mut latency_records = Vec::new();
+        // Every request that is waiting for a response, in the order they were sent
+        let mut outstanding = VecDeque::with_capacity(self.pipeline_depth);
 
-        while client_start.elapsed() < self.runtime {
-            // Serialize and send request
-            let req = Request {
-                send_time: get_time(),
-                work: self.work,
+        // The connection the next request is sent on
+        let mut next_conn = 0;
+
+        loop {
+            // Keep the pipeline full until the runtime is over (or the client's share of requests
+            // has been sent), then drain it
+            let elapsed = client_start.elapsed();
+            let warm = elapsed >= self.warmup;
+            let more = match share {
+                Some(share) => !warm || warm_sent < share,
+                None => elapsed < self.warmup + runtime,
+            };
+            if outstanding.len() < self.pipeline_depth && more {
+                // Serialize and send request
+                let work = self.work.sample(&mut rng);
+                let send_time = if self.throughput_only { 0 } else { get_time() };
+                let req = Request::new(send_time, next_id, work, self.verify_checksums)
+                    .with_body(self.payload.to_vec());
+                let checksum = req.checksum;
+                let stream = &mut streams[next_conn];
+                let inflight = counters.request_sent();
+                if let Err(e) = req.serialize(stream).and_then(|_| stream.flush()) {
+                    if !record_failure(counters, &e, &outstanding) {
+                        return Err(e);
+                    }
+                    requests_sent += warm as usize;
+                    requests_sent += outstanding.iter().filter(|request| request.warm).count();
+                    break;
+                }
+                outstanding.push_back(Outstanding {
+                    req_id: next_id,
+                    work,
+                    warm,
+                    checksum,
+                    clients: running.load(Ordering::SeqCst),
+                    conn: next_conn,
+                    inflight,
+                });
+                next_id += 1;
+                next_conn = (next_conn + 1) % streams.len();
+                warm_sent += warm as usize;
+                continue;
+            }
+
+            let Some(Outstanding {
+                req_id,
+                work,
+                warm,
+                checksum,
+                clients,
+                conn,
+                inflight,
+            }) = outstanding.pop_front()
+            else {
+                break;
             };
-            req.serialize(&mut stream).unwrap();
+            let stream = &mut streams[conn];
 
             // Wait for the response and update our latency records
-            let res = Response::deserialize(&mut stream).unwrap();
-            let lr = res.to_latency_record();
-            latency_records.push(lr);
+            if kernel_timestamps {
+                let mut reader = TimestampReader::new(stream.tcp());
+                let res = match Response::deserialize(&mut reader) {
+                    Ok(res) => res,
+                    Err(e) if record_failure(counters, &e, &outstanding) => {
+                        requests_sent += warm as usize;
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                };
+                res.verify_id(req_id)?;
+                res.verify(&work)?;
+                res.verify_body(&self.payload)?;
+                counters.verify_checksum(&res, checksum);
+
+                // Fall back to the userspace timestamps if the kernel's are missing (the send
+                // timestamp is always drained so it isn't mistaken for the next request's)
+                let mut lr = res.to_latency_record(id, inflight.count);
+                if let Some(send_time) = timestamp::sent_time(stream.tcp())? {
+                    lr.send_time = send_time;
+                }
+                if let Some(recv_time) = reader.recv_time() {
+                    lr.recv_time = recv_time;
+                }
+
+                if warm {
+                    requests_sent += 1;
+                    if !counters.record_busy(&res) {
+                        completed += 1;
+                        self.record(&tx, lr, clients);
+                    }
+                }
+                continue;
+            }
+
+            let res = loop {
+                match Response::deserialize(stream) {
+                    // Skip late responses to requests that were counted as dropped (UDP only)
+                    Ok(res) if stream.is_udp() && res.req_id < req_id => continue,
+                    res => break res,
+                }
+            };
+            let res = match res {
+                Ok(res) => res,
+                Err(e) if stream.is_udp() && counters.record_dropped(&e) => {
+                    requests_sent += warm as usize;
+                    continue;
+                }
+                Err(e) if record_failure(counters, &e, &outstanding) => {
+                    requests_sent += warm as usize;
+                    requests_sent += outstanding.iter().filter(|request| request.warm).count();
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            res.verify_id(req_id)?;
+            res.verify(&work)?;
+            res.verify_body(&self.payload)?;
+            counters.verify_checksum(&res, checksum);
+            if !warm {
+                continue;
+            }
+            requests_sent += 1;
+            if counters.record_busy(&res) {
+                continue;
+            }
+            completed += 1;
+            if !self.throughput_only {
+                self.record(&tx, res.to_latency_record(id, inflight.count), clients);
+            }
         }
 
-        latency_records
+        counters.completed.fetch_add(completed, Ordering::SeqCst);
+        for _ in &streams {
+            counters.connection_closed();
+        }
+        Ok(requests_sent)
+    }
+
+    /// Sends each connection's warmup requests. It returns `false` if a request timed out or the
+    /// server closed the connection, which is counted as a failed request.
+    fn _warm_up(
+        &self,
+        streams: &mut [Stream],
+        rng: &mut StdRng,
+        next_id: &mut u64,
+        counters: &Counters,
+    ) -> io::Result<bool> {
+        for stream in streams {
+            for _ in 0..self.warmup_requests {
+                let work = self.work.sample(rng);
+                let req_id = *next_id;
+                *next_id += 1;
+                let req = Request::new(0, req_id, work, self.verify_checksums)
+                    .with_body(self.payload.to_vec());
+                let checksum = req.checksum;
+                let res = req
+                    .serialize(stream)
+                    .and_then(|_| stream.flush())
+                    .and_then(|_| Response::deserialize(stream));
+                match res {
+                    Ok(res) => {
+                        res.verify_id(req_id)?;
+                        res.verify(&work)?;
+                        res.verify_body(&self.payload)?;
+                        counters.verify_checksum(&res, checksum);
+                    }
+                    Err(e) if counters.record_timeout(&e) || counters.record_error(&e, 1) => {
+                        return Ok(false);
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Sends a latency record to the sinks, and to the ramp's sink tagged with the number of
+    /// `clients` running when its request was sent.
+    fn record(&self, tx: &Sender<LatencyRecord>, lr: LatencyRecord, clients: usize) {
+        tx.send(lr).unwrap();
+        if let Some(ramp) = &self.ramp {
+            ramp.tx.send(RampRecord { lr, clients }).unwrap();
+        }
     }
 }
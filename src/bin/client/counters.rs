@@ -0,0 +1,190 @@
+use std::{
+    io,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Duration,
+};
+
+use rust_server_benchmarks::{framing, histogram::Histogram, protocol::Response};
+use rustls::HandshakeKind;
+
+/// Counters shared by every client thread of a request generator.
+#[derive(Debug, Default)]
+pub struct Counters {
+    /// Total number of connections opened.
+    pub connections: AtomicUsize,
+
+    /// Number of connections currently open.
+    pub open_connections: AtomicUsize,
+
+    /// Peak number of concurrently open connections.
+    pub peak_connections: AtomicUsize,
+
+    /// How long connecting took (in nanoseconds), for every connection.
+    pub connect_times: Mutex<Histogram>,
+
+    /// How long full TLS handshakes took (in nanoseconds), if timed separately from requests.
+    pub full_handshakes: Mutex<Histogram>,
+
+    /// How long TLS handshakes that resumed a session took (in nanoseconds).
+    pub resumed_handshakes: Mutex<Histogram>,
+
+    /// Number of worker threads spawned (partial open loop only).
+    pub threads: AtomicUsize,
+
+    /// Number of responses received. Clients add their count when they finish.
+    pub completed: AtomicUsize,
+
+    /// Number of requests that timed out waiting on the server.
+    pub timeouts: AtomicUsize,
+
+    /// Number of responses lost in transit (UDP only).
+    pub dropped: AtomicUsize,
+
+    /// Number of requests still waiting for a response when a client gave up on the server
+    /// (open loop only).
+    pub unacknowledged: AtomicUsize,
+
+    /// Number of requests the server received corrupted, as told by their checksums.
+    pub corrupted: AtomicUsize,
+
+    /// Number of requests that failed because the connection was reset or closed under them.
+    pub errors: AtomicUsize,
+
+    /// Number of requests the server turned away because it was busy.
+    pub busy: AtomicUsize,
+
+    /// Set once the server closes or resets a connection before the client is done with it,
+    /// which cuts the run short.
+    pub truncated: AtomicBool,
+
+    /// Number of times a sender paused because too many requests were outstanding (open loop
+    /// only).
+    pub stalls: AtomicUsize,
+
+    /// How long senders paused for in total (in nanoseconds).
+    pub stall_ns: AtomicU64,
+
+    /// Number of requests sent but not yet answered (or given up on), across all clients.
+    pub outstanding: AtomicU64,
+}
+
+/// A request in flight, counted as outstanding until it is dropped.
+pub struct InFlight<'a> {
+    counters: &'a Counters,
+
+    /// The number of requests in flight when it was sent, itself included.
+    pub count: u64,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.counters.outstanding.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Counters {
+    /// Records that a connection was opened, which took `connect_time`.
+    pub fn connection_opened(&self, connect_time: Duration) {
+        self.connect_times
+            .lock()
+            .unwrap()
+            .record(connect_time.as_nanos() as u64);
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        let open = self.open_connections.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak_connections.fetch_max(open, Ordering::SeqCst);
+    }
+
+    /// Records a TLS handshake of `kind` that took `handshake_time`.
+    pub fn record_handshake(&self, kind: HandshakeKind, handshake_time: Duration) {
+        let handshakes = match kind {
+            HandshakeKind::Resumed => &self.resumed_handshakes,
+            HandshakeKind::Full | HandshakeKind::FullWithHelloRetryRequest => &self.full_handshakes,
+        };
+        handshakes
+            .lock()
+            .unwrap()
+            .record(handshake_time.as_nanos() as u64);
+    }
+
+    /// Counts a request as outstanding until the returned guard is dropped, once the request has
+    /// been answered or given up on.
+    pub fn request_sent(&self) -> InFlight<'_> {
+        let count = self.outstanding.fetch_add(1, Ordering::SeqCst) + 1;
+        InFlight {
+            counters: self,
+            count,
+        }
+    }
+
+    /// Records that a connection was closed.
+    pub fn connection_closed(&self) {
+        self.open_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Records a failed request if `e` is a read or write timeout. It returns
+    /// whether it was one.
+    pub fn record_timeout(&self, e: &io::Error) -> bool {
+        let timed_out = is_timeout(e);
+        if timed_out {
+            self.timeouts.fetch_add(1, Ordering::SeqCst);
+        }
+        timed_out
+    }
+
+    /// Records `n` failed requests if `e` means the server reset or closed the
+    /// connection. It returns whether it did.
+    pub fn record_error(&self, e: &io::Error, n: usize) -> bool {
+        let disconnected = framing::is_disconnect(e);
+        if disconnected {
+            self.errors.fetch_add(n, Ordering::SeqCst);
+            self.truncated.store(true, Ordering::SeqCst);
+        }
+        disconnected
+    }
+
+    /// Records a lost response if `e` is a read timeout on a datagram socket.
+    /// It returns whether it was one.
+    pub fn record_dropped(&self, e: &io::Error) -> bool {
+        let timed_out = is_timeout(e);
+        if timed_out {
+            self.dropped.fetch_add(1, Ordering::SeqCst);
+        }
+        timed_out
+    }
+
+    /// Records that a sender paused for `duration` because too many requests were outstanding.
+    pub fn record_stall(&self, duration: Duration) {
+        self.stalls.fetch_add(1, Ordering::SeqCst);
+        self.stall_ns
+            .fetch_add(duration.as_nanos() as u64, Ordering::SeqCst);
+    }
+
+    /// Records a request the server turned away if `response` says it was busy.
+    /// It returns whether it did.
+    pub fn record_busy(&self, response: &Response) -> bool {
+        let busy = response.is_busy();
+        if busy {
+            self.busy.fetch_add(1, Ordering::SeqCst);
+        }
+        busy
+    }
+
+    /// Records a corrupted request if `response` doesn't carry the checksum
+    /// `checksum` of the request it answers.
+    pub fn verify_checksum(&self, response: &Response, checksum: u32) {
+        if !response.verify_checksum(checksum) {
+            self.corrupted.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Checks whether `e` is a read or write timeout.
+pub fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
@@ -1,17 +1,44 @@
 mod closed_loop;
+mod counters;
 mod open_loop;
 mod partial_open_loop;
+mod rate;
+mod split;
+mod stream;
+mod timestamp;
 
 use std::{
-    net::{Ipv4Addr, SocketAddrV4},
-    path::PathBuf,
-    time::Duration,
+    fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, atomic::Ordering},
+    time::{Duration, Instant},
 };
 
-use clap::{Parser, ValueEnum};
-use rust_server_benchmarks::{protocol::Work, write_stats};
+use clap::{CommandFactory, Parser, ValueEnum, error::ErrorKind};
+use closed_loop::Ramp;
+use counters::Counters;
+use crossbeam_channel::unbounded;
+use rand::Rng;
+use rate::Arrival;
+use rust_server_benchmarks::{
+    AppendOptions, Failures, Format, StatsOptions,
+    buffers::BufferSizes,
+    clock_overhead, get_time,
+    histogram::Histogram,
+    mix::Mix,
+    protocol::{MAX_REQUEST_PAYLOAD, Work},
+    proxy,
+    sink::{
+        CapacitySink, ConcurrencySink, ExactSink, HistogramSink, QueueDepthSink, RampSink, RawSink,
+        ServiceTimeSink, StatsSink, TimeseriesSink, TrialSink, spawn_collector,
+        spawn_ramp_collector,
+    },
+    tls, write_throughput, write_trial_stats,
+};
+use stream::{Retry, Transport};
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, serde::Serialize)]
 #[command(version, about, long_about = None)]
 struct Args {
     /// The type of server.
@@ -22,70 +49,1024 @@ struct Args {
     #[arg(short, long, default_value_t = 6)]
     runtime: u64,
 
-    /// Delay in microseconds. This argument is ignored if using
-    /// the closed loop request generator.
-    #[arg(short, long)]
-    delay: u64,
+    /// Stop once the clients have collectively sent this many requests (after
+    /// the warmup) instead of after the runtime, which is then measured.
+    /// Requests are split evenly between clients, so there must be at least
+    /// one per client. This argument is not supported by the partial open loop
+    /// request generator or with the ramp arrival pattern.
+    #[arg(long, conflicts_with = "runtime", value_parser = clap::value_parser!(u64).range(1..))]
+    total_requests: Option<u64>,
+
+    /// Run the whole benchmark this many times, reconnecting for each trial,
+    /// and report the mean and 95% confidence interval of each percentile and
+    /// of the throughput across trials (trials.txt, e.g. "p99: 52.2 ± 1.3").
+    /// Each trial writes its own results to trial_<i>/. This argument is not
+    /// supported with --throughput-only.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    trials: u64,
+
+    /// Seed for every random choice the client makes (sampled work, Poisson
+    /// arrivals), so a run can be reproduced. A random seed is chosen and
+    /// printed if none is given. Trial i of --trials uses seed + i.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Warmup in seconds before the runtime starts. Requests are still sent
+    /// during the warmup, but they are excluded from the statistics and the
+    /// number of requests sent, so throughput covers the runtime only.
+    #[arg(long, default_value_t = 0)]
+    warmup: u64,
+
+    /// Delay in microseconds, which may be fractional (e.g. 1.5). This
+    /// argument is ignored if using the closed loop request generator.
+    #[arg(short, long, required_unless_present = "target_rps")]
+    delay: Option<f64>,
+
+    /// Send this many requests per second across all clients instead of
+    /// using a fixed --delay. Each client starts from the delay that gives its
+    /// share of the rate and corrects it every second by how far the rate it
+    /// actually sent at was off, compensating for the time spent sending and
+    /// busy-wait jitter. This argument is only supported by the open loop
+    /// request generator with the fixed arrival pattern.
+    #[arg(long, conflicts_with = "delay")]
+    target_rps: Option<f64>,
+
+    /// Arrival pattern of requests (batches for the partial open loop): fixed,
+    /// poisson, ramp:<micros>, or schedule:<secs>=<micros>,... This argument is
+    /// ignored if using the closed loop request generator.
+    #[arg(long, default_value = "fixed")]
+    arrival: Arrival,
 
-    /// IP address of the server.
+    /// IP address of the server, IPv4 or IPv6 (e.g. ::1).
     #[arg(long, default_value = "127.0.0.1")]
-    ip: Ipv4Addr,
+    ip: IpAddr,
 
     /// Port of the server.
     #[arg(long, default_value_t = 8080)]
     port: u16,
 
+    /// Path of the server's socket, with --transport uds.
+    #[arg(long, default_value = "/tmp/rust-server-benchmarks.sock")]
+    uds_path: PathBuf,
+
     /// The number of clients.
     #[arg(long, default_value_t = 1)]
     num_clients: usize,
 
+    /// Start with one client and add another every this many seconds until
+    /// --num-clients are running, then stop them all one interval later. Each
+    /// number of clients gets its throughput and percentiles in ramp.txt, by
+    /// how many were running when a request was sent, showing latency against
+    /// concurrency in a single run. The run lasts this long times the number
+    /// of clients. This argument is only supported by the closed loop request
+    /// generator.
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with_all = [
+            "runtime",
+            "total_requests",
+            "warmup",
+            "connection_warmup_requests",
+            "throughput_only",
+        ],
+    )]
+    ramp: Option<u64>,
+
+    /// The maximum number of client threads. This argument is only used by
+    /// the partial open loop request generator.
+    #[arg(long, default_value_t = 16)]
+    max_threads: usize,
+
+    /// The number of requests each client sends per connection. This argument
+    /// is only used by the partial open loop request generator.
+    #[arg(long, default_value_t = 10)]
+    num_requests: usize,
+
+    /// Keep each thread's connection open between its batches instead of
+    /// opening a new one for every batch, to compare the cost of short-lived
+    /// connections with keep-alive. This argument is only used by the partial
+    /// open loop request generator.
+    #[arg(long)]
+    keep_alive: bool,
+
+    /// Measure latency from each request's scheduled send time instead of its
+    /// actual send time, correcting for coordinated omission. This argument is
+    /// ignored unless using the open loop request generator.
+    #[arg(long)]
+    correct_co: bool,
+
+    /// Maximum outstanding request and response bytes per client. This argument
+    /// is ignored unless using the open loop request generator.
+    #[arg(long)]
+    max_inflight_bytes: Option<u64>,
+
+    /// Maximum number of requests each client keeps sent but unanswered. Once
+    /// this many are outstanding, the sender pauses until the receiver catches
+    /// up instead of letting the backlog grow without bound, and the number
+    /// and total length of the pauses are reported: any pause means the server
+    /// couldn't sustain the offered rate. This argument is ignored unless using
+    /// the open loop request generator.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_inflight: Option<u64>,
+
+    /// Statistics sinks the latency records are fed through.
+    #[arg(long, value_delimiter = ',', default_value = "exact")]
+    sinks: Vec<Sink>,
+
+    /// Also write every latency record to this CSV file as it arrives, like
+    /// the raw sink does to raw.csv.
+    #[arg(long)]
+    raw_output: Option<PathBuf>,
+
+    /// Write the throughput and p99 of every interval of this many
+    /// milliseconds, by receive time (timeseries.csv). This enables the
+    /// timeseries sink.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    bucket_ms: Option<u64>,
+
+    /// Format of the statistics written by the exact and histogram sinks
+    /// (stats.txt or stats.json, histogram.txt or histogram.json).
+    #[arg(long, default_value = "text")]
+    format: Format,
+
+    /// Latency percentiles written by the exact and histogram sinks, in
+    /// percent.
+    #[arg(long, value_delimiter = ',', default_value = "50,95,99,99.9")]
+    percentiles: Vec<f64>,
+
+    /// Also append a row of summary statistics to this CSV file, led by the
+    /// request generator, delay, number of clients, and workload, so a sweep
+    /// accumulates into one file. The header is written when the file is
+    /// created. Rows are written by the exact and histogram sinks (one each if
+    /// both are used), and the file is locked while writing so concurrent
+    /// clients can share it.
+    #[arg(long)]
+    output_append: Option<PathBuf>,
+
+    /// Aggregate latencies into a constant-memory histogram instead of keeping
+    /// every record, i.e. use the histogram sink in place of the exact sink.
+    #[arg(long)]
+    histogram: bool,
+
+    /// Keep only each client's most recent n latency records (after the
+    /// warmup) in a ring buffer, so the exact sink's memory is bounded and its
+    /// percentiles reflect the steady state. The number of older records
+    /// discarded is printed; they still count towards the achieved throughput.
+    #[arg(long, value_name = "N", conflicts_with = "histogram", value_parser = clap::value_parser!(u64).range(1..))]
+    keep_last: Option<u64>,
+
+    /// Print and save (connections.txt) the number of connections opened, the
+    /// peak number of concurrent connections, and the number of threads spawned.
+    #[arg(long)]
+    report_connection_count: bool,
+
+    /// Print and save (connect_latency.txt) the 50th and 99th percentile and
+    /// maximum time it took to establish a connection, in microseconds, apart
+    /// from the request latencies.
+    #[arg(long)]
+    report_connect_latency: bool,
+
+    /// Only count completed requests (throughput.txt), skipping timestamps
+    /// and latency records entirely.
+    #[arg(long)]
+    throughput_only: bool,
+
+    /// Timestamp requests and responses with kernel software timestamps
+    /// (SO_TIMESTAMPING) instead of userspace clocks, removing scheduling
+    /// jitter from latencies. This argument is only used by the closed loop
+    /// request generator.
+    #[arg(long)]
+    kernel_timestamps: bool,
+
+    /// Measure the median cost of reading the clock at startup, over 10k
+    /// readings, and subtract it from every recorded latency. This matters
+    /// most for sub-microsecond work, where reading the clock dominates the
+    /// measured latency. Not supported with --kernel-timestamps.
+    #[arg(long)]
+    calibrate: bool,
+
+    /// Number of unrecorded requests each connection sends before recording
+    /// starts. Recording begins only once every connection is established and
+    /// warmed up. This argument is only used by the closed loop request
+    /// generator.
+    #[arg(long, default_value_t = 0)]
+    connection_warmup_requests: usize,
+
+    /// Milliseconds to wait for a response before giving up on the server.
+    /// Requests that time out are counted as failures (the last line of
+    /// stats.txt) and end the connection they were sent on.
+    #[arg(long)]
+    request_timeout: Option<u64>,
+
+    /// Give up on the server once no response has arrived for this many
+    /// milliseconds while requests are outstanding, stopping the client and
+    /// reporting what was collected. Unlike --request-timeout, a connection
+    /// with nothing outstanding never times out. This argument is only used by
+    /// the open loop request generator, and is not supported over UDP.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    liveness_window: Option<u64>,
+
+    /// Retry a connection the server refuses (e.g. because it hasn't started
+    /// listening yet) up to this many times, so the client can be launched
+    /// right after the server.
+    #[arg(long, default_value_t = 0)]
+    connect_retries: u32,
+
+    /// Milliseconds to wait before the first connection retry, doubling after
+    /// every retry.
+    #[arg(long, default_value_t = 10)]
+    connect_backoff: u64,
+
+    /// Leave Nagle's algorithm enabled instead of setting TCP_NODELAY on
+    /// connections. Small requests can then be held back until the previous
+    /// one is acknowledged, which with delayed ACKs adds up to ~40ms of
+    /// latency. This argument is not supported over UDP.
+    #[arg(long)]
+    nagle: bool,
+
+    /// Request this many bytes for the send buffer (SO_SNDBUF) of every
+    /// connection. The kernel may grant a different size, which is printed.
+    #[arg(long)]
+    sndbuf: Option<usize>,
+
+    /// Request this many bytes for the receive buffer (SO_RCVBUF) of every
+    /// connection. The kernel may grant a different size, which is printed.
+    #[arg(long)]
+    rcvbuf: Option<usize>,
+
+    /// Send a CRC-32 checksum with every request, which the server recomputes
+    /// over what it received and echoes back. Responses whose checksum doesn't
+    /// match are counted as corrupted.
+    #[arg(long)]
+    verify_checksums: bool,
+
+    /// Send the contents of this file as the body of every request, usually
+    /// with the echo workload. The server echoes the body back, and a response
+    /// whose body doesn't match stops the client that received it. The file
+    /// may be at most 4059 bytes long.
+    #[arg(long, value_name = "FILE")]
+    payload_from_file: Option<PathBuf>,
+
+    /// Send a PROXY protocol header of this version at the start of each
+    /// connection, e.g. for servers behind a load balancer.
+    #[arg(long)]
+    proxy_protocol: Option<proxy::Version>,
+
+    /// Connect over TLS, accepting any server certificate. This argument is
+    /// not supported by the open loop request generator or with kernel
+    /// timestamps.
+    #[arg(long)]
+    tls: bool,
+
+    /// Alternate new connections between full TLS handshakes and ones that
+    /// resume a cached session, timing each handshake apart from the requests,
+    /// and print and save (handshakes.txt) the 50th and 99th percentile of
+    /// both kinds in microseconds and how much resumption saves. This argument
+    /// is only supported by the partial open loop request generator with --tls
+    /// and without --keep-alive.
+    #[arg(long, requires = "tls")]
+    tls_resumption: bool,
+
+    /// Only offer TLS 1.2 with --tls. Resuming a TLS 1.2 session skips the
+    /// key exchange and a round trip, while TLS 1.3 resumption still does the
+    /// key exchange.
+    #[arg(long, requires = "tls")]
+    tls12: bool,
+
+    /// The transport to send requests over. Over UDP, responses that don't
+    /// arrive within the request timeout (1 second by default) are counted as
+    /// dropped. UDP is not supported by the partial open loop request
+    /// generator or with --tls, --proxy-protocol, or --kernel-timestamps.
+    /// Over a Unix domain socket (uds), requests skip the TCP/IP stack, which
+    /// isolates the suite's own overhead; it is not supported by the partial
+    /// open loop request generator or with --tls, --proxy-protocol,
+    /// --kernel-timestamps, --nagle, or --liveness-window.
+    #[arg(long, default_value = "tcp")]
+    transport: Transport,
+
+    /// The number of requests each client keeps outstanding on its
+    /// connections, sending the next ones before the earlier responses arrive.
+    /// This argument is only used by the closed loop request generator, and
+    /// is not supported over UDP or with --kernel-timestamps.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    pipeline_depth: u64,
+
+    /// The number of connections each client opens, sending its requests on
+    /// them in turn, like a client with a connection pool. Latencies are still
+    /// recorded per client. This argument is only used by the closed loop
+    /// request generator.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    connections_per_client: u64,
+
+    /// Print and save a derived report after the run.
+    #[arg(long)]
+    report: Option<Report>,
+
+    /// Check the arguments, print the resolved configuration (every argument,
+    /// defaults included, and derived values such as the offered rate) as
+    /// JSON, and exit without connecting to the server.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// The 99th percentile latency target in microseconds. The capacity
+    /// report shows the headroom to it.
+    #[arg(long)]
+    sla: Option<u64>,
+
     /// Directory to write results to
-    #[arg(short, long)]
+    #[arg(long)]
     dir: PathBuf,
 
-    /// The workload type.
+    /// Sample the work of each request from a weighted mix instead of using a
+    /// single workload, e.g. "constant:90,busy:9:1000,sleep:1:500". Entries are
+    /// <work>:<weight>[:<param>], with the loop count, microseconds, bytes,
+    /// matrix dimension, <size>/<hops>, <path_id>/<bytes>, or
+    /// <mean micros>/<exponential|lognormal> as the parameter of busy, sleep,
+    /// echo, matmul, memchase, fileread, or randomsleep.
+    #[arg(long)]
+    mix: Option<Mix>,
+
+    /// The workload type. Either this or --mix is required.
     #[command(subcommand)]
-    work: Work,
+    work: Option<Work>,
 }
 
-#[derive(Clone, Debug, ValueEnum)]
+#[derive(Clone, Debug, ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
 enum Kind {
     Closed,
     Open,
+    PartialOpen,
+}
+
+#[derive(Clone, Debug, ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Report {
+    /// Sustained throughput, p99 at that throughput, and the headroom to the
+    /// SLA (capacity.txt).
+    Capacity,
+}
+
+#[derive(Clone, Debug, ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Sink {
+    /// Exact percentiles over every record (stats.txt, clients.txt).
+    Exact,
+
+    /// Approximate percentiles in constant memory (histogram.txt).
+    Histogram,
+
+    /// Every record as CSV (raw.csv).
+    Raw,
+
+    /// Throughput and p99 per interval, every second by default
+    /// (timeseries.csv).
+    Timeseries,
+
+    /// Distribution of the server's reported queue depth (queue_depth.txt).
+    QueueDepth,
+
+    /// Percentiles of the server's service time and of the rest of the latency,
+    /// i.e. queueing and the network (service_time.txt).
+    ServiceTime,
+
+    /// Percentiles of the latency against the number of requests in flight
+    /// across all clients when each was sent (concurrency.txt).
+    Concurrency,
+}
+
+/// Creates the selected statistics sinks, writing to `dir`. The timeseries
+/// sink groups records into intervals of length `bucket`.
+fn make_sinks(
+    kinds: &[Sink],
+    dir: &Path,
+    work: &Mix,
+    options: &StatsOptions,
+    bucket: Duration,
+    keep_last: Option<usize>,
+) -> Vec<Box<dyn StatsSink>> {
+    kinds
+        .iter()
+        .map(|kind| -> Box<dyn StatsSink> {
+            match kind {
+                Sink::Exact => Box::new(ExactSink::new(
+                    dir,
+                    work.clone(),
+                    options.clone(),
+                    keep_last,
+                )),
+                Sink::Histogram => Box::new(HistogramSink::new(dir, work.clone(), options.clone())),
+                Sink::Raw => Box::new(RawSink::new(&dir.join("raw.csv")).unwrap()),
+                Sink::Timeseries => Box::new(TimeseriesSink::new(dir, get_time(), bucket)),
+                Sink::QueueDepth => Box::new(QueueDepthSink::new(dir)),
+                Sink::ServiceTime => Box::new(ServiceTimeSink::new(dir)),
+                Sink::Concurrency => {
+                    Box::new(ConcurrencySink::new(dir, options.percentiles.clone()))
+                }
+            }
+        })
+        .collect()
 }
 
 fn main() {
     let args = Args::parse();
-    let addr = SocketAddrV4::new(args.ip, args.port);
-    let runtime = Duration::from_secs(args.runtime);
-    let delay = Duration::from_micros(args.delay);
-    let dir = args.dir;
-
-    match args.kind {
-        Kind::Closed => {
-            let cfg = closed_loop::Config {
-                addr,
-                runtime,
-                work: args.work,
-                num_clients: args.num_clients,
-            };
-            let lrs = cfg.run();
-            let n_reqs = lrs.len();
-            let path = dir.join("closed/stats.txt");
-            println!("{:?}", path);
-            write_stats(lrs, n_reqs, args.runtime, &path).unwrap();
-        }
-        Kind::Open => {
-            let cfg = open_loop::Config {
-                addr,
-                runtime,
-                delay,
-                work: args.work,
-                num_clients: args.num_clients,
-            };
-            let (n_reqs, lrs) = cfg.run();
-            let path = dir.join("open/stats.txt");
-            write_stats(lrs, n_reqs, args.runtime, &path).unwrap();
+    let addr = SocketAddr::new(args.ip, args.port);
+    let runtime = match args.ramp {
+        Some(interval) => Duration::from_secs(interval * args.num_clients as u64),
+        None => Duration::from_secs(args.runtime),
+    };
+    let warmup = Duration::from_secs(args.warmup);
+    if let Some(delay) = args.delay
+        && !(delay.is_finite() && delay >= 0.0)
+    {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!("--delay {delay} is not a finite, non-negative number of microseconds"),
+            )
+            .exit();
+    }
+    // Each client sends its share of the target rate
+    let delay_us = match args.target_rps {
+        Some(rps) => 1e6 * args.num_clients as f64 / rps,
+        None => args.delay.unwrap(),
+    };
+    let delay = rate::from_micros(delay_us);
+    let request_timeout = args.request_timeout.map(Duration::from_millis);
+    let dir = args.dir.join(match args.kind {
+        Kind::Closed => "closed",
+        Kind::Open => "open",
+        Kind::PartialOpen => "partial_open",
+    });
+    if !args.dry_run {
+        println!("{:?}", dir);
+    }
+
+    let work = match (args.mix.clone(), args.work) {
+        (Some(mix), None) => mix,
+        (None, Some(work)) => {
+            if let Err(e) = work.validate() {
+                Args::command()
+                    .error(ErrorKind::ValueValidation, e.to_string())
+                    .exit();
+            }
+            Mix::from(work)
+        }
+        _ => Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "exactly one of a workload or --mix is required",
+            )
+            .exit(),
+    };
+
+    let payload: Arc<[u8]> = match &args.payload_from_file {
+        Some(path) => {
+            let payload = fs::read(path).unwrap_or_else(|e| {
+                Args::command()
+                    .error(
+                        ErrorKind::Io,
+                        format!("failed to read --payload-from-file {}: {e}", path.display()),
+                    )
+                    .exit()
+            });
+            if payload.len() > MAX_REQUEST_PAYLOAD {
+                Args::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        format!(
+                            "--payload-from-file {} is {} bytes long, but request bodies are \
+                             limited to {MAX_REQUEST_PAYLOAD} bytes",
+                            path.display(),
+                            payload.len()
+                        ),
+                    )
+                    .exit();
+            }
+            payload.into()
+        }
+        None => Arc::new([]),
+    };
+
+    if args.tls && (matches!(args.kind, Kind::Open) || args.kernel_timestamps) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--tls is not supported by the open loop or with --kernel-timestamps",
+            )
+            .exit();
+    }
+    let tls = args.tls.then(|| tls::client_config(args.tls12));
+    if args.tls_resumption && (!matches!(args.kind, Kind::PartialOpen) || args.keep_alive) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--tls-resumption is only supported by the partial open loop request generator \
+                 without --keep-alive",
+            )
+            .exit();
+    }
+    let tls_without_resumption = tls
+        .as_deref()
+        .filter(|_| args.tls_resumption)
+        .map(tls::without_resumption);
+
+    if args.calibrate && args.kernel_timestamps {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--calibrate is not supported with --kernel-timestamps",
+            )
+            .exit();
+    }
+
+    if args.transport == Transport::Udp
+        && (matches!(args.kind, Kind::PartialOpen)
+            || args.tls
+            || args.proxy_protocol.is_some()
+            || args.kernel_timestamps
+            || args.nagle)
+    {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--transport udp is not supported by the partial open loop or with --tls, \
+                 --proxy-protocol, --kernel-timestamps, or --nagle",
+            )
+            .exit();
+    }
+
+    if args.transport == Transport::Uds
+        && (matches!(args.kind, Kind::PartialOpen)
+            || args.tls
+            || args.proxy_protocol.is_some()
+            || args.kernel_timestamps
+            || args.nagle
+            || args.liveness_window.is_some())
+    {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--transport uds is not supported by the partial open loop or with --tls, \
+                 --proxy-protocol, --kernel-timestamps, --nagle, or --liveness-window",
+            )
+            .exit();
+    }
+
+    if let Some(p) = args
+        .percentiles
+        .iter()
+        .find(|p| !(0.0..=100.0).contains(*p))
+    {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!("percentile {p} is not between 0 and 100"),
+            )
+            .exit();
+    }
+    if args.pipeline_depth > 1 && (args.transport == Transport::Udp || args.kernel_timestamps) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--pipeline-depth is not supported over UDP or with --kernel-timestamps",
+            )
+            .exit();
+    }
+
+    if args.ramp.is_some() && !matches!(args.kind, Kind::Closed) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--ramp is only supported by the closed loop request generator",
+            )
+            .exit();
+    }
+
+    if let Some(rps) = args.target_rps {
+        if !(rps.is_finite() && rps > 0.0) {
+            Args::command()
+                .error(
+                    ErrorKind::ValueValidation,
+                    format!("--target-rps {rps} is not a positive rate"),
+                )
+                .exit();
         }
+        if !matches!(args.kind, Kind::Open) || !matches!(args.arrival, Arrival::Fixed) {
+            Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--target-rps is only supported by the open loop request generator with \
+                     the fixed arrival pattern",
+                )
+                .exit();
+        }
+    }
+
+    if args.trials > 1 && args.throughput_only {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--trials is not supported with --throughput-only",
+            )
+            .exit();
+    }
+
+    if args.liveness_window.is_some() && args.transport == Transport::Udp {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--liveness-window is not supported over UDP",
+            )
+            .exit();
+    }
+
+    if let Some(total) = args.total_requests {
+        if matches!(args.kind, Kind::PartialOpen) || matches!(args.arrival, Arrival::Ramp { .. }) {
+            Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--total-requests is not supported by the partial open loop or with the \
+                     ramp arrival pattern",
+                )
+                .exit();
+        }
+        if total < args.num_clients as u64 {
+            Args::command()
+                .error(
+                    ErrorKind::ValueValidation,
+                    format!(
+                        "--total-requests {total} is less than one request per client ({})",
+                        args.num_clients
+                    ),
+                )
+                .exit();
+        }
+    }
+    let total_requests = args.total_requests.map(|total| total as usize);
+
+    let append = args.output_append.clone().map(|path| AppendOptions {
+        path,
+        params: vec![
+            (
+                "kind".to_string(),
+                args.kind
+                    .to_possible_value()
+                    .unwrap()
+                    .get_name()
+                    .to_string(),
+            ),
+            ("delay_us".to_string(), delay_us.to_string()),
+            ("num_clients".to_string(), args.num_clients.to_string()),
+        ],
+    });
+    let options = StatsOptions {
+        percentiles: args.percentiles.clone(),
+        format: args.format,
+        append,
+    };
+
+    let mut kinds = args.sinks.clone();
+    if args.bucket_ms.is_some() && !kinds.iter().any(|kind| matches!(kind, Sink::Timeseries)) {
+        kinds.push(Sink::Timeseries);
+    }
+    let bucket = Duration::from_millis(args.bucket_ms.unwrap_or(1000));
+    if args.histogram {
+        kinds.retain(|kind| !matches!(kind, Sink::Exact | Sink::Histogram));
+        kinds.push(Sink::Histogram);
+    }
+
+    if args.dry_run {
+        // The requests (batches for the partial open loop) sent per second by each client, and
+        // the requests sent by all of them together
+        let rate = match args.kind {
+            Kind::Closed => None,
+            Kind::Open | Kind::PartialOpen => (delay_us > 0.0).then(|| 1e6 / delay_us),
+        };
+        let offered = match args.kind {
+            Kind::Closed => None,
+            Kind::Open => rate.map(|rate| rate * args.num_clients as f64),
+            Kind::PartialOpen => rate.map(|rate| rate * args.num_requests as f64),
+        };
+        let config = serde_json::json!({
+            "args": &args,
+            "resolved": {
+                "addr": addr.to_string(),
+                "dir": dir,
+                "workload": work.to_string(),
+                "runtime_secs": runtime.as_secs_f64(),
+                "warmup_secs": warmup.as_secs_f64(),
+                "delay_ns": delay.as_nanos() as u64,
+                "client_rps": rate,
+                "offered_rps": offered,
+                "total_requests": total_requests,
+                "sinks": kinds,
+                "bucket_ms": bucket.as_millis() as u64,
+                "seed": args.seed,
+            },
+        });
+        println!("{}", serde_json::to_string_pretty(&config).unwrap());
+        return;
+    }
+
+    let seed = args.seed.unwrap_or_else(|| rand::rng().random());
+    println!("seed: {seed}");
+
+    let clock_overhead = if args.calibrate {
+        let overhead = clock_overhead(10_000);
+        println!("clock overhead: {overhead} ns per reading, subtracted from every latency");
+        overhead
+    } else {
+        0
     };
+
+    let buffers = BufferSizes {
+        send: args.sndbuf,
+        recv: args.rcvbuf,
+    };
+    if !buffers.is_default() {
+        match stream::granted_buffers(addr, args.transport, buffers) {
+            Ok(granted) => println!("socket buffers: {granted}"),
+            Err(e) => Args::command()
+                .error(ErrorKind::Io, format!("failed to set socket buffers: {e}"))
+                .exit(),
+        }
+    }
+
+    let (trial_tx, trial_rx) = unbounded();
+    for trial in 0..args.trials {
+        // Every trial gets its own directory, fresh connections, and seed
+        let trial_seed = seed.wrapping_add(trial);
+        let dir = if args.trials > 1 {
+            dir.join(format!("trial_{trial}"))
+        } else {
+            dir.clone()
+        };
+
+        let mut sinks = if args.throughput_only {
+            Vec::new()
+        } else {
+            make_sinks(
+                &kinds,
+                &dir,
+                &work,
+                &options,
+                bucket,
+                args.keep_last.map(|n| n as usize),
+            )
+        };
+        if let Some(path) = &args.raw_output {
+            sinks.push(Box::new(RawSink::new(path).unwrap()));
+        }
+        if let Some(Report::Capacity) = args.report {
+            let sla = args.sla.map(Duration::from_micros);
+            sinks.push(Box::new(CapacitySink::new(&dir, sla)));
+        }
+        if args.trials > 1 {
+            let percentiles = options.percentiles.clone();
+            sinks.push(Box::new(TrialSink::new(percentiles, trial_tx.clone())));
+        }
+        let (tx, collector) = spawn_collector(sinks, clock_overhead);
+        let retry = Retry {
+            retries: args.connect_retries,
+            backoff: Duration::from_millis(args.connect_backoff),
+        };
+        let counters = Arc::new(Counters::default());
+        let start = Instant::now();
+
+        let n_reqs = match args.kind {
+            Kind::Closed => {
+                let (ramp, ramp_collector) = match args.ramp {
+                    Some(interval) => {
+                        let interval = Duration::from_secs(interval);
+                        let sink = RampSink::new(&dir, interval, options.percentiles.clone());
+                        let (tx, collector) = spawn_ramp_collector(sink, clock_overhead);
+                        (Some(Ramp { interval, tx }), Some(collector))
+                    }
+                    None => (None, None),
+                };
+                let cfg = closed_loop::Config {
+                    addr,
+                    runtime,
+                    warmup,
+                    work: work.clone(),
+                    num_clients: args.num_clients,
+                    throughput_only: args.throughput_only,
+                    proxy_protocol: args.proxy_protocol,
+                    kernel_timestamps: args.kernel_timestamps,
+                    warmup_requests: args.connection_warmup_requests,
+                    seed: trial_seed,
+                    request_timeout,
+                    tls: tls.clone(),
+                    transport: args.transport,
+                    uds_path: args.uds_path.clone(),
+                    connections_per_client: args.connections_per_client as usize,
+                    pipeline_depth: args.pipeline_depth as usize,
+                    retry,
+                    nagle: args.nagle,
+                    buffers,
+                    verify_checksums: args.verify_checksums,
+                    payload: payload.clone(),
+                    total_requests,
+                    ramp,
+                };
+                let n_reqs = cfg.run(tx, &counters);
+
+                if let Some(collector) = ramp_collector
+                    && let Err(e) = collector.join().unwrap().finish()
+                {
+                    eprintln!("failed to write statistics: {e}");
+                }
+                n_reqs
+            }
+            Kind::Open => {
+                let cfg = open_loop::Config {
+                    addr,
+                    runtime,
+                    warmup,
+                    delay,
+                    arrival: args.arrival.clone(),
+                    target_rate: args.target_rps.is_some(),
+                    seed: trial_seed,
+                    work: work.clone(),
+                    num_clients: args.num_clients,
+                    max_inflight_bytes: args.max_inflight_bytes,
+                    max_inflight: args.max_inflight,
+                    throughput_only: args.throughput_only,
+                    correct_co: args.correct_co,
+                    request_timeout,
+                    proxy_protocol: args.proxy_protocol,
+                    transport: args.transport,
+                    uds_path: args.uds_path.clone(),
+                    retry,
+                    nagle: args.nagle,
+                    buffers,
+                    verify_checksums: args.verify_checksums,
+                    payload: payload.clone(),
+                    total_requests,
+                    liveness_window: args.liveness_window.map(Duration::from_millis),
+                };
+                cfg.run(tx, &counters)
+            }
+            Kind::PartialOpen => {
+                let cfg = partial_open_loop::Config {
+                    addr,
+                    runtime,
+                    warmup,
+                    delay,
+                    arrival: args.arrival.clone(),
+                    seed: trial_seed,
+                    work: work.clone(),
+                    max_threads: args.max_threads,
+                    num_requests: args.num_requests,
+                    timeout: request_timeout.unwrap_or(runtime),
+                    throughput_only: args.throughput_only,
+                    proxy_protocol: args.proxy_protocol,
+                    tls: tls.clone(),
+                    tls_without_resumption: tls_without_resumption.clone(),
+                    keep_alive: args.keep_alive,
+                    retry,
+                    nagle: args.nagle,
+                    buffers,
+                    verify_checksums: args.verify_checksums,
+                    payload: payload.clone(),
+                };
+                cfg.run(tx, &counters)
+            }
+        };
+
+        // A run stopped by its request count lasts as long as the requests took
+        let runtime = match total_requests {
+            Some(_) => start.elapsed().saturating_sub(warmup),
+            None => runtime,
+        };
+
+        let failures = Failures {
+            timeouts: counters.timeouts.load(Ordering::SeqCst),
+            dropped: counters.dropped.load(Ordering::SeqCst),
+            unacknowledged: counters.unacknowledged.load(Ordering::SeqCst),
+            corrupted: counters.corrupted.load(Ordering::SeqCst),
+            errors: counters.errors.load(Ordering::SeqCst),
+            busy: counters.busy.load(Ordering::SeqCst),
+        };
+        if failures.timeouts > 0 {
+            eprintln!("{} requests timed out", failures.timeouts);
+        }
+        if failures.dropped > 0 {
+            eprintln!("{} responses were dropped", failures.dropped);
+        }
+        if failures.unacknowledged > 0 {
+            eprintln!("{} requests were never answered", failures.unacknowledged);
+        }
+        if failures.errors > 0 {
+            eprintln!(
+                "{} requests failed because the connection was reset or closed",
+                failures.errors
+            );
+        }
+        if failures.busy > 0 {
+            eprintln!(
+                "{} requests were turned away by a busy server",
+                failures.busy
+            );
+        }
+        if counters.truncated.load(Ordering::SeqCst) {
+            eprintln!(
+                "the server closed connections before the run ended, so it was truncated: the \
+                 statistics only cover the responses received before then"
+            );
+        }
+        if failures.corrupted > 0 {
+            eprintln!(
+                "{} responses failed checksum verification",
+                failures.corrupted
+            );
+        }
+
+        let stalls = counters.stalls.load(Ordering::SeqCst);
+        if stalls > 0 {
+            let stall_ms = counters.stall_ns.load(Ordering::SeqCst) as f64 / 1e6;
+            eprintln!(
+                "senders paused {stalls} times for {stall_ms:.1} ms in total with \
+                 --max-inflight requests outstanding"
+            );
+        }
+
+        for sink in collector.join().unwrap() {
+            if let Err(e) = sink.finish(n_reqs, failures, runtime) {
+                eprintln!("failed to write statistics: {e}");
+            }
+        }
+
+        if args.throughput_only {
+            let completed = counters.completed.load(Ordering::SeqCst);
+            write_throughput(n_reqs, completed, runtime, &dir.join("throughput.txt")).unwrap();
+        }
+
+        if args.report_connect_latency {
+            let connect_times = counters.connect_times.lock().unwrap();
+            let percentile = |p| connect_times.percentile(p).unwrap_or(0) as f64 / 1000.0;
+            let (p_50, p_99, max) = (percentile(0.5), percentile(0.99), percentile(1.0));
+
+            println!(
+                "connect latency over {} connections: p50: {p_50} us, p99: {p_99} us, max: {max} us",
+                connect_times.len()
+            );
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(
+                dir.join("connect_latency.txt"),
+                format!("{p_50}, {p_99}, {max}\n"),
+            )
+            .unwrap();
+        }
+
+        if args.tls_resumption {
+            let full = counters.full_handshakes.lock().unwrap();
+            let resumed = counters.resumed_handshakes.lock().unwrap();
+            let percentiles = |handshakes: &Histogram| {
+                let percentile = |p| handshakes.percentile(p).unwrap_or(0) as f64 / 1000.0;
+                (percentile(0.5), percentile(0.99))
+            };
+            let (full_50, full_99) = percentiles(&full);
+            let (resumed_50, resumed_99) = percentiles(&resumed);
+
+            println!(
+                "{} full handshakes: p50: {full_50} us, p99: {full_99} us; {} resumed: p50: \
+                 {resumed_50} us, p99: {resumed_99} us; resumption saves {:.1} us at p50",
+                full.len(),
+                resumed.len(),
+                full_50 - resumed_50
+            );
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(
+                dir.join("handshakes.txt"),
+                format!("{full_50}, {full_99}, {resumed_50}, {resumed_99}\n"),
+            )
+            .unwrap();
+        }
+
+        if args.report_connection_count {
+            let connections = counters.connections.load(Ordering::SeqCst);
+            let peak = counters.peak_connections.load(Ordering::SeqCst);
+            let threads = counters.threads.load(Ordering::SeqCst);
+
+            println!(
+                "connections: {connections}, peak concurrent: {peak}, threads spawned: {threads}"
+            );
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(
+                dir.join("connections.txt"),
+                format!("{connections}, {peak}, {threads}\n"),
+            )
+            .unwrap();
+        }
+    }
+
+    if args.trials > 1 {
+        drop(trial_tx);
+        let trials = trial_rx.iter().collect::<Vec<_>>();
+        let path = dir.join(format!("trials.{}", options.format.extension()));
+        write_trial_stats(&trials, &options, &path).unwrap();
+    }
 }
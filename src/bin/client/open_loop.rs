@@ -1,113 +1,420 @@
 use std::{
-    net::{SocketAddrV4, TcpStream},
+    io::{self, Write},
+    net::{Shutdown, SocketAddr},
+    path::PathBuf,
     sync::{
         Arc,
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
     },
     thread::JoinHandle,
     time::{Duration, Instant},
 };
 
+use crossbeam_channel::{Receiver, Sender};
 use rust_server_benchmarks::{
-    get_time,
-    protocol::{Deserialize, LatencyRecord, Request, Response, Serialize, Work},
+    buffers::BufferSizes,
+    framing, get_time,
+    mix::Mix,
+    protocol::{
+        Deserialize, LatencyRecord, REQUEST_SIZE, RESPONSE_SIZE, Request, Response, Serialize,
+    },
+    proxy,
+};
+
+use crate::{
+    counters::{self, Counters},
+    rate::{Arrival, Feedback, RateController},
+    split::{client_share, seeded_rng},
+    stream::{Retry, Stream, Transport},
 };
 
+/// State shared between a client's sender and receiver.
+struct ClientState {
+    /// Set once the last request is about to be sent (or the server has stalled).
+    done: AtomicBool,
+
+    /// The number of requests sent, including the one being sent. Once `done` is set, the
+    /// receiver stops after the response to the last of them.
+    sent: AtomicU64,
+
+    /// The number of outstanding request and expected response bytes.
+    inflight: AtomicU64,
+
+    /// The number of requests sent but not yet answered.
+    outstanding: AtomicU64,
+
+    /// Requests sent before this are excluded from the latency records and request counts.
+    warmup_end: Instant,
+
+    /// The id of the first request sent after the warmup (`u64::MAX` until then).
+    first_recorded: AtomicU64,
+
+    /// Every request sent, in order, so the receiver can verify the checksums of their responses
+    /// and record how many requests were in flight when they were sent (unused when neither is
+    /// needed).
+    sent_tx: Sender<Sent>,
+    sent_rx: Receiver<Sent>,
+}
+
+impl ClientState {
+    /// Stops counting the client's outstanding requests as in flight across all clients, once
+    /// the sender or receiver stops (whichever stops last settles the requests the other left).
+    fn settle(&self, counters: &Counters) {
+        let outstanding = self.outstanding.swap(0, Ordering::SeqCst);
+        counters
+            .outstanding
+            .fetch_sub(outstanding, Ordering::SeqCst);
+    }
+}
+
+/// What the receiver needs to know about a request it didn't send.
+struct Sent {
+    req_id: u64,
+
+    /// The checksum it was sent with.
+    checksum: u32,
+
+    /// The number of requests in flight across all clients when it was sent, itself included.
+    inflight: u64,
+}
+
 pub struct Config {
     /// The address of the server.
-    pub addr: SocketAddrV4,
+    pub addr: SocketAddr,
 
     /// The duration of time for which the experiment is run.
     pub runtime: Duration,
 
+    /// How long requests are sent before the runtime starts. Requests sent during the warmup are
+    /// excluded from the latency records and request counts.
+    pub warmup: Duration,
+
     /// The delay between when a client receives a response and sends the next request.
     pub delay: Duration,
 
-    /// The work the server must do for the client.
-    pub work: Work,
+    /// The arrival pattern of requests (`delay` is used as the base or mean delay).
+    pub arrival: Arrival,
+
+    /// Correct the delay every second so each client actually sends at the rate `delay` gives,
+    /// instead of following `arrival`.
+    pub target_rate: bool,
+
+    /// Seeds every random choice (sampled work, Poisson arrivals), so the run can be reproduced.
+    pub seed: u64,
+
+    /// The work the server must do for each request.
+    pub work: Mix,
 
     /// The number of clients that are concurrently run.
     pub num_clients: usize,
+
+    /// The maximum number of outstanding request and expected response bytes per client.
+    /// The sender pauses while this limit would be exceeded.
+    pub max_inflight_bytes: Option<u64>,
+
+    /// The maximum number of requests sent but not yet answered per client. The sender pauses
+    /// once this many are outstanding, recording the stall.
+    pub max_inflight: Option<u64>,
+
+    /// Skip timestamping and latency records, only counting completed requests.
+    pub throughput_only: bool,
+
+    /// Measure latencies from when requests were scheduled to be sent rather than when they were
+    /// actually sent, so stalls that delay sending still count (coordinated omission).
+    pub correct_co: bool,
+
+    /// How long the receiver waits for a response (and the sender waits to send a request) before
+    /// giving up on the server.
+    pub request_timeout: Option<Duration>,
+
+    /// Send a PROXY protocol header of this version at the start of each connection.
+    pub proxy_protocol: Option<proxy::Version>,
+
+    /// The transport to connect over.
+    pub transport: Transport,
+
+    /// The path of the server's socket, when connecting over a Unix domain socket.
+    pub uds_path: PathBuf,
+
+    /// How refused connections are retried.
+    pub retry: Retry,
+
+    /// Leave Nagle's algorithm enabled on connections instead of setting `TCP_NODELAY`.
+    pub nagle: bool,
+
+    /// The send and receive buffer sizes to request for every connection.
+    pub buffers: BufferSizes,
+
+    /// Send a checksum with every request and count responses that report it corrupted.
+    pub verify_checksums: bool,
+
+    /// The body every request carries, which the server must echo back unchanged. Empty for
+    /// requests without one.
+    pub payload: Arc<[u8]>,
+
+    /// Stop once the clients have collectively sent this many requests after the warmup, instead
+    /// of after `runtime`. Each client sends its share.
+    pub total_requests: Option<usize>,
+
+    /// Give up on the server once no response has arrived for this long while requests are
+    /// outstanding (TCP only).
+    pub liveness_window: Option<Duration>,
 }
 
 impl Config {
-    pub fn run(self) -> (usize, Vec<LatencyRecord>) {
+    /// The number of bytes a single request/response exchange keeps in flight (at most, when
+    /// the work is mixed).
+    fn exchange_size(&self) -> u64 {
+        (REQUEST_SIZE + RESPONSE_SIZE + 2 * self.payload.len()) as u64 + self.work.max_payload_len()
+    }
+
+    /// Runs the open loop request generator, sending the latency records collected from all
+    /// clients to `tx`. It returns the number of requests sent.
+    pub fn run(self, tx: Sender<LatencyRecord>, counters: &Arc<Counters>) -> usize {
         let cfg = Arc::new(self);
 
         let handles: Vec<_> = (0..cfg.num_clients)
-            .map(|_| {
+            .map(|id| {
                 let cfg_clone = cfg.clone();
-                cfg_clone._run_client()
+                cfg_clone._run_client(id, tx.clone(), counters)
             })
             .collect();
 
+        // A client that stopped on an error reports what it did until then
+        let result = |id: usize, result: io::Result<usize>| {
+            result.unwrap_or_else(|e| {
+                eprintln!("client {id} stopped: {e}");
+                0
+            })
+        };
+
         let mut n_reqs = 0;
-        let mut lrs = Vec::new();
 
-        for handle in handles {
-            n_reqs += handle.0.join().unwrap();
-            lrs.append(&mut handle.1.join().unwrap());
+        for (id, handle) in handles.into_iter().enumerate() {
+            let (sender, receiver) = match handle {
+                Ok(handle) => handle,
+                Err(e) => {
+                    eprintln!("client {id} stopped: {e}");
+                    continue;
+                }
+            };
+            let sent = result(id, sender.join().unwrap());
+            let completed = result(id, receiver.join().unwrap());
+            n_reqs += sent;
+            counters.completed.fetch_add(completed, Ordering::SeqCst);
+            if cfg.transport == Transport::Udp {
+                counters
+                    .dropped
+                    .fetch_add(sent.saturating_sub(completed), Ordering::SeqCst);
+            }
+            counters.connection_closed();
         }
 
-        (n_reqs, lrs)
+        n_reqs
     }
 
-    /// Runs a single client of closed loop request generator. It returns the number of requests
-    /// sent and the latency records received.
-    fn _run_client(self: Arc<Self>) -> (JoinHandle<usize>, JoinHandle<Vec<LatencyRecord>>) {
-        let stream = TcpStream::connect(self.addr).unwrap();
-        stream.set_nodelay(true).unwrap();
+    /// Runs a single client of closed loop request generator. It returns the handles of the
+    /// sender, which returns the number of requests sent, and the receiver, which returns the
+    /// number of responses received, or an error if the client couldn't connect.
+    #[allow(clippy::type_complexity)]
+    fn _run_client(
+        self: Arc<Self>,
+        id: usize,
+        tx: Sender<LatencyRecord>,
+        counters: &Arc<Counters>,
+    ) -> io::Result<(JoinHandle<io::Result<usize>>, JoinHandle<io::Result<usize>>)> {
+        let (stream, connect_time) = Stream::connect(
+            self.addr,
+            &self.uds_path,
+            self.transport,
+            self.request_timeout,
+            self.proxy_protocol,
+            None,
+            self.retry,
+            self.nagle,
+            self.buffers,
+        )?;
+        counters.connection_opened(connect_time);
 
-        let done = Arc::new(AtomicBool::new(false));
+        let (sent_tx, sent_rx) = crossbeam_channel::unbounded();
+        let state = Arc::new(ClientState {
+            done: AtomicBool::new(false),
+            sent: AtomicU64::new(0),
+            inflight: AtomicU64::new(0),
+            outstanding: AtomicU64::new(0),
+            warmup_end: Instant::now() + self.warmup,
+            first_recorded: AtomicU64::new(u64::MAX),
+            sent_tx,
+            sent_rx,
+        });
 
         // Start the receiver (note: it is important to start the receiver first since spawning a
         // thread has substantial overhead and this can skew the latencies.
         let cfg_clone = self.clone();
-        let stream_clone = stream.try_clone().unwrap();
-        let done_clone = done.clone();
-        let receiver =
-            std::thread::spawn(move || cfg_clone._run_receiver(stream_clone, done_clone));
+        let stream_clone = stream.try_clone()?;
+        if let Some(window) = self.liveness_window {
+            // Only the receiver reads, so this doesn't affect the sender
+            stream_clone.tcp().set_read_timeout(Some(window))?;
+        }
+        let state_clone = state.clone();
+        let counters_clone = counters.clone();
+        let receiver = std::thread::spawn(move || {
+            let completed =
+                cfg_clone._run_receiver(id, stream_clone, &state_clone, tx, &counters_clone);
+            state_clone.settle(&counters_clone);
+            completed
+        });
 
         // Start the sender
-        let sender = std::thread::spawn(move || self._run_sender(stream, done));
+        let counters = counters.clone();
+        let sender = std::thread::spawn(move || {
+            let sent = self._run_sender(id, stream, &state, &counters);
+            state.settle(&counters);
+            sent
+        });
 
-        (sender, receiver)
+        Ok((sender, receiver))
     }
 
-    /// Sends requests to the server.
-    fn _run_sender(&self, mut stream: TcpStream, done: Arc<AtomicBool>) -> usize {
+    /// Sends requests to the server. It returns the number of requests sent, or an error if the
+    /// client couldn't go on (which stops the receiver too). A request that times out or finds
+    /// the connection closed is counted as failed instead, and stops the client early.
+    fn _run_sender(
+        &self,
+        id: usize,
+        mut stream: Stream,
+        state: &ClientState,
+        counters: &Counters,
+    ) -> io::Result<usize> {
         let client_start = Instant::now();
         let mut excess_duration = Duration::from_micros(0);
+        let mut rate: Box<dyn RateController> = if self.target_rate {
+            Box::new(Feedback::new(self.delay))
+        } else {
+            self.arrival
+                .controller(self.delay, self.runtime, seeded_rng(self.seed, 2 * id + 1))
+        };
+        let mut rng = seeded_rng(self.seed, 2 * id);
+
+        // When the next request is scheduled to be sent
+        let mut intended_send_time = get_time();
 
         let mut requests_sent = 0;
+        let mut next_id = 0;
+
+        // The number of requests this client may send after the warmup
+        let share = self
+            .total_requests
+            .map(|total| client_share(total, self.num_clients, id));
 
         loop {
             let start = Instant::now();
 
-            // We have to make sure there is an outstanding request before `done` is
-            // true to avoid deadlocking the receiver when the last request has been sent.
-            let is_last = client_start.elapsed() >= self.runtime;
+            // Wait for the receiver to drain enough responses to stay under the byte limit (we
+            // always allow one exchange so that a tiny limit can't stall the client).
+            if let Some(limit) = self.max_inflight_bytes {
+                loop {
+                    let bytes = state.inflight.load(Ordering::SeqCst);
+                    if bytes == 0
+                        || bytes + self.exchange_size() <= limit
+                        || state.done.load(Ordering::SeqCst)
+                    {
+                        break;
+                    }
+                    std::hint::spin_loop();
+                }
+            }
+
+            // Wait for the receiver to catch up once too many requests are outstanding, rather
+            // than letting them (and the receiver's backlog) grow without bound
+            if let Some(limit) = self.max_inflight
+                && state.outstanding.load(Ordering::SeqCst) >= limit
+            {
+                let stall_start = Instant::now();
+                while state.outstanding.load(Ordering::SeqCst) >= limit
+                    && !state.done.load(Ordering::SeqCst)
+                {
+                    std::hint::spin_loop();
+                }
+                counters.record_stall(stall_start.elapsed());
+            }
+
+            // The receiver gave up on the server
+            if state.done.load(Ordering::SeqCst) {
+                return Ok(requests_sent);
+            }
+
+            // The request is counted before `done` is set, so the receiver knows how many
+            // responses to wait for once it sees `done`
+            state.sent.fetch_add(1, Ordering::SeqCst);
+            let warm = Instant::now() >= state.warmup_end;
+            let is_last = match share {
+                Some(share) => warm && requests_sent + 1 >= share,
+                None => client_start.elapsed() >= self.warmup + self.runtime,
+            };
             if is_last {
-                done.store(true, Ordering::SeqCst);
+                state.done.store(true, Ordering::SeqCst);
+            }
+
+            if warm && requests_sent == 0 {
+                state.first_recorded.store(next_id, Ordering::SeqCst);
             }
 
             // Serialize and send request
-            let req = Request {
-                send_time: get_time(),
-                work: self.work,
+            let work = self.work.sample(&mut rng);
+            let send_time = match (self.throughput_only, self.correct_co) {
+                (true, _) => 0,
+                (false, true) => intended_send_time.min(get_time()),
+                (false, false) => get_time(),
             };
-            req.serialize(&mut stream).unwrap();
+            let req = Request::new(send_time, next_id, work, self.verify_checksums)
+                .with_body(self.payload.to_vec());
+            // Counted across all clients first, so that count never falls below the sum of the
+            // clients' own
+            let inflight = counters.outstanding.fetch_add(1, Ordering::SeqCst) + 1;
+            if self.verify_checksums || !self.throughput_only {
+                // Queued before sending, so it is there by the time the response arrives
+                let sent = Sent {
+                    req_id: next_id,
+                    checksum: req.checksum,
+                    inflight,
+                };
+                state.sent_tx.send(sent).unwrap();
+            }
+            next_id += 1;
+            state
+                .inflight
+                .fetch_add(self.exchange_size(), Ordering::SeqCst);
+            state.outstanding.fetch_add(1, Ordering::SeqCst);
+            if let Err(e) = req.serialize(&mut stream).and_then(|_| stream.flush()) {
+                // The receiver gave up on the server and shut the connection down
+                if state.done.load(Ordering::SeqCst) {
+                    return Ok(requests_sent);
+                }
 
-            if is_last {
-                return requests_sent;
+                // The request never made it, so the receiver mustn't wait for it
+                state.sent.fetch_sub(1, Ordering::SeqCst);
+                state.done.store(true, Ordering::SeqCst);
+                if counters.record_timeout(&e) || counters.record_error(&e, 1) {
+                    return Ok(requests_sent);
+                }
+                return Err(e);
+            }
+
+            if warm {
+                requests_sent += 1;
             }
 
-            requests_sent += 1;
+            if is_last {
+                return Ok(requests_sent);
+            }
 
             // Factor in the excess time
+            let delay = rate.next_delay();
+            intended_send_time += delay.as_nanos() as u64;
             excess_duration += start.elapsed();
-            let excess_delay = excess_duration.min(self.delay);
-            let busy_wait_time = self.delay - excess_delay;
+            let excess_delay = excess_duration.min(delay);
+            let busy_wait_time = delay - excess_delay;
             excess_duration -= excess_delay;
 
             // Busy loop
@@ -118,16 +425,137 @@ impl Config {
         }
     }
 
-    /// Receives responses from the server.
-    fn _run_receiver(&self, mut stream: TcpStream, done: Arc<AtomicBool>) -> Vec<LatencyRecord> {
-        let mut lrs = Vec::new();
+    /// Receives responses from the server until the last request has been answered (or the
+    /// server closes the connection). It returns the number of responses received, or an error if
+    /// the client couldn't go on (which stops the sender too). Over UDP, it gives up on the
+    /// remaining responses once a read times out after the last request was sent.
+    fn _run_receiver(
+        &self,
+        id: usize,
+        mut stream: Stream,
+        state: &ClientState,
+        tx: Sender<LatencyRecord>,
+        counters: &Counters,
+    ) -> io::Result<usize> {
+        let mut completed = 0;
+        let udp = stream.is_udp();
+
+        // The id of the request the next response should answer
+        let mut next_id = 0;
+
+        loop {
+            if state.done.load(Ordering::SeqCst) && next_id >= state.sent.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let response = match Response::deserialize(&mut stream) {
+                Ok(response) => response,
+                Err(e) if self.liveness_window.is_some() && counters::is_timeout(&e) => {
+                    let unacknowledged = state.sent.load(Ordering::SeqCst).saturating_sub(next_id);
+                    if unacknowledged == 0 {
+                        // Nothing is outstanding, so the server isn't expected to respond
+                        continue;
+                    }
+
+                    eprintln!(
+                        "client {id} got no response for {:?}, giving up on the server",
+                        self.liveness_window.unwrap()
+                    );
+                    counters
+                        .unacknowledged
+                        .fetch_add(unacknowledged as usize, Ordering::SeqCst);
 
-        while !done.load(Ordering::SeqCst) {
-            let response = Response::deserialize(&mut stream).unwrap();
-            let lr = response.to_latency_record();
-            lrs.push(lr);
+                    // Stop the sender, waking it up if it is blocked on a full send buffer
+                    state.done.store(true, Ordering::SeqCst);
+                    let _ = stream.tcp().shutdown(Shutdown::Both);
+                    break;
+                }
+                Err(e) if udp && counters::is_timeout(&e) => {
+                    if state.done.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    // Whatever was in flight has been lost (counted once the client finishes)
+                    state.inflight.store(0, Ordering::SeqCst);
+                    state.settle(counters);
+                    continue;
+                }
+                Err(e)
+                    if counters::is_timeout(&e) && state.sent.load(Ordering::SeqCst) <= next_id =>
+                {
+                    // Nothing is outstanding, so the server isn't expected to respond
+                    continue;
+                }
+                Err(e) if counters.record_timeout(&e) => {
+                    // Stop the sender too, since the server has stalled
+                    state.done.store(true, Ordering::SeqCst);
+                    break;
+                }
+                Err(e) if framing::is_disconnect(&e) => {
+                    eprintln!("the server closed the connection of client {id}");
+                    state.done.store(true, Ordering::SeqCst);
+                    let unacknowledged = state.sent.load(Ordering::SeqCst).saturating_sub(next_id);
+                    counters.record_error(&e, unacknowledged as usize);
+                    break;
+                }
+                Err(e) => {
+                    state.done.store(true, Ordering::SeqCst);
+                    return Err(e);
+                }
+            };
+            // Responses arrive in order (skipping lost ones over UDP), but the receiver doesn't
+            // track which work each was for
+            if udp {
+                next_id = next_id.max(response.req_id);
+            }
+            let verified = response
+                .verify_id(next_id)
+                .and_then(|_| self.work.verify(&response))
+                .and_then(|_| response.verify_body(&self.payload));
+            if let Err(e) = verified {
+                state.done.store(true, Ordering::SeqCst);
+                return Err(e);
+            }
+            next_id += 1;
+            let mut inflight = 0;
+            if self.verify_checksums || !self.throughput_only {
+                // Skip the requests whose responses were lost (UDP only)
+                let sent = loop {
+                    let sent = state.sent_rx.try_recv().unwrap();
+                    if sent.req_id == response.req_id {
+                        break sent;
+                    }
+                };
+                if self.verify_checksums {
+                    counters.verify_checksum(&response, sent.checksum);
+                }
+                inflight = sent.inflight;
+            }
+            // A late UDP response may arrive after its bytes were written off as lost
+            let _ = state
+                .inflight
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |bytes| {
+                    Some(bytes.saturating_sub(self.exchange_size()))
+                });
+            let outstanding =
+                state
+                    .outstanding
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                        Some(n.saturating_sub(1))
+                    });
+            if outstanding.is_ok_and(|n| n > 0) {
+                counters.outstanding.fetch_sub(1, Ordering::SeqCst);
+            }
+            if response.req_id < state.first_recorded.load(Ordering::SeqCst)
+                || counters.record_busy(&response)
+            {
+                continue;
+            }
+            completed += 1;
+            if !self.throughput_only {
+                tx.send(response.to_latency_record(id, inflight)).unwrap();
+            }
         }
 
-        lrs
+        Ok(completed)
     }
 }
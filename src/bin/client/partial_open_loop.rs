@@ -1,63 +1,142 @@
 use std::{
-    net::{SocketAddrV4, TcpStream},
+    io::{self, Write},
+    net::SocketAddr,
     sync::{
         Arc,
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
     },
     thread::JoinHandle,
     time::{Duration, Instant},
 };
 
 use rust_server_benchmarks::{
+    buffers::BufferSizes,
     get_time,
-    protocol::{Deserialize, LatencyRecord, Request, Response, Serialize, Work},
+    mix::Mix,
+    protocol::{Deserialize, LatencyRecord, Request, Response, Serialize},
+    proxy,
 };
 
 use crossbeam_channel::{Receiver, Sender, unbounded};
+use rustls::ClientConfig;
+
+use crate::{
+    counters::Counters,
+    rate::Arrival,
+    split::seeded_rng,
+    stream::{Retry, Stream},
+};
+
+/// State shared between the generator and its client threads.
+struct WorkerState {
+    /// Number of idle threads.
+    ready: AtomicU64,
+
+    /// Tells busy threads to stop between requests once the runtime has expired.
+    shutdown: AtomicBool,
+
+    /// Requests sent before this are excluded from the latency records and request counts.
+    warmup_end: Instant,
+}
 
-#[derive(Copy, Clone)]
 pub struct Config {
     /// The address of the server.
-    pub addr: SocketAddrV4,
+    pub addr: SocketAddr,
 
     /// The duration of time for which the experiment is run.
     pub runtime: Duration,
 
+    /// How long requests are sent before the runtime starts. Requests sent during the warmup are
+    /// excluded from the latency records and request counts.
+    pub warmup: Duration,
+
     /// The delay between when a client receives a response and sends the next request.
     pub delay: Duration,
 
-    /// The work the server must do for the client.
-    pub work: Work,
+    /// The arrival pattern of batches (`delay` is used as the base or mean delay).
+    pub arrival: Arrival,
+
+    /// Seeds every random choice (sampled work, Poisson arrivals), so the run can be reproduced.
+    pub seed: u64,
+
+    /// The work the server must do for each request.
+    pub work: Mix,
 
     /// The maximum number of client threads that can be running concurrently.
     pub max_threads: usize,
 
     /// Number of requests each client sends.
     pub num_requests: usize,
+
+    /// How long a client waits on the server before giving up on its batch of requests.
+    pub timeout: Duration,
+
+    /// Skip timestamping and latency records, only counting completed requests.
+    pub throughput_only: bool,
+    /// Send a PROXY protocol header of this version at the start of each connection.
+    pub proxy_protocol: Option<proxy::Version>,
+
+    /// Connect over TLS with this configuration.
+    pub tls: Option<Arc<ClientConfig>>,
+
+    /// Alternate new connections between `tls` and this configuration, which never resumes a
+    /// session, completing and timing every handshake before the batch so that full and
+    /// resumed handshakes can be compared.
+    pub tls_without_resumption: Option<Arc<ClientConfig>>,
+
+    /// Keep each thread's connection open between batches instead of opening a new one for
+    /// every batch.
+    pub keep_alive: bool,
+
+    /// How refused connections are retried.
+    pub retry: Retry,
+
+    /// Leave Nagle's algorithm enabled on connections instead of setting `TCP_NODELAY`.
+    pub nagle: bool,
+
+    /// The send and receive buffer sizes to request for every connection.
+    pub buffers: BufferSizes,
+
+    /// Send a checksum with every request and count responses that report it corrupted.
+    pub verify_checksums: bool,
+
+    /// The body every request carries, which the server must echo back unchanged. Empty for
+    /// requests without one.
+    pub payload: Arc<[u8]>,
 }
 
 impl Config {
-    pub fn run(self) -> Vec<LatencyRecord> {
+    /// Runs the partial open loop request generator, sending the latency records collected from
+    /// all clients to `lr_tx`. It returns the number of requests sent.
+    pub fn run(self, lr_tx: Sender<LatencyRecord>, counters: &Arc<Counters>) -> usize {
         let start = Instant::now();
         let mut excess_duration = Duration::from_micros(0);
+        let mut rate = self
+            .arrival
+            .controller(self.delay, self.runtime, seeded_rng(self.seed, 1));
+        let cfg = Arc::new(self);
 
         // Notifications for the threads run
         let (tx, rx) = unbounded();
 
-        // Number of idle threads
-        let ready = Arc::new(AtomicU64::new(0));
+        let state = Arc::new(WorkerState {
+            ready: AtomicU64::new(0),
+            shutdown: AtomicBool::new(false),
+            warmup_end: start + cfg.warmup,
+        });
 
-        let mut handles: Vec<JoinHandle<Vec<LatencyRecord>>> = Vec::new();
+        let mut handles: Vec<JoinHandle<io::Result<usize>>> = Vec::new();
 
-        while start.elapsed() < self.runtime {
+        while start.elapsed() < cfg.warmup + cfg.runtime {
             let iter_start = Instant::now();
 
-            self._run_client(&tx, &rx, &ready, &mut handles);
+            cfg._run_client(&tx, &rx, &lr_tx, &state, counters, &mut handles);
 
             // Factor in the excess time
+            let delay = rate.next_delay();
             excess_duration += iter_start.elapsed();
-            let excess_delay = excess_duration.min(self.delay);
-            let busy_wait_time = self.delay - excess_delay;
+            let excess_delay = excess_duration.min(delay);
+            let busy_wait_time = delay - excess_delay;
             excess_duration -= excess_delay;
 
             // Busy loop
@@ -67,48 +146,47 @@ impl Config {
             }
         }
 
+        // Stop busy threads between requests, since a stalled server could keep
+        // them from ever finishing their batch.
+        state.shutdown.store(true, Ordering::SeqCst);
+
         // Drop the sender so that receivers will exit out of the receive loop.
         // Otherwise, we'll deadlock.
         drop(tx);
 
+        // A worker that stopped on an error reports nothing
         handles
             .into_iter()
-            .flat_map(|v| v.join().unwrap())
-            .collect()
+            .enumerate()
+            .map(|(id, handle)| {
+                handle.join().unwrap().unwrap_or_else(|e| {
+                    eprintln!("worker {id} stopped: {e}");
+                    0
+                })
+            })
+            .sum()
     }
 
     fn _run_client(
-        self,
+        self: &Arc<Self>,
         tx: &Sender<()>,
         rx: &Receiver<()>,
-        ready: &Arc<AtomicU64>,
-        handles: &mut Vec<JoinHandle<Vec<LatencyRecord>>>,
+        lr_tx: &Sender<LatencyRecord>,
+        state: &Arc<WorkerState>,
+        counters: &Arc<Counters>,
+        handles: &mut Vec<JoinHandle<io::Result<usize>>>,
     ) {
         // If all threads are busy and we haven't reached the threadpool capacity, spawn another thread.
-        if ready.load(Ordering::SeqCst) == 0 && handles.len() < self.max_threads {
+        if state.ready.load(Ordering::SeqCst) == 0 && handles.len() < self.max_threads {
+            let id = handles.len();
             let rx = rx.clone();
-            let ready = ready.clone();
-            let handle = std::thread::spawn(move || {
-                let mut lrs = Vec::new();
-
-                for _ in rx {
-                    ready.fetch_sub(1, Ordering::SeqCst);
-                    let mut stream = TcpStream::connect(self.addr).unwrap();
-                    for _ in 0..self.num_requests {
-                        let req = Request {
-                            send_time: get_time(),
-                            work: self.work,
-                        };
-                        req.serialize(&mut stream).unwrap();
-
-                        let resp = Response::deserialize(&mut stream).unwrap();
-                        lrs.push(resp.to_latency_record());
-                    }
-                    ready.fetch_add(1, Ordering::SeqCst);
-                }
-
-                lrs
-            });
+            let lr_tx = lr_tx.clone();
+            let state = state.clone();
+            let counters = counters.clone();
+            let cfg = self.clone();
+            counters.threads.fetch_add(1, Ordering::SeqCst);
+            let handle =
+                std::thread::spawn(move || cfg._run_worker(id, rx, &lr_tx, &state, &counters));
 
             handles.push(handle);
         }
@@ -116,4 +194,124 @@ impl Config {
         // Either way, send a notification.
         tx.send(()).unwrap();
     }
+
+    /// Runs a worker thread, which sends a batch of requests for every notification. It returns
+    /// the number of requests sent, or an error if the worker couldn't go on. A request that
+    /// times out or finds the connection closed is counted as failed instead, and ends its batch.
+    fn _run_worker(
+        &self,
+        id: usize,
+        rx: Receiver<()>,
+        lr_tx: &Sender<LatencyRecord>,
+        state: &WorkerState,
+        counters: &Counters,
+    ) -> io::Result<usize> {
+        let mut requests_sent = 0;
+        let mut completed = 0;
+        let mut rng = seeded_rng(self.seed, 2 * id);
+
+        // The connection kept between batches with keep-alive
+        let mut conn: Option<(Stream, u64)> = None;
+        let mut connections = 0;
+
+        for _ in rx {
+            if state.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+
+            state.ready.fetch_sub(1, Ordering::SeqCst);
+            let (mut stream, mut next_id) = match conn.take() {
+                Some(conn) => conn,
+                None => {
+                    let (mut stream, connect_time) = self.retry.connect(self.addr, self.buffers)?;
+                    stream.set_nodelay(!self.nagle)?;
+                    stream.set_read_timeout(Some(self.timeout))?;
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    if let Some(version) = self.proxy_protocol {
+                        let (src, dst) = (stream.local_addr()?, stream.peer_addr()?);
+                        proxy::write_header(&mut stream, version, src, dst)?;
+                    }
+                    counters.connection_opened(connect_time);
+
+                    // Every other connection skips resumption when comparing handshakes
+                    let tls = match &self.tls_without_resumption {
+                        Some(config) if connections % 2 == 0 => Some(config),
+                        _ => self.tls.as_ref(),
+                    };
+                    connections += 1;
+                    let mut stream = Stream::new(stream, tls)?;
+                    if self.tls_without_resumption.is_some() {
+                        let start = Instant::now();
+                        if let Some(kind) = stream.handshake()? {
+                            counters.record_handshake(kind, start.elapsed());
+                        }
+                    }
+                    (stream, 0)
+                }
+            };
+
+            // Whether the whole batch was answered, so the connection can be reused
+            let mut answered = true;
+            for _ in 0..self.num_requests {
+                let req_id = next_id;
+                next_id += 1;
+                if state.shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let warm = Instant::now() >= state.warmup_end;
+                let work = self.work.sample(&mut rng);
+                let send_time = if self.throughput_only { 0 } else { get_time() };
+                let req = Request::new(send_time, req_id, work, self.verify_checksums)
+                    .with_body(self.payload.to_vec());
+                let checksum = req.checksum;
+                let inflight = counters.request_sent();
+                if let Err(e) = req.serialize(&mut stream).and_then(|_| stream.flush()) {
+                    if counters.record_timeout(&e) || counters.record_error(&e, 1) {
+                        answered = false;
+                        break;
+                    }
+                    return Err(e);
+                }
+                if warm {
+                    requests_sent += 1;
+                }
+
+                // Give up on the batch if the server stalls or closes the connection
+                let resp = match Response::deserialize(&mut stream) {
+                    Ok(resp) => resp,
+                    Err(e) if counters.record_timeout(&e) || counters.record_error(&e, 1) => {
+                        answered = false;
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                };
+                resp.verify_id(req_id)?;
+                resp.verify(&work)?;
+                resp.verify_body(&self.payload)?;
+                counters.verify_checksum(&resp, checksum);
+                if !warm || counters.record_busy(&resp) {
+                    continue;
+                }
+                completed += 1;
+                if !self.throughput_only {
+                    lr_tx
+                        .send(resp.to_latency_record(id, inflight.count))
+                        .unwrap();
+                }
+            }
+            if answered && self.keep_alive {
+                conn = Some((stream, next_id));
+            } else {
+                counters.connection_closed();
+            }
+            state.ready.fetch_add(1, Ordering::SeqCst);
+        }
+        if conn.is_some() {
+            counters.connection_closed();
+        }
+
+        counters.completed.fetch_add(completed, Ordering::SeqCst);
+        Ok(requests_sent)
+    }
 }
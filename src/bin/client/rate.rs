@@ -0,0 +1,384 @@
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use rand::{Rng, rngs::StdRng};
+
+/// Decides how long a sender waits between consecutive requests.
+pub trait RateController: Send {
+    /// Returns the delay before the next request is sent.
+    fn next_delay(&mut self) -> Duration;
+}
+
+/// Sends requests at a fixed interval.
+pub struct Fixed {
+    delay: Duration,
+}
+
+impl Fixed {
+    pub fn new(delay: Duration) -> Self {
+        Self { delay }
+    }
+}
+
+impl RateController for Fixed {
+    fn next_delay(&mut self) -> Duration {
+        self.delay
+    }
+}
+
+/// Sends requests as a Poisson process, i.e. with exponentially distributed
+/// inter-arrival times.
+pub struct Poisson {
+    /// The mean inter-arrival time.
+    mean: Duration,
+
+    rng: StdRng,
+}
+
+impl Poisson {
+    pub fn new(mean: Duration, rng: StdRng) -> Self {
+        Self { mean, rng }
+    }
+}
+
+impl RateController for Poisson {
+    fn next_delay(&mut self) -> Duration {
+        // Inverse transform sampling of the exponential distribution
+        let u: f64 = self.rng.random();
+        self.mean.mul_f64(-(1.0 - u).ln())
+    }
+}
+
+/// Sends requests at the interval `target`, correcting the delay once a
+/// second by how far the interval requests were actually sent at was off, so
+/// the time spent sending them and busy-wait jitter don't lower the rate.
+pub struct Feedback {
+    target: Duration,
+
+    /// The delay currently handed out.
+    delay: Duration,
+
+    /// When the current second started.
+    window_start: Instant,
+
+    /// The number of delays handed out in the current second.
+    sent: u32,
+}
+
+impl Feedback {
+    pub fn new(target: Duration) -> Self {
+        Self {
+            target,
+            delay: target,
+            window_start: Instant::now(),
+            sent: 0,
+        }
+    }
+}
+
+impl RateController for Feedback {
+    fn next_delay(&mut self) -> Duration {
+        self.sent += 1;
+
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            let actual = elapsed / self.sent;
+            self.delay = (self.delay + self.target).saturating_sub(actual);
+            self.window_start = Instant::now();
+            self.sent = 0;
+        }
+
+        self.delay
+    }
+}
+
+/// Linearly moves the delay from `start` to `end` over `length`, then holds
+/// it at `end`. Progress is measured in wall-clock time from when the ramp is
+/// created, so it advances even while the delay is zero.
+pub struct Ramp {
+    start: Duration,
+    end: Duration,
+    length: Duration,
+
+    /// When the ramp started.
+    started: Instant,
+}
+
+impl Ramp {
+    pub fn new(start: Duration, end: Duration, length: Duration) -> Self {
+        Self {
+            start,
+            end,
+            length,
+            started: Instant::now(),
+        }
+    }
+
+    /// Gets the delay `elapsed` into the ramp.
+    fn delay_at(&self, elapsed: Duration) -> Duration {
+        let progress = if self.length.is_zero() {
+            1.0
+        } else {
+            (elapsed.as_secs_f64() / self.length.as_secs_f64()).min(1.0)
+        };
+
+        let start = self.start.as_secs_f64();
+        let end = self.end.as_secs_f64();
+        Duration::from_secs_f64(start + (end - start) * progress)
+    }
+}
+
+impl RateController for Ramp {
+    fn next_delay(&mut self) -> Duration {
+        self.delay_at(self.started.elapsed())
+    }
+}
+
+/// Steps through a list of `(length, delay)` phases, holding the last delay
+/// once the schedule is exhausted. Phases are timed in wall-clock time from
+/// when the schedule is created, so a phase with a zero delay still ends.
+pub struct Schedule {
+    phases: Vec<(Duration, Duration)>,
+
+    /// When the schedule started.
+    started: Instant,
+}
+
+impl Schedule {
+    pub fn new(phases: Vec<(Duration, Duration)>) -> Self {
+        assert!(!phases.is_empty(), "a schedule needs at least one phase");
+        Self {
+            phases,
+            started: Instant::now(),
+        }
+    }
+
+    /// Gets the delay of the phase `elapsed` into the schedule.
+    fn delay_at(&self, elapsed: Duration) -> Duration {
+        let mut end = Duration::ZERO;
+        for &(length, delay) in &self.phases {
+            end += length;
+            if elapsed < end {
+                return delay;
+            }
+        }
+
+        self.phases[self.phases.len() - 1].1
+    }
+}
+
+impl RateController for Schedule {
+    fn next_delay(&mut self) -> Duration {
+        self.delay_at(self.started.elapsed())
+    }
+}
+
+/// Converts a (possibly fractional) number of microseconds to a duration, to
+/// the nearest nanosecond, so sub-microsecond pacing can be expressed.
+pub fn from_micros(micros: f64) -> Duration {
+    Duration::from_nanos((micros * 1000.0).round() as u64)
+}
+
+/// The arrival pattern of requests, as selected on the command line.
+///
+/// * `fixed` - a constant delay.
+/// * `poisson` - exponentially distributed delays with the given mean.
+/// * `ramp:<micros>` - ramp the delay to `<micros>` over the runtime.
+/// * `schedule:<secs>=<micros>,...` - run each delay for the given number of seconds.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Arrival {
+    Fixed,
+    Poisson,
+    Ramp { end: Duration },
+    Schedule { phases: Vec<(Duration, Duration)> },
+}
+
+impl Arrival {
+    /// Builds a rate controller for one sender.
+    ///
+    /// # Arguments
+    ///
+    /// * `delay` - The (mean) delay between requests.
+    /// * `runtime` - The duration of the experiment.
+    /// * `rng` - The source of randomness of random arrivals.
+    pub fn controller(
+        &self,
+        delay: Duration,
+        runtime: Duration,
+        rng: StdRng,
+    ) -> Box<dyn RateController> {
+        match self {
+            Arrival::Fixed => Box::new(Fixed::new(delay)),
+            Arrival::Poisson => Box::new(Poisson::new(delay, rng)),
+            Arrival::Ramp { end } => Box::new(Ramp::new(delay, *end, runtime)),
+            Arrival::Schedule { phases } => Box::new(Schedule::new(phases.clone())),
+        }
+    }
+}
+
+impl FromStr for Arrival {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, params) = s.split_once(':').unwrap_or((s, ""));
+
+        let parse_u64 = |v: &str| {
+            v.trim()
+                .parse::<u64>()
+                .map_err(|e| format!("invalid number '{v}': {e}"))
+        };
+
+        let parse_micros = |v: &str| {
+            v.trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|micros| micros.is_finite() && *micros >= 0.0)
+                .map(from_micros)
+                .ok_or(format!("invalid delay '{v}'"))
+        };
+
+        match kind {
+            "fixed" => Ok(Arrival::Fixed),
+            "poisson" => Ok(Arrival::Poisson),
+            "ramp" => Ok(Arrival::Ramp {
+                end: parse_micros(params)?,
+            }),
+            "schedule" => {
+                let phases = params
+                    .split(',')
+                    .map(|phase| {
+                        let (secs, micros) = phase
+                            .split_once('=')
+                            .ok_or(format!("expected <secs>=<micros>, got '{phase}'"))?;
+                        Ok((Duration::from_secs(parse_u64(secs)?), parse_micros(micros)?))
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+                Ok(Arrival::Schedule { phases })
+            }
+            _ => Err(format!("unknown arrival pattern '{kind}'")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use rand::SeedableRng;
+
+    use super::*;
+
+    /// The first `n` delays `controller` hands out.
+    fn delays(controller: &mut dyn RateController, n: usize) -> Vec<Duration> {
+        (0..n).map(|_| controller.next_delay()).collect()
+    }
+
+    #[test]
+    fn fixed() {
+        let delay = Duration::from_micros(5);
+        assert_eq!(delays(&mut Fixed::new(delay), 100), vec![delay; 100]);
+    }
+
+    #[test]
+    fn fractional_micros() {
+        let delay = Duration::from_nanos(1500);
+        assert_eq!(from_micros(1.5), delay);
+        assert_eq!(
+            delays(&mut Fixed::new(from_micros(1.5)), 10),
+            vec![delay; 10]
+        );
+
+        let Ok(Arrival::Ramp { end }) = "ramp:0.25".parse() else {
+            panic!("failed to parse a fractional ramp");
+        };
+        assert_eq!(end, Duration::from_nanos(250));
+    }
+
+    #[test]
+    fn poisson() {
+        let mean = Duration::from_micros(100);
+        let sample = || delays(&mut Poisson::new(mean, StdRng::seed_from_u64(7)), 10_000);
+
+        // The same seed gives the same delays, which average out to the mean
+        let first = sample();
+        assert_eq!(first, sample());
+        let average = first.iter().sum::<Duration>() / first.len() as u32;
+        assert!(
+            average.abs_diff(mean) < mean / 20,
+            "averaged {average:?}, expected {mean:?}"
+        );
+    }
+
+    #[test]
+    fn feedback() {
+        let target = Duration::from_millis(10);
+        let mut feedback = Feedback::new(target);
+        assert_eq!(delays(&mut feedback, 10), vec![target; 10]);
+
+        // Only 80 requests went out in the last second, 12.5 ms apart instead of 10 ms, so the
+        // delay is shortened by the difference
+        feedback.window_start -= Duration::from_secs(1);
+        feedback.sent = 79;
+        let corrected = feedback.next_delay();
+        assert!(
+            corrected <= Duration::from_micros(7500) && corrected > Duration::from_micros(7400),
+            "corrected to {corrected:?}"
+        );
+        assert_eq!(feedback.next_delay(), corrected);
+    }
+
+    #[test]
+    fn ramp() {
+        let ramp = Ramp::new(
+            Duration::from_micros(100),
+            Duration::from_micros(20),
+            Duration::from_secs(10),
+        );
+        let at = |secs| ramp.delay_at(Duration::from_secs_f64(secs));
+        assert_eq!(at(0.0), Duration::from_micros(100));
+        assert_eq!(at(2.5), Duration::from_micros(80));
+        assert_eq!(at(5.0), Duration::from_micros(60));
+        assert_eq!(at(10.0), Duration::from_micros(20));
+        assert_eq!(at(20.0), Duration::from_micros(20));
+    }
+
+    #[test]
+    fn ramp_from_zero_delay() {
+        let end = Duration::from_micros(50);
+        let mut ramp = Ramp::new(Duration::ZERO, end, Duration::from_millis(20));
+        assert!(ramp.next_delay() < end);
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(ramp.next_delay(), end);
+    }
+
+    #[test]
+    fn schedule() {
+        let schedule = Schedule::new(vec![
+            (Duration::from_secs(2), Duration::from_micros(10)),
+            (Duration::from_secs(1), Duration::from_micros(5)),
+            (Duration::from_secs(3), Duration::from_micros(20)),
+        ]);
+        let at = |secs| schedule.delay_at(Duration::from_secs_f64(secs));
+        assert_eq!(at(0.0), Duration::from_micros(10));
+        assert_eq!(at(1.9), Duration::from_micros(10));
+        assert_eq!(at(2.0), Duration::from_micros(5));
+        assert_eq!(at(3.5), Duration::from_micros(20));
+        assert_eq!(at(60.0), Duration::from_micros(20));
+    }
+
+    #[test]
+    fn schedule_past_zero_delay_phase() {
+        let mut schedule = Schedule::new(vec![
+            (Duration::from_millis(20), Duration::ZERO),
+            (Duration::from_secs(1), Duration::from_micros(5)),
+        ]);
+        assert_eq!(delays(&mut schedule, 1000), vec![Duration::ZERO; 1000]);
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(schedule.next_delay(), Duration::from_micros(5));
+    }
+}
@@ -0,0 +1,17 @@
+//! How a run is split between its clients.
+
+use rand::{SeedableRng, rngs::StdRng};
+
+/// Splits `total` requests as evenly as possible between `num_clients`
+/// clients, returning the share of client `id`.
+pub fn client_share(total: usize, num_clients: usize, id: usize) -> usize {
+    total / num_clients + usize::from(id < total % num_clients)
+}
+
+/// Seeds the random number generator of one source of randomness in the run,
+/// e.g. client `i`'s workload (stream `2 * i`) or arrivals (stream `2 * i + 1`),
+/// from the run's seed.
+pub fn seeded_rng(seed: u64, stream: usize) -> StdRng {
+    // Spread the streams apart so neighbouring ones don't start from related seeds
+    StdRng::seed_from_u64(seed ^ (stream as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15))
+}
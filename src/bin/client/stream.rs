@@ -0,0 +1,360 @@
+use std::{
+    fmt::Display,
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream, UdpSocket},
+    os::unix::net::UnixStream,
+    path::Path,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use rust_server_benchmarks::{
+    buffers::{BufferSizes, Granted},
+    proxy,
+    tls::TlsStream,
+};
+use rustls::{ClientConfig, ClientConnection, HandshakeKind, pki_types::ServerName};
+use socket2::{Domain, SockRef, Socket, Type};
+
+/// The largest datagram a response can arrive in.
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// How long to wait for a response datagram before counting it as dropped, if no request timeout
+/// is given.
+const DEFAULT_UDP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How connecting retries connections the server refuses, e.g. because it hasn't started
+/// listening yet.
+#[derive(Clone, Copy, Debug)]
+pub struct Retry {
+    /// The number of times a refused connection is retried.
+    pub retries: u32,
+
+    /// How long to wait before the first retry. The wait doubles after every retry.
+    pub backoff: Duration,
+}
+
+impl Retry {
+    /// Connects to `addr` with a socket given `buffers` before connecting, retrying refused
+    /// connections with exponential backoff. It returns the stream and how long the successful
+    /// attempt took.
+    pub fn connect(
+        self,
+        addr: SocketAddr,
+        buffers: BufferSizes,
+    ) -> io::Result<(TcpStream, Duration)> {
+        self.connect_with(addr, || {
+            let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+            buffers.apply(SockRef::from(&socket))?;
+            socket.connect(&addr.into())?;
+            Ok(socket.into())
+        })
+    }
+
+    /// Connects to the Unix domain socket at `path` like `connect`, also retrying while the
+    /// socket doesn't exist yet. The buffer sizes are set once connected.
+    pub fn connect_unix(
+        self,
+        path: &Path,
+        buffers: BufferSizes,
+    ) -> io::Result<(UnixStream, Duration)> {
+        let (stream, connect_time) =
+            self.connect_with(path.display(), || UnixStream::connect(path))?;
+        buffers.apply(SockRef::from(&stream))?;
+        Ok((stream, connect_time))
+    }
+
+    fn connect_with<S>(
+        self,
+        target: impl Display,
+        connect: impl Fn() -> io::Result<S>,
+    ) -> io::Result<(S, Duration)> {
+        let mut backoff = self.backoff;
+        let mut retry = 0;
+
+        loop {
+            let start = Instant::now();
+            match connect() {
+                Ok(stream) => return Ok((stream, start.elapsed())),
+                Err(e) if is_refused(&e) && retry < self.retries => {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    retry += 1;
+                }
+                Err(e) if is_refused(&e) => {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!(
+                            "{target} refused {} connection attempts, is the server running?",
+                            self.retries + 1
+                        ),
+                    ));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Sets `buffers` on a new socket of the kind `transport` connects with, to find the sizes the
+/// kernel grants connections.
+pub fn granted_buffers(
+    addr: SocketAddr,
+    transport: Transport,
+    buffers: BufferSizes,
+) -> io::Result<Granted> {
+    let socket = match transport {
+        Transport::Tcp => Socket::new(Domain::for_address(addr), Type::STREAM, None)?,
+        Transport::Udp => Socket::new(Domain::for_address(addr), Type::DGRAM, None)?,
+        Transport::Uds => Socket::new(Domain::UNIX, Type::STREAM, None)?,
+    };
+    buffers.apply(SockRef::from(&socket))
+}
+
+/// Returns `true` if nothing is listening at the address, e.g. because the server hasn't started
+/// yet (a Unix domain socket that doesn't exist yet is `NotFound`).
+fn is_refused(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::NotFound
+    )
+}
+
+/// The transport requests and responses are exchanged over.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Tcp,
+
+    /// Each request and response is a single datagram.
+    Udp,
+
+    /// A Unix domain socket, which leaves the TCP/IP stack out of the latency.
+    Uds,
+}
+
+/// A connection to the server, in plaintext, over TLS, over UDP, or over a
+/// Unix domain socket. Requests must be flushed once serialized, since TLS and
+/// UDP buffer them.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<ClientConnection, TcpStream>>),
+    Udp(Datagrams),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Connects to the server at `addr`, or at `uds_path` over a Unix domain socket.
+    ///
+    /// # Arguments
+    ///
+    /// * `transport` - The transport to connect over.
+    /// * `timeout` - How long to wait for a response or to send a request. Over UDP, a response
+    ///   that takes longer is counted as dropped.
+    /// * `proxy_protocol` - Send a PROXY protocol header of this version (TCP only).
+    /// * `tls` - Connect over TLS with this configuration (TCP only).
+    /// * `retry` - How refused connections are retried (TCP only).
+    /// * `nagle` - Leave Nagle's algorithm enabled instead of setting `TCP_NODELAY` (TCP only).
+    /// * `buffers` - The send and receive buffer sizes to request.
+    ///
+    /// It returns the stream and how long connecting took, excluding refused attempts.
+    #[allow(clippy::too_many_arguments)]
+    pub fn connect(
+        addr: SocketAddr,
+        uds_path: &Path,
+        transport: Transport,
+        timeout: Option<Duration>,
+        proxy_protocol: Option<proxy::Version>,
+        tls: Option<&Arc<ClientConfig>>,
+        retry: Retry,
+        nagle: bool,
+        buffers: BufferSizes,
+    ) -> io::Result<(Self, Duration)> {
+        if transport == Transport::Udp {
+            let start = Instant::now();
+            let stream = Self::udp(addr, timeout.unwrap_or(DEFAULT_UDP_TIMEOUT), buffers)?;
+            return Ok((stream, start.elapsed()));
+        }
+        if transport == Transport::Uds {
+            let (stream, connect_time) = retry.connect_unix(uds_path, buffers)?;
+            stream.set_read_timeout(timeout)?;
+            stream.set_write_timeout(timeout)?;
+            return Ok((Stream::Unix(stream), connect_time));
+        }
+
+        let (mut stream, connect_time) = retry.connect(addr, buffers)?;
+        stream.set_nodelay(!nagle)?;
+        stream.set_read_timeout(timeout)?;
+        stream.set_write_timeout(timeout)?;
+        if let Some(version) = proxy_protocol {
+            let (src, dst) = (stream.local_addr()?, stream.peer_addr()?);
+            proxy::write_header(&mut stream, version, src, dst)?;
+        }
+
+        Ok((Self::new(stream, tls)?, connect_time))
+    }
+
+    /// Wraps `stream` in TLS if a configuration is given. The handshake happens
+    /// on the first request.
+    pub fn new(stream: TcpStream, tls: Option<&Arc<ClientConfig>>) -> io::Result<Self> {
+        let Some(config) = tls else {
+            return Ok(Stream::Plain(stream));
+        };
+
+        let name = ServerName::from(stream.peer_addr()?.ip());
+        let conn = ClientConnection::new(config.clone(), name).map_err(io::Error::other)?;
+        Ok(Stream::Tls(Box::new(TlsStream::new(conn, stream))))
+    }
+
+    /// Completes the TLS handshake now instead of on the first request. It
+    /// returns which kind of handshake it was, or `None` if the stream isn't
+    /// TLS.
+    pub fn handshake(&mut self) -> io::Result<Option<HandshakeKind>> {
+        match self {
+            Stream::Tls(stream) => stream.handshake(),
+            _ => Ok(None),
+        }
+    }
+
+    /// Creates a UDP socket that exchanges datagrams with the server at `addr`,
+    /// giving up on a response after `timeout`.
+    pub fn udp(addr: SocketAddr, timeout: Duration, buffers: BufferSizes) -> io::Result<Self> {
+        let local = if addr.is_ipv6() {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        let socket = UdpSocket::bind(local)?;
+        buffers.apply(SockRef::from(&socket))?;
+        socket.connect(addr)?;
+        socket.set_read_timeout(Some(timeout))?;
+        Ok(Stream::Udp(Datagrams::new(socket)))
+    }
+
+    /// Clones the stream so requests can be sent and responses received from
+    /// different threads. TLS streams can't be cloned.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Plain(stream) => Ok(Stream::Plain(stream.try_clone()?)),
+            Stream::Tls(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "TLS streams can't be cloned",
+            )),
+            Stream::Udp(datagrams) => {
+                Ok(Stream::Udp(Datagrams::new(datagrams.socket.try_clone()?)))
+            }
+            Stream::Unix(stream) => Ok(Stream::Unix(stream.try_clone()?)),
+        }
+    }
+
+    /// Whether responses can be lost in transit.
+    pub fn is_udp(&self) -> bool {
+        matches!(self, Stream::Udp(_))
+    }
+
+    /// Gets the underlying TCP stream.
+    ///
+    /// # Panics
+    ///
+    /// If the stream is a UDP or Unix domain socket.
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(stream) => stream.get_ref(),
+            Stream::Udp(_) => panic!("UDP sockets have no TCP stream"),
+            Stream::Unix(_) => panic!("Unix domain sockets have no TCP stream"),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+            Stream::Udp(datagrams) => datagrams.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+            Stream::Udp(datagrams) => datagrams.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+            Stream::Udp(datagrams) => datagrams.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A connected UDP socket that sends everything written since the last flush as
+/// one datagram, and reads one datagram at a time.
+pub struct Datagrams {
+    socket: UdpSocket,
+
+    /// The datagram being written.
+    out: Vec<u8>,
+
+    /// The last datagram received.
+    recv_buf: Vec<u8>,
+
+    /// The current index into the received datagram.
+    pos: usize,
+
+    /// The length of the received datagram.
+    len: usize,
+}
+
+impl Datagrams {
+    fn new(socket: UdpSocket) -> Self {
+        Self {
+            socket,
+            out: Vec::new(),
+            recv_buf: vec![0u8; MAX_DATAGRAM_SIZE],
+            pos: 0,
+            len: 0,
+        }
+    }
+}
+
+impl Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.len {
+            self.len = self.socket.recv(&mut self.recv_buf)?;
+            self.pos = 0;
+        }
+
+        let n = buf.len().min(self.len - self.pos);
+        buf[..n].copy_from_slice(&self.recv_buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.out.is_empty() {
+            self.socket.send(&self.out)?;
+            self.out.clear();
+        }
+        Ok(())
+    }
+}
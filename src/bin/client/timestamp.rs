@@ -0,0 +1,186 @@
+use std::{
+    io::{self, IoSliceMut, Read},
+    net::TcpStream,
+    os::fd::AsRawFd,
+};
+
+use nix::{
+    cmsg_space,
+    errno::Errno,
+    libc,
+    sys::{
+        socket::{
+            ControlMessageOwned, MsgFlags, TimestampingFlag, Timestamps, recvmsg, setsockopt,
+            sockopt,
+        },
+        time::TimeSpec,
+    },
+    time::{ClockId, clock_gettime},
+};
+use rust_server_benchmarks::get_time;
+
+/// Enables kernel software timestamps (`SO_TIMESTAMPING`) for packets sent and
+/// received on `stream`.
+pub fn enable(stream: &TcpStream) -> io::Result<()> {
+    let flags = TimestampingFlag::SOF_TIMESTAMPING_SOFTWARE
+        | TimestampingFlag::SOF_TIMESTAMPING_RX_SOFTWARE
+        | TimestampingFlag::SOF_TIMESTAMPING_TX_SOFTWARE
+        | TimestampingFlag::SOF_TIMESTAMPING_OPT_TSONLY;
+
+    setsockopt(stream, sockopt::Timestamping, &flags)?;
+    Ok(())
+}
+
+/// Gets the time (in nanoseconds) the kernel sent the last request, draining
+/// the socket's error queue. It returns `None` if no timestamp is queued.
+pub fn sent_time(stream: &TcpStream) -> io::Result<Option<u64>> {
+    let mut sent_time = None;
+    // The extended error is followed by the address of the offending node
+    let mut cmsg = cmsg_space!(Timestamps, libc::sock_extended_err, libc::sockaddr_in6);
+
+    loop {
+        let mut iov = [IoSliceMut::new(&mut [])];
+        let msg = match recvmsg::<()>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg),
+            MsgFlags::MSG_ERRQUEUE | MsgFlags::MSG_DONTWAIT,
+        ) {
+            Ok(msg) => msg,
+            Err(Errno::EAGAIN) => return Ok(sent_time),
+            Err(e) => return Err(e.into()),
+        };
+
+        // A request split across segments is timestamped once per segment, so
+        // keep the first
+        if sent_time.is_none() {
+            sent_time = software_time(msg.cmsgs()?);
+        }
+    }
+}
+
+/// Reads from a stream, keeping the kernel's receive time of the first
+/// segment read.
+pub struct TimestampReader<'a> {
+    stream: &'a TcpStream,
+
+    /// The time (in nanoseconds) the first segment was received by the kernel.
+    recv_time: Option<u64>,
+}
+
+impl<'a> TimestampReader<'a> {
+    pub fn new(stream: &'a TcpStream) -> Self {
+        Self {
+            stream,
+            recv_time: None,
+        }
+    }
+
+    /// Gets the time (in nanoseconds) the first segment was received by the kernel.
+    pub fn recv_time(&self) -> Option<u64> {
+        self.recv_time
+    }
+}
+
+impl Read for TimestampReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut cmsg = cmsg_space!(Timestamps);
+        let mut iov = [IoSliceMut::new(buf)];
+
+        let msg = recvmsg::<()>(
+            self.stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg),
+            MsgFlags::empty(),
+        )?;
+
+        if self.recv_time.is_none() {
+            self.recv_time = software_time(msg.cmsgs()?);
+        }
+
+        Ok(msg.bytes)
+    }
+}
+
+/// Finds the software timestamp in a message's control messages.
+fn software_time(mut cmsgs: impl Iterator<Item = ControlMessageOwned>) -> Option<u64> {
+    cmsgs.find_map(|cmsg| match cmsg {
+        ControlMessageOwned::ScmTimestampsns(Timestamps { system, .. })
+            if system != TimeSpec::new(0, 0) =>
+        {
+            Some(to_monotonic(nanos(system)))
+        }
+        _ => None,
+    })
+}
+
+/// Moves a kernel timestamp, which is on the realtime clock, onto the
+/// monotonic clock `get_time` reads, so the two can be mixed when one of them
+/// is missing.
+fn to_monotonic(realtime: u64) -> u64 {
+    let now = nanos(clock_gettime(ClockId::CLOCK_REALTIME).unwrap());
+    realtime.saturating_sub(now.saturating_sub(get_time()))
+}
+
+fn nanos(time: TimeSpec) -> u64 {
+    time.tv_sec() as u64 * 1_000_000_000 + time.tv_nsec() as u64
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::{
+        io::Write,
+        net::{Ipv4Addr, TcpListener},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    /// How far a kernel timestamp may be from the userspace clock reading before it.
+    const SLACK_NS: u64 = 10_000_000;
+
+    #[test]
+    fn kernel_timestamps_match_get_time() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        enable(&client).unwrap();
+
+        // The send timestamp is queued once the segment leaves, shortly after the write
+        let before = get_time();
+        client.write_all(b"ping").unwrap();
+        let mut sent = None;
+        for _ in 0..100 {
+            sent = sent_time(&client).unwrap();
+            if sent.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        let sent = sent.expect("no send timestamp was queued");
+        assert!(sent.abs_diff(before) < SLACK_NS, "{sent} vs {before}");
+
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+
+        // The receive timestamp is taken when the segment arrives, before it's read. The kernel
+        // may switch receive timestamps on a little after they're enabled, so the first segments
+        // can go without.
+        let mut recv = None;
+        let mut before = 0;
+        for _ in 0..100 {
+            before = get_time();
+            server.write_all(b"pong").unwrap();
+            let mut reader = TimestampReader::new(&client);
+            reader.read_exact(&mut buf).unwrap();
+            recv = reader.recv_time();
+            if recv.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        let recv = recv.expect("no receive timestamp was captured");
+        assert!(recv.abs_diff(before) < SLACK_NS, "{recv} vs {before}");
+    }
+}
@@ -0,0 +1,148 @@
+//! Confines the server process to a cgroup v2 with a CPU quota (Linux only).
+//!
+//! The cgroup is created next to the one the process is in, so it works
+//! inside a delegated subtree as well as with write access to the whole
+//! hierarchy (e.g. as root). The `cpu` controller must already be enabled in
+//! the parent's `cgroup.subtree_control`; it's never enabled here, since that
+//! would change the configuration of the host's other cgroups.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Root of the cgroup v2 hierarchy.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// The CPU bandwidth period (in microseconds) used for the quota.
+const PERIOD_MICROS: u64 = 100_000;
+
+/// A cgroup the server process was moved into. The process stays in it until
+/// [`Cgroup::remove`] is called.
+pub struct Cgroup {
+    path: PathBuf,
+    /// The cgroup the process was in before, which it's moved back to.
+    parent: PathBuf,
+}
+
+/// Creates a cgroup limited to `cores` CPUs and moves the current process,
+/// with all its threads, into it.
+pub fn limit_cpu(cores: f64) -> io::Result<Cgroup> {
+    if cores <= 0.0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cpu quota must be positive",
+        ));
+    }
+
+    let root = Path::new(CGROUP_ROOT);
+    let parent = current(root)?;
+
+    // A cgroup with processes can't have children with controllers (except the
+    // root), so the new cgroup is a sibling of the current one
+    let base = match parent.parent() {
+        Some(base) if parent != root => base,
+        _ => root,
+    };
+    let enabled = fs::read_to_string(base.join("cgroup.subtree_control"))?;
+    if !enabled
+        .split_whitespace()
+        .any(|controller| controller == "cpu")
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "the cpu controller isn't enabled in {}/cgroup.subtree_control",
+                base.display()
+            ),
+        ));
+    }
+
+    let path = base.join(format!("rust-server-benchmarks-{}", std::process::id()));
+    fs::create_dir(&path)?;
+    let cgroup = Cgroup { path, parent };
+
+    let quota = (cores * PERIOD_MICROS as f64) as u64;
+    let moved = fs::write(
+        cgroup.path.join("cpu.max"),
+        format!("{quota} {PERIOD_MICROS}"),
+    )
+    .and_then(|_| {
+        fs::write(
+            cgroup.path.join("cgroup.procs"),
+            std::process::id().to_string(),
+        )
+    });
+    if let Err(e) = moved {
+        let _ = fs::remove_dir(&cgroup.path);
+        return Err(e);
+    }
+
+    Ok(cgroup)
+}
+
+/// The cgroup v2 the current process is in, as listed in `/proc/self/cgroup`.
+fn current(root: &Path) -> io::Result<PathBuf> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup")?;
+    cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| root.join(path.trim_start_matches('/')))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not in a cgroup v2"))
+}
+
+impl Cgroup {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Moves the process back to the cgroup it came from and removes this
+    /// one. A cgroup can only be removed once it has no processes left.
+    pub fn remove(self) -> io::Result<()> {
+        fs::write(
+            self.parent.join("cgroup.procs"),
+            std::process::id().to_string(),
+        )?;
+        fs::remove_dir(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Removes the cgroup when a test ends, even if it fails.
+    struct Guard(Option<Cgroup>);
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            if let Some(cgroup) = self.0.take() {
+                let _ = cgroup.remove();
+            }
+        }
+    }
+
+    /// This moves the whole test process, and the tests running in parallel with it, into the
+    /// limited cgroup, so it only runs when asked for (`cargo test -- --ignored`), with write
+    /// access to the cgroup hierarchy.
+    #[test]
+    #[ignore]
+    fn limits_and_removes() {
+        let mut cgroup = Guard(Some(limit_cpu(0.5).unwrap()));
+        let path = cgroup.0.as_ref().unwrap().path().to_path_buf();
+        assert_eq!(
+            fs::read_to_string(path.join("cpu.max")).unwrap().trim(),
+            "50000 100000"
+        );
+        assert_eq!(current(Path::new(CGROUP_ROOT)).unwrap(), path);
+
+        cgroup.0.take().unwrap().remove().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn rejects_non_positive_quota() {
+        let e = limit_cpu(0.0).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+}
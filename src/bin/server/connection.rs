@@ -0,0 +1,180 @@
+//! The buffer management of a non-blocking connection, shared by the event loop servers (epoll
+//! and mio): a request is read in two steps, its length prefix and then its body, and the
+//! response is written from the same buffer.
+
+use std::{
+    io::{self, Cursor, Read, Write},
+    time::{Duration, Instant},
+};
+
+use rust_server_benchmarks::{
+    framing::{self, HEADER_SIZE},
+    protocol::{Deserialize, MAX_REQUEST_BODY, Request, Response, Serialize},
+};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Action {
+    Read,
+    Write,
+}
+
+pub struct Connection<S> {
+    /// The connection stream.
+    pub stream: Option<S>,
+
+    /// A reusable buffer for reading from and writing to the client.
+    pub buf: Cursor<Vec<u8>>,
+
+    /// The current index into the buffer for reading or writing.
+    pub idx: usize,
+
+    /// The action being performed on the connection.
+    pub action: Action,
+
+    /// The readiness the connection is registered for (unused when edge-triggered).
+    pub interest: Action,
+
+    /// The maximum number of bytes requested from a single `read`.
+    pub read_chunk_size: usize,
+
+    /// The last time data was read from or written to the connection.
+    pub last_active: Instant,
+
+    /// When the first byte of the request currently being read arrived.
+    pub request_start: Option<Instant>,
+}
+
+impl<S: Read + Write> Connection<S> {
+    pub fn new(stream: Option<S>, read_chunk_size: Option<usize>) -> Self {
+        Self {
+            stream,
+            buf: Cursor::new(vec![0u8; HEADER_SIZE]),
+            idx: 0,
+            action: Action::Read,
+            interest: Action::Read,
+            read_chunk_size: read_chunk_size.unwrap_or(usize::MAX).max(1),
+            last_active: Instant::now(),
+            request_start: None,
+        }
+    }
+
+    pub fn init(&mut self, stream: S) {
+        self.stream = Some(stream);
+        self.last_active = Instant::now();
+    }
+
+    pub fn reset(&mut self, state: Action) {
+        match state {
+            Action::Read => {
+                // Requests are read in two steps, the length prefix and then the body
+                self.buf.get_mut().resize(HEADER_SIZE, 0);
+            }
+            Action::Write => {
+                // The response is variable-length, so serializing it sizes the buffer
+                self.buf.get_mut().clear();
+            }
+        }
+        self.request_start = None;
+        self.buf.set_position(0);
+        self.idx = 0;
+        self.action = state;
+    }
+
+    pub fn copy_until_blocked(&mut self) -> io::Result<()> {
+        let stream = self.stream.as_mut().unwrap();
+
+        loop {
+            let size = self.buf.get_ref().len();
+            let result = match self.action {
+                Action::Read => {
+                    let end = size.min(self.idx.saturating_add(self.read_chunk_size));
+                    stream.read(&mut self.buf.get_mut()[self.idx..end])
+                }
+                _ => stream.write(&self.buf.get_ref()[self.idx..]),
+            };
+
+            match result {
+                Ok(0) => match self.action {
+                    Action::Write => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::WriteZero,
+                            "unexpectedly wrote zero bytes",
+                        ));
+                    }
+                    _ => {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "end of file"));
+                    }
+                },
+                Ok(n) => {
+                    if let (Action::Read, None) = (&self.action, self.request_start) {
+                        self.request_start = Some(Instant::now());
+                    }
+
+                    self.idx += n;
+                    self.last_active = Instant::now();
+
+                    if self.idx < size {
+                        continue;
+                    }
+
+                    // Size the buffer for the body once the length prefix is in
+                    if let (Action::Read, HEADER_SIZE) = (&self.action, size) {
+                        let header = self.buf.get_ref()[..HEADER_SIZE].try_into().unwrap();
+                        let len = framing::decode_header(header);
+                        if len == 0 || len > MAX_REQUEST_BODY {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("invalid request length {len}"),
+                            ));
+                        }
+                        self.buf.get_mut().resize(HEADER_SIZE + len, 0);
+                        continue;
+                    }
+
+                    break;
+                }
+                Err(e) => match e.kind() {
+                    io::ErrorKind::Interrupted => continue,
+                    _ => {
+                        return Err(e);
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn deserialize_request(&mut self) -> io::Result<Request> {
+        Request::deserialize(&mut self.buf)
+    }
+
+    pub fn serialize_response(&mut self, response: Response) -> io::Result<()> {
+        response.serialize(&mut self.buf)
+    }
+
+    /// Returns `true` if the connection is open and waiting for its next request, so it can be
+    /// closed when shutting down.
+    pub fn is_between_requests(&self) -> bool {
+        self.stream.is_some() && matches!(self.action, Action::Read) && self.idx == 0
+    }
+
+    /// Returns `true` if the connection is open and has been idle for at least `idle_timeout` or
+    /// has been receiving its current request for at least `request_deadline`.
+    pub fn is_expired(
+        &self,
+        idle_timeout: Option<Duration>,
+        request_deadline: Option<Duration>,
+    ) -> bool {
+        if self.stream.is_none() {
+            return false;
+        }
+
+        let idle = idle_timeout.is_some_and(|timeout| self.last_active.elapsed() >= timeout);
+        let slow = match (self.request_start, request_deadline) {
+            (Some(start), Some(deadline)) => start.elapsed() >= deadline,
+            _ => false,
+        };
+        idle || slow
+    }
+}
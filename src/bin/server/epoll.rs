@@ -1,136 +1,212 @@
 use std::{
-    io::{self, Cursor, Read, Write},
-    net::{SocketAddrV4, TcpListener, TcpStream},
+    io,
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
 use nix::sys::*;
 
-use crossbeam_channel::{Receiver, unbounded};
-use rust_server_benchmarks::protocol::{
-    Deserialize, REQUEST_SIZE, RESPONSE_SIZE, Request, Response, Serialize,
+use crate::{
+    connection::{Action, Connection},
+    handler::Handler,
+    metrics::Metrics,
 };
+use clap::ValueEnum;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, unbounded};
+use log::{error, info, warn};
+use rust_server_benchmarks::{buffers::BufferSizes, framing, proxy};
+use socket2::{Domain, SockRef, Socket, Type};
 
-pub fn run(addr: SocketAddrV4, n_threads: usize, capacity: usize, max_events: usize) {
-    let listener = TcpListener::bind(addr).unwrap();
-    let (tx, rx) = unbounded::<TcpStream>();
-    println!("Server listening at {}", addr);
+#[derive(Clone, Copy)]
+pub struct Config {
+    /// The address to bind to.
+    pub addr: SocketAddr,
 
-    // Start each epoll thread
-    for _ in 0..n_threads {
-        let rx = rx.clone();
-        std::thread::spawn(move || {
-            EpollThread::new(capacity, max_events, rx).run();
-        });
-    }
+    /// The send and receive buffer sizes of accepted connections, which they inherit from the
+    /// listener.
+    pub buffers: BufferSizes,
 
-    // Accept connections
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        stream.set_nonblocking(true).unwrap();
-        stream.set_nodelay(true).unwrap();
-        tx.send(stream).unwrap();
-    }
-}
+    /// The number of epoll threads.
+    pub n_threads: usize,
 
-enum Action {
-    Read,
-    Write,
-}
+    /// The maximum number of concurrent connections per thread.
+    pub capacity: usize,
 
-struct Connection {
-    /// The connection stream.
-    stream: Option<TcpStream>,
+    /// The maximum number of events each thread waits for per cycle.
+    pub max_events: usize,
 
-    /// A reusable buffer for reading from and writing to the client.
-    buf: Cursor<Vec<u8>>,
+    /// The maximum number of bytes requested from a single `read`.
+    pub read_chunk_size: Option<usize>,
 
-    /// The current index into the buffer for reading or writing.
-    idx: usize,
+    /// How long a thread waits for events before doing periodic maintenance (checking for
+    /// shutdown and closing idle connections).
+    pub wait_timeout: Duration,
 
-    /// The action being performed on the connection.
-    action: Action,
-}
+    /// Connections without any activity for this long are closed during maintenance.
+    pub idle_timeout: Option<Duration>,
 
-impl Connection {
-    fn new(stream: Option<TcpStream>) -> Self {
-        Self {
-            stream,
-            buf: Cursor::new(vec![0u8; REQUEST_SIZE]),
-            idx: 0,
-            action: Action::Read,
-        }
-    }
+    /// Connections that started sending a request this long ago without finishing it are closed
+    /// during maintenance.
+    pub request_deadline: Option<Duration>,
 
-    fn init(&mut self, stream: TcpStream) {
-        self.stream = Some(stream);
-    }
+    /// Strip a PROXY protocol header from the start of each connection. Headers are read by the
+    /// accepting thread before the connection is handed to an epoll thread.
+    pub accept_proxy_protocol: bool,
 
-    fn reset(&mut self, state: Action) {
-        match state {
-            Action::Read => {
-                self.buf.get_mut().resize(REQUEST_SIZE, 0);
-            }
-            Action::Write => {
-                self.buf.get_mut().resize(RESPONSE_SIZE, 0);
-            }
-        }
-        self.stream = None; // drop the connection
-        self.buf.set_position(0);
-        self.idx = 0;
-        self.action = state;
-    }
+    /// Leave Nagle's algorithm enabled on accepted connections instead of setting `TCP_NODELAY`.
+    pub nagle: bool,
+
+    /// Pin epoll thread `i` to core `i % num_cores`.
+    pub pin_threads: bool,
 
-    fn copy_until_blocked(&mut self) -> io::Result<()> {
-        let stream = self.stream.as_mut().unwrap();
+    /// Register connections for both reads and writes with `EPOLLET` once, instead of
+    /// level-triggered for whichever of the two they are waiting on.
+    pub edge_triggered: bool,
 
-        let size = match self.action {
-            Action::Read => REQUEST_SIZE,
-            _ => RESPONSE_SIZE,
+    /// How accepted connections are handed to the epoll threads.
+    pub sharding: AcceptSharding,
+}
+
+/// How accepted connections are handed to the epoll threads.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AcceptSharding {
+    /// One acceptor sends every connection over a single channel that all
+    /// epoll threads receive from.
+    Shared,
+
+    /// One acceptor deals connections out to the epoll threads in turn, over
+    /// a channel per thread.
+    RoundRobin,
+
+    /// Every epoll thread has its own acceptor and `SO_REUSEPORT` listener,
+    /// and the kernel balances connections across them.
+    ReusePort,
+}
+
+impl Config {
+    /// Runs the epoll server. Once `shutdown` is set, the next accepted connection stops the
+    /// server from accepting, and epoll threads close their connections as they finish their
+    /// current request, exiting once they have none left.
+    pub fn run(self, shutdown: Arc<AtomicBool>, metrics: Arc<Metrics>, handler: Arc<dyn Handler>) {
+        let (n_listeners, n_channels) = match self.sharding {
+            AcceptSharding::Shared => (1, 1),
+            AcceptSharding::RoundRobin => (1, self.n_threads),
+            AcceptSharding::ReusePort => (self.n_threads, self.n_threads),
+        };
+        let listeners = (0..n_listeners)
+            .map(|_| self.bind())
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        let granted = listeners
+            .iter()
+            .map(|listener| self.buffers.apply(SockRef::from(listener)))
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        let (txs, rxs): (Vec<_>, Vec<_>) =
+            (0..n_channels).map(|_| unbounded::<TcpStream>()).unzip();
+        info!("Server listening at {}", self.addr);
+        info!("Socket buffers: {}", granted[0]);
+
+        let cores = if self.pin_threads {
+            core_affinity::get_core_ids().unwrap_or_default()
+        } else {
+            Vec::new()
         };
+        if self.pin_threads && cores.is_empty() {
+            warn!("failed to get the core ids, epoll threads won't be pinned");
+        }
 
-        loop {
-            let result = match self.action {
-                Action::Read => stream.read(&mut self.buf.get_mut()[self.idx..]),
-                _ => stream.write(&mut self.buf.get_mut()[self.idx..]),
-            };
-
-            match result {
-                Ok(0) => match self.action {
-                    Action::Write => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::WriteZero,
-                            "unexpectedly wrote zero bytes",
-                        ));
-                    }
-                    _ => {
-                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "end of file"));
+        // Start each epoll thread
+        let handles = (0..self.n_threads)
+            .map(|i| {
+                let rx = rxs[i % n_channels].clone();
+                let shutdown = shutdown.clone();
+                let metrics = metrics.clone();
+                let handler = handler.clone();
+                let core = (!cores.is_empty()).then(|| cores[i % cores.len()]);
+                std::thread::spawn(move || {
+                    if let Some(core) = core
+                        && !core_affinity::set_for_current(core)
+                    {
+                        warn!("failed to pin epoll thread {i} to core {}", core.id);
                     }
-                },
-                Ok(n) => {
-                    self.idx += n;
+                    EpollThread::new(&self, rx, shutdown, metrics, handler).run();
+                })
+            })
+            .collect::<Vec<_>>();
 
-                    if self.idx == size {
-                        break;
-                    }
-                }
-                Err(e) => match e.kind() {
-                    io::ErrorKind::Interrupted => continue,
-                    _ => {
-                        return Err(e);
-                    }
-                },
+        // Accept connections, each listener handing them to its own epoll thread when sharding
+        // with SO_REUSEPORT
+        std::thread::scope(|s| {
+            for (i, listener) in listeners.iter().enumerate() {
+                let txs = match self.sharding {
+                    AcceptSharding::ReusePort => &txs[i..=i],
+                    AcceptSharding::Shared | AcceptSharding::RoundRobin => &txs[..],
+                };
+                let (listeners, shutdown) = (&listeners, &shutdown);
+                s.spawn(move || self._accept(listener, listeners, shutdown, txs));
             }
-        }
+        });
 
-        Ok(())
+        for handle in handles {
+            handle.join().unwrap();
+        }
     }
 
-    fn deserialize_request(&mut self) -> io::Result<Request> {
-        Request::deserialize(&mut self.buf)
+    /// Binds a listener to the server's address, sharing it with the other epoll threads'
+    /// listeners when sharding with `SO_REUSEPORT`.
+    fn bind(&self) -> io::Result<TcpListener> {
+        if self.sharding != AcceptSharding::ReusePort {
+            return TcpListener::bind(self.addr);
+        }
+
+        let socket = Socket::new(Domain::for_address(self.addr), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        socket.set_reuse_port(true)?;
+        socket.bind(&self.addr.into())?;
+        socket.listen(1024)?;
+        Ok(socket.into())
     }
 
-    fn serialize_response(&mut self, response: Response) -> io::Result<()> {
-        response.serialize(&mut self.buf)
+    /// Accepts connections from `listener` until the server shuts down, handing them out to the
+    /// epoll threads behind `txs` in turn.
+    fn _accept(
+        &self,
+        listener: &TcpListener,
+        listeners: &[TcpListener],
+        shutdown: &AtomicBool,
+        txs: &[Sender<TcpStream>],
+    ) {
+        for (i, stream) in listener.incoming().enumerate() {
+            if shutdown.load(Ordering::SeqCst) {
+                // Wake up the other acceptors, whose accepts fail once their listener is shut
+                // down
+                for listener in listeners {
+                    let _ = SockRef::from(listener).shutdown(Shutdown::Read);
+                }
+                break;
+            }
+
+            let mut stream = stream.unwrap();
+            if self.accept_proxy_protocol {
+                stream
+                    .set_read_timeout(Some(proxy::HEADER_TIMEOUT))
+                    .unwrap();
+                if let Err(e) = proxy::read_header(&mut stream) {
+                    warn!("{e}");
+                    continue;
+                }
+            }
+
+            stream.set_nonblocking(true).unwrap();
+            stream.set_nodelay(!self.nagle).unwrap();
+            txs[i % txs.len()].send(stream).unwrap();
+        }
     }
 }
 
@@ -142,18 +218,29 @@ struct Epoll {
     capacity: usize,
 
     /// The connections.
-    conns: Vec<Connection>,
+    conns: Vec<Connection<TcpStream>>,
 
     /// Buffer of connections that are available to use.
     free_conns: Vec<usize>,
+
+    /// Whether connections are registered edge-triggered.
+    edge_triggered: bool,
+
+    /// The server's load counters.
+    metrics: Arc<Metrics>,
 }
 
 impl Epoll {
     /// Creates a new Epoll instance.
-    fn new(capacity: usize) -> Self {
+    fn new(
+        capacity: usize,
+        read_chunk_size: Option<usize>,
+        edge_triggered: bool,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         let epoll_fd = epoll::Epoll::new(epoll::EpollCreateFlags::empty()).unwrap();
         let conns = (0..capacity)
-            .map(|_| Connection::new(None))
+            .map(|_| Connection::new(None, read_chunk_size))
             .collect::<Vec<_>>();
         let free_conns = (0..capacity).collect::<Vec<_>>();
 
@@ -162,6 +249,8 @@ impl Epoll {
             capacity,
             conns,
             free_conns,
+            edge_triggered,
+            metrics,
         }
     }
 
@@ -172,12 +261,19 @@ impl Epoll {
             .pop()
             .expect("cannot add a connection while connection pool is full.");
 
-        // Add an entry to the epoll fd's interest list.
-        let event = epoll::EpollEvent::new(epoll::EpollFlags::EPOLLIN, id as u64);
+        // Add an entry to the epoll fd's interest list. Edge-triggered connections are interested
+        // in both directions for good, since an edge is only reported once.
+        let flags = if self.edge_triggered {
+            epoll::EpollFlags::EPOLLIN | epoll::EpollFlags::EPOLLOUT | epoll::EpollFlags::EPOLLET
+        } else {
+            epoll::EpollFlags::EPOLLIN
+        };
+        let event = epoll::EpollEvent::new(flags, id as u64);
         self.epoll_fd.add(&stream, event)?;
 
         let conn = &mut self.conns[id];
         conn.init(stream);
+        self.metrics.connection_opened();
 
         Ok(())
     }
@@ -189,41 +285,88 @@ impl Epoll {
 
         self.epoll_fd.delete(stream)?;
 
+        conn.stream = None; // drop the connection
         conn.reset(Action::Read);
+        conn.interest = Action::Read;
         self.free_conns.push(id);
+        self.metrics.connection_closed();
 
         Ok(())
     }
 
-    fn modify(&mut self, id: usize, state: Action) -> io::Result<()> {
+    /// Registers a connection for the readiness `action` needs, unless it already is (or is
+    /// edge-triggered, and so registered for both).
+    fn watch(&mut self, id: usize, action: Action) -> io::Result<()> {
         let conn = &mut self.conns[id];
+        if self.edge_triggered || conn.interest == action {
+            return Ok(());
+        }
+
         let stream = conn.stream.as_ref().expect("connection not in use.");
 
-        let event_flags = match state {
+        let event_flags = match action {
             Action::Read => epoll::EpollFlags::EPOLLIN,
             _ => epoll::EpollFlags::EPOLLOUT,
         };
 
         let mut event = epoll::EpollEvent::new(event_flags, id as u64);
         self.epoll_fd.modify(stream, &mut event)?;
+        conn.interest = action;
+
+        Ok(())
+    }
+
+    fn wait(
+        &mut self,
+        events: &mut [epoll::EpollEvent],
+        timeout: epoll::EpollTimeout,
+    ) -> io::Result<usize> {
+        // Signals (e.g. SIGINT for shutting down) interrupt the wait
+        match self.epoll_fd.wait(events, timeout) {
+            Ok(event_count) => Ok(event_count),
+            Err(nix::errno::Errno::EINTR) => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
 
-        conn.reset(state);
+    /// Deletes every connection that is waiting for its next request, for shutting down.
+    fn delete_between_requests(&mut self) -> io::Result<()> {
+        for id in 0..self.capacity {
+            if self.conns[id].is_between_requests() {
+                self.delete(id)?;
+            }
+        }
 
         Ok(())
     }
 
-    fn wait(&mut self, events: &mut [epoll::EpollEvent]) -> io::Result<usize> {
-        let event_count = self.epoll_fd.wait(events, epoll::EpollTimeout::NONE)?;
-        Ok(event_count)
+    /// Deletes every connection that has been idle for at least `idle_timeout` or has been
+    /// receiving its current request for at least `request_deadline`.
+    fn delete_expired(
+        &mut self,
+        idle_timeout: Option<Duration>,
+        request_deadline: Option<Duration>,
+    ) -> io::Result<()> {
+        for id in 0..self.capacity {
+            if self.conns[id].is_expired(idle_timeout, request_deadline) {
+                self.delete(id)?;
+            }
+        }
+
+        Ok(())
     }
 
-    /// Gets an immutable reference to a connection.
-    fn get_ref(&self, id: usize) -> &Connection {
-        &self.conns[id]
+    /// Returns `true` if `event` reports a connection readable while it waits for a request,
+    /// i.e. one with a request queued for the server.
+    fn is_queued(&self, event: &epoll::EpollEvent) -> bool {
+        let conn = &self.conns[event.data() as usize];
+        event.events().contains(epoll::EpollFlags::EPOLLIN)
+            && conn.stream.is_some()
+            && conn.action == Action::Read
     }
 
     /// Gets a mutable reference to a connection.
-    fn get_mut(&mut self, id: usize) -> &mut Connection {
+    fn get_mut(&mut self, id: usize) -> &mut Connection<TcpStream> {
         &mut self.conns[id]
     }
 
@@ -247,6 +390,24 @@ struct EpollThread {
 
     /// The receiving side of a channel of connections.
     rx_conn: Receiver<TcpStream>,
+
+    /// How long to wait for events before doing maintenance.
+    wait_timeout: Duration,
+
+    /// Connections idle for this long are closed during maintenance.
+    idle_timeout: Option<Duration>,
+
+    /// Connections taking this long to send a request are closed during maintenance.
+    request_deadline: Option<Duration>,
+
+    /// Set when the thread should exit.
+    shutdown: Arc<AtomicBool>,
+
+    /// Whether connections are registered edge-triggered.
+    edge_triggered: bool,
+
+    /// Answers requests.
+    handler: Arc<dyn Handler>,
 }
 
 impl EpollThread {
@@ -254,30 +415,77 @@ impl EpollThread {
     ///
     /// # [Arguments]
     ///
-    /// `capacity`   - the maximum number of concurrent connections.
+    /// `cfg`      - the server configuration.
+    ///
+    /// `rx_conn`  - the receiving side of a channel of connections.
     ///
-    /// `max_events` - the maximum number of events it waits for per cycle.
+    /// `shutdown` - set when the thread should exit.
     ///
-    /// `rx_conn`    - the receiving side of a channel of connections.
-    fn new(capacity: usize, max_events: usize, rx_conn: Receiver<TcpStream>) -> Self {
+    /// `metrics`  - the server's load counters.
+    ///
+    /// `handler`  - answers requests.
+    fn new(
+        cfg: &Config,
+        rx_conn: Receiver<TcpStream>,
+        shutdown: Arc<AtomicBool>,
+        metrics: Arc<Metrics>,
+        handler: Arc<dyn Handler>,
+    ) -> Self {
         Self {
-            epoll: Epoll::new(capacity),
-            events: vec![epoll::EpollEvent::empty(); max_events],
+            epoll: Epoll::new(
+                cfg.capacity,
+                cfg.read_chunk_size,
+                cfg.edge_triggered,
+                metrics,
+            ),
+            events: vec![epoll::EpollEvent::empty(); cfg.max_events],
             rx_conn,
+            wait_timeout: cfg.wait_timeout,
+            idle_timeout: cfg.idle_timeout,
+            request_deadline: cfg.request_deadline,
+            shutdown,
+            edge_triggered: cfg.edge_triggered,
+            handler,
         }
     }
 
     fn run(mut self) {
+        let timeout = epoll::EpollTimeout::try_from(self.wait_timeout).unwrap();
+        let mut last_maintenance = Instant::now();
+        let mut shutting_down = false;
+
         loop {
+            // Periodically check for shutdown and close idle connections
+            if last_maintenance.elapsed() >= self.wait_timeout {
+                if self.shutdown.load(Ordering::SeqCst) {
+                    shutting_down = true;
+                    self.epoll.delete_between_requests().unwrap();
+                }
+
+                self.epoll
+                    .delete_expired(self.idle_timeout, self.request_deadline)
+                    .unwrap();
+
+                last_maintenance = Instant::now();
+            }
+
+            if shutting_down && self.epoll.is_empty() {
+                return;
+            }
+
             // We must have at least one connection
             if self.epoll.is_empty() {
-                let stream = self.rx_conn.recv().unwrap();
+                let stream = match self.rx_conn.recv_timeout(self.wait_timeout) {
+                    Ok(stream) => stream,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                };
                 self.epoll.add(stream).unwrap();
             }
 
             // Keep accepting connections until we've reached the capacity or there
             // are no connections ready.
-            while !self.epoll.is_full() {
+            while !shutting_down && !self.epoll.is_full() {
                 match self.rx_conn.try_recv() {
                     Ok(stream) => {
                         self.epoll.add(stream).unwrap();
@@ -286,37 +494,80 @@ impl EpollThread {
                 }
             }
 
-            let event_count = self.epoll.wait(&mut self.events).unwrap();
+            let event_count = self.epoll.wait(&mut self.events, timeout).unwrap();
+
+            // Connections that are ready to be read and waiting for a request, which make up
+            // the queue depth reported to the requests handled before them
+            let mut queue_depth = self.events[..event_count]
+                .iter()
+                .filter(|event| self.epoll.is_queued(event))
+                .count() as u32;
 
             for i in 0..event_count {
                 let event = self.events[i];
                 self.events[i] = epoll::EpollEvent::empty();
 
                 let id = event.data() as usize;
-                let conn = self.epoll.get_mut(id);
+                if self.epoll.is_queued(&event) {
+                    queue_depth -= 1;
+                }
 
-                match conn.copy_until_blocked() {
-                    Err(e) => {
-                        if e.kind() == io::ErrorKind::WouldBlock {
-                            continue;
+                // Responses are written right away, only waiting for the socket to become writable
+                // if it fills up. Edge-triggered connections are served until they would block,
+                // since several requests may have arrived behind a single edge.
+                loop {
+                    let conn = self.epoll.get_mut(id);
+
+                    match conn.copy_until_blocked() {
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            let action = conn.action;
+                            self.epoll.watch(id, action).unwrap();
+                            break;
                         }
+                        Err(e) => {
+                            if !framing::is_disconnect(&e) {
+                                error!("unexpected error: {e}");
+                            }
 
-                        if e.kind() != io::ErrorKind::UnexpectedEof {
-                            eprintln!("unexpected error: {e}");
+                            self.epoll.delete(id).unwrap();
+                            break;
                         }
-
-                        self.epoll.delete(id).unwrap();
+                        _ => match conn.action {
+                            Action::Read => {
+                                let request = match conn.deserialize_request() {
+                                    Ok(request) => request,
+                                    Err(e) => {
+                                        warn!("malformed request: {e}");
+                                        self.epoll.delete(id).unwrap();
+                                        break;
+                                    }
+                                };
+                                let response = self.handler.handle(&request, queue_depth);
+
+                                let service_ns = response.server_process_ns;
+
+                                // Resetting the buffer for writing must happen before serializing
+                                conn.reset(Action::Write);
+                                if let Err(e) = conn.serialize_response(response) {
+                                    warn!("failed to serialize response: {e}");
+                                    self.epoll.delete(id).unwrap();
+                                    break;
+                                }
+                                self.epoll.metrics.request_handled(service_ns);
+                            }
+                            Action::Write if shutting_down => {
+                                self.epoll.delete(id).unwrap();
+                                break;
+                            }
+                            Action::Write => {
+                                conn.reset(Action::Read);
+                                self.epoll.watch(id, Action::Read).unwrap();
+                                if !self.edge_triggered {
+                                    break;
+                                }
+                            }
+                        },
                     }
-                    _ => match conn.action {
-                        Action::Read => {
-                            let response = conn.deserialize_request().unwrap().do_work();
-                            conn.serialize_response(response).unwrap();
-                            self.epoll.modify(id, Action::Write).unwrap();
-                        }
-                        Action::Write => {
-                            self.epoll.modify(id, Action::Read).unwrap();
-                        }
-                    },
                 }
             }
         }
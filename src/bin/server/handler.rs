@@ -0,0 +1,134 @@
+//! How requests are answered, shared by every server so only the way they
+//! handle connections concurrently differs.
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::error;
+use rust_server_benchmarks::{
+    framing,
+    protocol::{Deserialize, Request, Response, Serialize},
+};
+
+use crate::metrics::Metrics;
+
+/// Turns a request into its response, which reports `queue_depth`: the
+/// server's queue depth when it handled the request.
+pub trait Handler: Send + Sync {
+    fn handle(&self, request: &Request, queue_depth: u32) -> Response;
+}
+
+/// Does the work each request asks for.
+pub struct DoWork;
+
+impl Handler for DoWork {
+    fn handle(&self, request: &Request, queue_depth: u32) -> Response {
+        let mut response = request.do_work();
+        response.queue_depth = queue_depth;
+        response
+    }
+}
+
+/// Makes a random fraction of requests slow by sleeping before answering them
+/// with another handler, giving the latencies a known tail (e.g. to model GC
+/// pauses). The sleep counts towards the server's processing time.
+pub struct SlowTail {
+    pub inner: Arc<dyn Handler>,
+
+    /// The fraction of requests that are slowed down.
+    pub fraction: f64,
+
+    /// How long slowed down requests sleep for.
+    pub extra: Duration,
+}
+
+impl Handler for SlowTail {
+    fn handle(&self, request: &Request, queue_depth: u32) -> Response {
+        let start = Instant::now();
+        if rand::random::<f64>() < self.fraction {
+            thread::sleep(self.extra);
+        }
+
+        let mut response = self.inner.handle(request, queue_depth);
+        response.server_process_ns = start.elapsed().as_nanos() as u64;
+        response
+    }
+}
+
+/// Turns requests away with a busy status, without doing their work, while
+/// the server's queue depth is above `threshold`, and answers the rest with
+/// another handler. Clients can back off instead of the server just getting
+/// slower.
+pub struct Backpressure {
+    pub inner: Arc<dyn Handler>,
+
+    /// The largest queue depth requests are still answered at.
+    pub threshold: u32,
+}
+
+impl Handler for Backpressure {
+    fn handle(&self, request: &Request, queue_depth: u32) -> Response {
+        if queue_depth <= self.threshold {
+            return self.inner.handle(request, queue_depth);
+        }
+
+        let mut response = request.reject();
+        response.queue_depth = queue_depth;
+        response
+    }
+}
+
+/// A blocking connection requests are read from and responses written to.
+pub trait Transport: Read + Write {
+    /// Restarts the request deadline, before a new request is read.
+    fn start_request(&mut self);
+}
+
+/// Answers requests on a blocking connection until the client disconnects or
+/// times out, or a request can't be read or a response written. Every request
+/// is handled at the server's queue depth at the time, as given by
+/// `queue_depth`.
+pub fn serve<T: Transport>(
+    transport: &mut T,
+    handler: &dyn Handler,
+    queue_depth: impl Fn() -> u32,
+    metrics: &Metrics,
+) {
+    loop {
+        // Deserialize and handle the request
+        transport.start_request();
+        let request = Request::deserialize(transport);
+
+        let response = match request {
+            Ok(request) => handler.handle(&request, queue_depth()),
+            Err(e) => {
+                if !framing::is_disconnect(&e)
+                    && !matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+                {
+                    error!("{e}");
+                }
+
+                break;
+            }
+        };
+
+        metrics.request_handled(response.server_process_ns);
+
+        // Serialize and send the response, giving up on the connection if it fails (e.g. the
+        // client reset it mid-write)
+        if let Err(e) = response
+            .serialize(&mut *transport)
+            .and_then(|_| transport.flush())
+        {
+            if !framing::is_disconnect(&e) {
+                error!("{e}");
+            }
+
+            break;
+        }
+    }
+}
@@ -1 +1,420 @@
+use std::{
+    io::{self, Cursor},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    os::fd::{AsRawFd, FromRawFd, RawFd},
+    ptr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
 
+use ::io_uring::{IoUring, opcode, squeue, types};
+use nix::libc;
+use socket2::SockRef;
+
+use crate::{handler::Handler, metrics::Metrics};
+use log::{error, info, warn};
+use rust_server_benchmarks::{
+    buffers::BufferSizes,
+    framing::{self, HEADER_SIZE},
+    protocol::{Deserialize, MAX_REQUEST_BODY, Request, Response, Serialize},
+};
+
+/// The user data of accept completions (connections use their id).
+const ACCEPT: u64 = u64::MAX;
+
+/// The user data of the completion of cancelling the accept.
+const CANCEL: u64 = u64::MAX - 1;
+
+/// How long a thread waits for completions before checking for shutdown.
+const WAIT_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy)]
+pub struct Config {
+    /// The address to bind to.
+    pub addr: SocketAddr,
+
+    /// The send and receive buffer sizes of accepted connections, which they inherit from the
+    /// listener.
+    pub buffers: BufferSizes,
+
+    /// The number of io_uring threads.
+    pub n_threads: usize,
+
+    /// The maximum number of concurrent connections per thread.
+    pub capacity: usize,
+
+    /// The number of submission queue entries of each thread's ring.
+    pub sq_depth: u32,
+
+    /// Leave Nagle's algorithm enabled on accepted connections instead of setting `TCP_NODELAY`.
+    pub nagle: bool,
+}
+
+impl Config {
+    /// Runs the io_uring server. Every thread accepts connections from the
+    /// shared listener through its own ring. Once `shutdown` is set, threads
+    /// stop accepting and close their connections as they finish their current
+    /// request, exiting once they have none left.
+    pub fn run(self, shutdown: Arc<AtomicBool>, metrics: Arc<Metrics>, handler: Arc<dyn Handler>) {
+        let listener = Arc::new(TcpListener::bind(self.addr).unwrap());
+        let granted = self.buffers.apply(SockRef::from(&*listener)).unwrap();
+        info!("Server listening at {}", self.addr);
+        info!("Socket buffers: {granted}");
+
+        let handles = (0..self.n_threads)
+            .map(|_| {
+                let listener = listener.clone();
+                let shutdown = shutdown.clone();
+                let metrics = metrics.clone();
+                let handler = handler.clone();
+                std::thread::spawn(move || {
+                    UringThread::new(&self, listener, shutdown, metrics, handler).run()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+}
+
+enum Action {
+    Read,
+    Write,
+}
+
+struct Connection {
+    /// The connection stream.
+    stream: Option<TcpStream>,
+
+    /// A reusable buffer for reading from and writing to the client. It is only
+    /// resized between operations, so it stays valid while they are in flight.
+    buf: Cursor<Vec<u8>>,
+
+    /// The current index into the buffer for reading or writing.
+    idx: usize,
+
+    /// The action being performed on the connection.
+    action: Action,
+}
+
+impl Connection {
+    fn new() -> Self {
+        Self {
+            stream: None,
+            buf: Cursor::new(vec![0u8; HEADER_SIZE]),
+            idx: 0,
+            action: Action::Read,
+        }
+    }
+
+    fn reset(&mut self, state: Action) {
+        match state {
+            Action::Read => {
+                // Requests are read in two steps, the length prefix and then the body
+                self.buf.get_mut().resize(HEADER_SIZE, 0);
+            }
+            Action::Write => {
+                // The response is variable-length, so serializing it sizes the buffer
+                self.buf.get_mut().clear();
+            }
+        }
+        self.buf.set_position(0);
+        self.idx = 0;
+        self.action = state;
+    }
+
+    /// Builds the next `Recv` or `Send` entry for the rest of the buffer.
+    fn next_entry(&mut self, id: usize) -> squeue::Entry {
+        let fd = types::Fd(self.stream.as_ref().unwrap().as_raw_fd());
+        let remaining = &mut self.buf.get_mut()[self.idx..];
+
+        let entry = match self.action {
+            Action::Read => {
+                opcode::Recv::new(fd, remaining.as_mut_ptr(), remaining.len() as u32).build()
+            }
+            Action::Write => {
+                opcode::Send::new(fd, remaining.as_ptr(), remaining.len() as u32).build()
+            }
+        };
+
+        entry.user_data(id as u64)
+    }
+
+    fn deserialize_request(&mut self) -> io::Result<Request> {
+        Request::deserialize(&mut self.buf)
+    }
+
+    fn serialize_response(&mut self, response: Response) -> io::Result<()> {
+        response.serialize(&mut self.buf)
+    }
+}
+
+struct UringThread {
+    /// The thread's ring.
+    ring: IoUring,
+
+    /// The listener connections are accepted from.
+    listener: Arc<TcpListener>,
+
+    /// The connections.
+    conns: Vec<Connection>,
+
+    /// Buffer of connections that are available to use.
+    free_conns: Vec<usize>,
+
+    /// Whether an accept is in flight.
+    accepting: bool,
+
+    /// Set when the thread should stop.
+    shutdown: Arc<AtomicBool>,
+
+    /// Whether the thread has started shutting down.
+    shutting_down: bool,
+
+    /// Whether Nagle's algorithm is left enabled on connections.
+    nagle: bool,
+
+    /// The server's load counters.
+    metrics: Arc<Metrics>,
+
+    /// Answers requests.
+    handler: Arc<dyn Handler>,
+}
+
+impl UringThread {
+    /// Creates a new `UringThread`.
+    ///
+    /// # [Arguments]
+    ///
+    /// `cfg`      - the server configuration.
+    ///
+    /// `listener` - the listener to accept connections from.
+    ///
+    /// `shutdown` - set when the thread should stop.
+    ///
+    /// `metrics`  - the server's load counters.
+    ///
+    /// `handler`  - answers requests.
+    fn new(
+        cfg: &Config,
+        listener: Arc<TcpListener>,
+        shutdown: Arc<AtomicBool>,
+        metrics: Arc<Metrics>,
+        handler: Arc<dyn Handler>,
+    ) -> Self {
+        Self {
+            ring: IoUring::new(cfg.sq_depth).unwrap(),
+            listener,
+            conns: (0..cfg.capacity).map(|_| Connection::new()).collect(),
+            free_conns: (0..cfg.capacity).collect(),
+            accepting: false,
+            shutdown,
+            shutting_down: false,
+            nagle: cfg.nagle,
+            metrics,
+            handler,
+        }
+    }
+
+    fn run(mut self) {
+        self.accept().unwrap();
+
+        loop {
+            // Wake up periodically to check for shutdown
+            let timeout = types::Timespec::from(WAIT_TIMEOUT);
+            let args = types::SubmitArgs::new().timespec(&timeout);
+            match self.ring.submitter().submit_with_args(1, &args) {
+                Ok(_) => {}
+                Err(e) if matches!(e.raw_os_error(), Some(libc::ETIME | libc::EINTR)) => {}
+                Err(e) => panic!("failed to submit: {e}"),
+            }
+
+            if !self.shutting_down && self.shutdown.load(Ordering::SeqCst) {
+                self.start_shutdown().unwrap();
+            }
+
+            let completions = self
+                .ring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect::<Vec<_>>();
+
+            for (i, &(user_data, result)) in completions.iter().enumerate() {
+                if user_data == CANCEL {
+                    continue;
+                }
+
+                if user_data == ACCEPT {
+                    self.accepting = false;
+                    if result < 0 {
+                        if result != -libc::ECANCELED {
+                            error!("accept failed: {}", io::Error::from_raw_os_error(-result));
+                        }
+                    } else if self.shutting_down {
+                        // SAFETY: the fd was just returned by accept and is owned by nobody else
+                        drop(unsafe { TcpStream::from_raw_fd(result) });
+                    } else {
+                        self.add(result).unwrap();
+                    }
+                    self.accept().unwrap();
+                    continue;
+                }
+
+                // Connections whose completions are still waiting to be handled
+                let queue_depth = (completions.len() - i - 1) as u32;
+                self.complete(user_data as usize, result, queue_depth)
+                    .unwrap();
+            }
+
+            if self.shutting_down && !self.accepting && self.free_conns.len() == self.conns.len() {
+                return;
+            }
+        }
+    }
+
+    /// Stops accepting and closes every connection that is waiting for its next
+    /// request. The rest are closed once their response is sent.
+    fn start_shutdown(&mut self) -> io::Result<()> {
+        self.shutting_down = true;
+
+        if self.accepting {
+            let entry = opcode::AsyncCancel::new(ACCEPT).build().user_data(CANCEL);
+            self.push(entry)?;
+        }
+
+        // Their pending receives complete with the end of the stream, deleting them
+        for conn in &self.conns {
+            if let (Some(stream), Action::Read, 0) = (&conn.stream, &conn.action, conn.idx) {
+                stream.shutdown(Shutdown::Read)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Submits an accept if there is room for another connection.
+    fn accept(&mut self) -> io::Result<()> {
+        if self.shutting_down || self.accepting || self.free_conns.is_empty() {
+            return Ok(());
+        }
+
+        let fd = types::Fd(self.listener.as_raw_fd());
+        let entry = opcode::Accept::new(fd, ptr::null_mut(), ptr::null_mut())
+            .build()
+            .user_data(ACCEPT);
+        self.push(entry)?;
+        self.accepting = true;
+
+        Ok(())
+    }
+
+    /// Adds an accepted connection and starts reading its first request.
+    fn add(&mut self, fd: RawFd) -> io::Result<()> {
+        let id = self
+            .free_conns
+            .pop()
+            .expect("cannot add a connection while connection pool is full.");
+
+        // SAFETY: the fd was just returned by accept and is owned by nobody else
+        let stream = unsafe { TcpStream::from_raw_fd(fd) };
+        stream.set_nodelay(!self.nagle)?;
+
+        let conn = &mut self.conns[id];
+        conn.stream = Some(stream);
+        conn.reset(Action::Read);
+        self.metrics.connection_opened();
+
+        let entry = conn.next_entry(id);
+        self.push(entry)
+    }
+
+    /// Deletes a connection by id, closing it.
+    fn delete(&mut self, id: usize) {
+        let conn = &mut self.conns[id];
+        conn.stream = None;
+        conn.reset(Action::Read);
+        self.free_conns.push(id);
+        self.metrics.connection_closed();
+    }
+
+    /// Handles the completion of a connection's `Recv` or `Send`.
+    fn complete(&mut self, id: usize, result: i32, queue_depth: u32) -> io::Result<()> {
+        if result <= 0 {
+            if result < 0 {
+                let e = io::Error::from_raw_os_error(-result);
+                if !framing::is_disconnect(&e) {
+                    error!("unexpected error: {e}");
+                }
+            }
+
+            self.delete(id);
+            return self.accept();
+        }
+
+        let conn = &mut self.conns[id];
+        conn.idx += result as usize;
+
+        match conn.action {
+            // Size the buffer for the body once the length prefix is in
+            Action::Read if conn.idx == HEADER_SIZE && conn.buf.get_ref().len() == HEADER_SIZE => {
+                let header = conn.buf.get_ref()[..HEADER_SIZE].try_into().unwrap();
+                let len = framing::decode_header(header);
+                if len == 0 || len > MAX_REQUEST_BODY {
+                    warn!("invalid request length {len}");
+                    self.delete(id);
+                    return self.accept();
+                }
+                conn.buf.get_mut().resize(HEADER_SIZE + len, 0);
+            }
+            Action::Read if conn.idx == conn.buf.get_ref().len() => {
+                let request = match conn.deserialize_request() {
+                    Ok(request) => request,
+                    Err(e) => {
+                        warn!("malformed request: {e}");
+                        self.delete(id);
+                        return self.accept();
+                    }
+                };
+                let response = self.handler.handle(&request, queue_depth);
+                self.metrics.request_handled(response.server_process_ns);
+                conn.reset(Action::Write);
+                if let Err(e) = conn.serialize_response(response) {
+                    warn!("failed to serialize response: {e}");
+                    self.delete(id);
+                    return self.accept();
+                }
+            }
+            Action::Write if conn.idx == conn.buf.get_ref().len() => {
+                if self.shutting_down {
+                    self.delete(id);
+                    return Ok(());
+                }
+                conn.reset(Action::Read);
+            }
+            _ => {}
+        }
+
+        let entry = conn.next_entry(id);
+        self.push(entry)
+    }
+
+    /// Pushes an entry to the submission queue, submitting queued entries
+    /// first if it is full.
+    fn push(&mut self, entry: squeue::Entry) -> io::Result<()> {
+        loop {
+            // SAFETY: the buffers of connection entries live in `self.conns`,
+            // which is never resized, and a connection's buffer is only touched
+            // again once its entry completes
+            if unsafe { self.ring.submission().push(&entry) }.is_ok() {
+                return Ok(());
+            }
+
+            self.ring.submit()?;
+        }
+    }
+}
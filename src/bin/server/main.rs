@@ -1,61 +1,560 @@
 use std::{
-    net::{Ipv4Addr, SocketAddrV4},
+    fs::File,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
     time::Duration,
 };
 
-use clap::{Parser, ValueEnum};
+use clap::{CommandFactory, Parser, ValueEnum, error::ErrorKind};
+use crossbeam_channel::bounded;
+use handler::{Backpressure, DoWork, Handler, SlowTail};
+use log::info;
+use rust_server_benchmarks::{buffers::BufferSizes, files, tls};
 
+mod cgroup;
+mod connection;
 mod epoll;
+mod handler;
 mod io_uring;
+mod metrics;
+mod mio;
 mod threadpool;
+mod tokio;
+mod udp;
+mod uds;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, serde::Serialize)]
 #[command(version, about, long_about = None)]
 struct Args {
     /// The type of server
     #[arg(short, long)]
     kind: Kind,
 
-    /// Timeout in seconds
+    /// Timeout in seconds, after which the server shuts down (as it does on
+    /// Ctrl-C), letting connections finish their current request
     #[arg(short, long, default_value_t = 24)]
     timeout: u64,
 
-    /// IP address to bind to
+    /// IP address to bind to, IPv4 or IPv6 (e.g. ::1)
     #[arg(short, long, default_value = "127.0.0.1")]
-    ip: Ipv4Addr,
+    ip: IpAddr,
 
     /// Port to bind to
     #[arg(short, long, default_value_t = 8080)]
     port: u16,
 
-    /// Threadpool size (ignored for epoll, io_uring servers)
-    #[arg(short, long, default_value_t = 16)]
-    tp_size: usize,
+    /// Path of the socket to bind to (Unix domain socket server only)
+    #[arg(long, default_value = "/tmp/rust-server-benchmarks.sock")]
+    uds_path: PathBuf,
+
+    /// Threadpool size, per acceptor: "auto" for one thread per available
+    /// CPU, or a fixed number of threads (threadpool server only)
+    #[arg(long, default_value = "auto")]
+    tp_size: threadpool::PoolSize,
+
+    /// Number of accept loops, each with its own SO_REUSEPORT listener and
+    /// threadpool, so the kernel balances new connections across them
+    /// (threadpool server only)
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    acceptors: u64,
+
+    /// Maximum number of accepted connections waiting for a worker thread,
+    /// per acceptor. Unbounded by default, which hides saturation by queueing
+    /// without limit (threadpool server only)
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    queue_depth: Option<u64>,
+
+    /// What an acceptor does with a connection when its queue is full
+    /// (threadpool server only)
+    #[arg(long, default_value = "block")]
+    when_full: threadpool::WhenFull,
+
+    /// Number of event loop threads (epoll, mio, io_uring servers), runtime worker
+    /// threads (Tokio server), or threads receiving datagrams (UDP server)
+    #[arg(long, default_value_t = 4)]
+    threads: usize,
+
+    /// Pin each epoll thread to a core, thread i to core i % number of cores
+    /// (epoll server only)
+    #[arg(long)]
+    pin_threads: bool,
+
+    /// Register connections edge-triggered (EPOLLET) instead of
+    /// level-triggered, draining each one until it would block on every event
+    /// (epoll server only)
+    #[arg(long)]
+    edge_triggered: bool,
+
+    /// How accepted connections reach the epoll threads: over one channel
+    /// they all receive from (shared), dealt out in turn over a channel per
+    /// thread (round-robin), or from a SO_REUSEPORT listener and acceptor per
+    /// thread that the kernel balances connections across (reuse-port)
+    /// (epoll server only)
+    #[arg(long, default_value = "shared")]
+    accept_sharding: epoll::AcceptSharding,
+
+    /// Maximum number of concurrent connections per event loop thread
+    /// (epoll, mio, io_uring servers only)
+    #[arg(long, default_value_t = 1024)]
+    capacity: usize,
+
+    /// Maximum number of events each event loop thread handles per wait
+    /// (epoll, mio servers only)
+    #[arg(long, default_value_t = 256)]
+    max_events: usize,
+
+    /// Number of submission queue entries per ring (io_uring server only)
+    #[arg(long, default_value_t = 256)]
+    sq_depth: u32,
+
+    /// Maximum number of bytes requested per read syscall (epoll, mio servers
+    /// only)
+    #[arg(long)]
+    read_chunk_size: Option<usize>,
+
+    /// Milliseconds event loop threads wait for events before checking for
+    /// shutdown and idle connections (epoll, mio servers only)
+    #[arg(long, default_value_t = 100)]
+    wait_timeout: u64,
+
+    /// Close connections that have been idle for this many seconds (epoll,
+    /// mio, threadpool, Unix domain socket servers only; the epoll and mio
+    /// servers check for idle connections every wait timeout)
+    #[arg(long)]
+    idle_timeout: Option<u64>,
+
+    /// Close connections that take longer than this many milliseconds to send
+    /// a whole request once it has started (epoll, mio, threadpool servers
+    /// only; the epoll and mio servers check it every wait timeout)
+    #[arg(long)]
+    request_deadline: Option<u64>,
+
+    /// Leave Nagle's algorithm enabled instead of setting TCP_NODELAY on
+    /// accepted connections. Small responses can then be held back until the
+    /// previous one is acknowledged, which with delayed ACKs adds up to ~40ms
+    /// of latency (TCP servers only)
+    #[arg(long)]
+    nagle: bool,
+
+    /// Size in bytes to request for the send buffer (SO_SNDBUF) of every
+    /// connection, or of the socket for the UDP server. The kernel may grant a
+    /// different size (Linux doubles it), which is logged at startup
+    #[arg(long)]
+    sndbuf: Option<usize>,
+
+    /// Size in bytes to request for the receive buffer (SO_RCVBUF) of every
+    /// connection, or of the socket for the UDP server. The granted size is
+    /// logged at startup
+    #[arg(long)]
+    rcvbuf: Option<usize>,
+
+    /// Expect and strip a PROXY protocol (v1 or v2) header at the start of
+    /// each connection. Connections whose header doesn't arrive within 100 ms
+    /// are dropped (epoll, mio, threadpool servers only)
+    #[arg(long)]
+    accept_proxy_protocol: bool,
+
+    /// Limit the server to this many CPUs using a cgroup v2 created next to
+    /// the server's own (Linux only, requires write access to the parent
+    /// cgroup, e.g. running as root, with the cpu controller enabled in it)
+    #[arg(long)]
+    cpu_quota: Option<f64>,
+
+    /// Serve connections over TLS with a self-signed certificate (threadpool
+    /// server only)
+    #[arg(long)]
+    tls: bool,
+
+    /// Number of files to create at startup for file-read work to read, with
+    /// path ids 0 to n - 1. They are deleted when the server shuts down
+    #[arg(long, default_value_t = 0)]
+    files: u64,
+
+    /// Size of each file created for file-read work, in bytes
+    #[arg(long, default_value_t = 1 << 20)]
+    file_size: u64,
+
+    /// Fraction of requests (between 0 and 1), chosen at random, that sleep
+    /// for --slow-extra-micros before being answered, giving the latencies a
+    /// known tail to check client percentiles against (for the Tokio server,
+    /// requests for sleep work are never slowed down)
+    #[arg(long, requires = "slow_extra_micros")]
+    slow_fraction: Option<f64>,
+
+    /// Microseconds the requests picked by --slow-fraction sleep for
+    #[arg(long, requires = "slow_fraction")]
+    slow_extra_micros: Option<u64>,
+
+    /// Turn requests away with a busy status, without doing their work, while
+    /// the queue depth responses report is above this, so clients can back off
+    /// (epoll, io_uring, mio, threadpool servers only)
+    #[arg(long)]
+    busy_threshold: Option<u32>,
+
+    /// Print the request rate and number of open connections (and queued
+    /// connections, for the threadpool server) every second
+    #[arg(long)]
+    log_metrics: bool,
+
+    /// Write the per-second metrics of --log-metrics to this CSV file instead
+    /// of printing them
+    #[arg(long)]
+    metrics_file: Option<PathBuf>,
+
+    /// Serve metrics for Prometheus to scrape over HTTP on this port (at
+    /// /metrics, on --ip): the requests handled, open connections (and queued
+    /// connections, for the threadpool server), and a histogram of the time
+    /// spent doing requests' work
+    #[arg(long)]
+    metrics_port: Option<u16>,
+
+    /// Check the arguments, print the resolved configuration (every argument,
+    /// defaults included, and derived values such as the number of worker
+    /// threads) as JSON, and exit without binding
+    #[arg(long)]
+    dry_run: bool,
 }
 
-#[derive(Clone, Debug, ValueEnum)]
+#[derive(Clone, Debug, ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
 enum Kind {
     Epoll,
+    #[serde(rename = "io-uring")]
     IOUring,
     ThreadPool,
+
+    /// An event loop like the edge-triggered epoll server's, built on mio
+    /// (epoll on Linux, kqueue on macOS and the BSDs)
+    Mio,
+
+    /// A task per connection on a multi-threaded Tokio runtime
+    Tokio,
+
+    /// Requests and responses are single datagrams
+    Udp,
+
+    /// A thread per connection over a Unix domain socket at --uds-path,
+    /// leaving the TCP/IP stack out of the latency
+    Uds,
 }
 
 fn main() {
+    // Startup and shutdown messages are logged at info, connection errors at warn or error; set
+    // RUST_LOG (e.g. RUST_LOG=warn) to quiet the server or to show more
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
     let args = Args::parse();
     let timeout = Duration::from_secs(args.timeout);
-    let addr = SocketAddrV4::new(args.ip, args.port);
+    let addr = SocketAddr::new(args.ip, args.port);
+    let idle_timeout = args.idle_timeout.map(Duration::from_secs);
+    let request_deadline = args.request_deadline.map(Duration::from_millis);
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let buffers = BufferSizes {
+        send: args.sndbuf,
+        recv: args.rcvbuf,
+    };
+
+    let wait_timeout = Duration::from_millis(args.wait_timeout);
+
+    if args.tls && !matches!(args.kind, Kind::ThreadPool) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--tls is only supported by the threadpool server",
+            )
+            .exit();
+    }
+    let tls = args.tls.then(|| tls::server_config().unwrap());
+
+    if args.nagle && matches!(args.kind, Kind::Udp | Kind::Uds) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--nagle is not supported by the UDP or Unix domain socket servers",
+            )
+            .exit();
+    }
+
+    if args.accept_proxy_protocol
+        && matches!(
+            args.kind,
+            Kind::IOUring | Kind::Tokio | Kind::Udp | Kind::Uds
+        )
+    {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--accept-proxy-protocol is not supported by the io_uring, Tokio, UDP, or Unix \
+                 domain socket servers",
+            )
+            .exit();
+    }
+
+    if args.idle_timeout.is_some() && matches!(args.kind, Kind::IOUring | Kind::Tokio | Kind::Udp) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--idle-timeout is not supported by the io_uring, Tokio, or UDP servers",
+            )
+            .exit();
+    }
+
+    if args.request_deadline.is_some()
+        && matches!(
+            args.kind,
+            Kind::IOUring | Kind::Tokio | Kind::Udp | Kind::Uds
+        )
+    {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--request-deadline is not supported by the io_uring, Tokio, UDP, or Unix domain \
+                 socket servers",
+            )
+            .exit();
+    }
+
+    if args.busy_threshold.is_some() && matches!(args.kind, Kind::Tokio | Kind::Udp | Kind::Uds) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--busy-threshold is not supported by the Tokio, UDP, or Unix domain socket \
+                 servers, which don't report a queue depth",
+            )
+            .exit();
+    }
+
+    if args.metrics_port == Some(args.port) && !matches!(args.kind, Kind::Udp | Kind::Uds) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "--metrics-port {} is the port the server binds to",
+                    args.port
+                ),
+            )
+            .exit();
+    }
+    let metrics_addr = args.metrics_port.map(|port| SocketAddr::new(args.ip, port));
+
+    if let Some(fraction) = args.slow_fraction
+        && !(0.0..=1.0).contains(&fraction)
+    {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!("--slow-fraction {fraction} is not between 0 and 1"),
+            )
+            .exit();
+    }
+
+    if args.dry_run {
+        // The Unix domain socket server has a thread per connection instead
+        let workers = match args.kind {
+            Kind::ThreadPool => Some(args.tp_size.resolve() * args.acceptors as usize),
+            Kind::Epoll | Kind::IOUring | Kind::Mio | Kind::Tokio | Kind::Udp => Some(args.threads),
+            Kind::Uds => None,
+        };
+        let listen = match args.kind {
+            Kind::Uds => args.uds_path.display().to_string(),
+            _ => addr.to_string(),
+        };
+        let config = serde_json::json!({
+            "args": &args,
+            "resolved": {
+                "addr": listen,
+                "timeout_secs": timeout.as_secs_f64(),
+                "worker_threads": workers,
+                "log_metrics": args.log_metrics || args.metrics_file.is_some(),
+                "metrics_addr": metrics_addr.map(|addr| addr.to_string()),
+            },
+        });
+        println!("{}", serde_json::to_string_pretty(&config).unwrap());
+        return;
+    }
 
-    std::thread::spawn(move || match args.kind {
+    let cgroup = args.cpu_quota.map(|cores| match cgroup::limit_cpu(cores) {
+        Ok(cgroup) => {
+            info!("Limited to {cores} CPUs via {}", cgroup.path().display());
+            cgroup
+        }
+        Err(e) => Args::command()
+            .error(
+                ErrorKind::Io,
+                format!("--cpu-quota {cores} couldn't create a cgroup: {e}"),
+            )
+            .exit(),
+    });
+
+    let log_metrics = args.log_metrics || args.metrics_file.is_some();
+    let metrics = Arc::new(metrics::Metrics::new(log_metrics || metrics_addr.is_some()));
+    let queue_depth = matches!(args.kind, Kind::ThreadPool);
+    let logger = log_metrics.then(|| {
+        let out: Box<dyn Write + Send> = match &args.metrics_file {
+            Some(path) => Box::new(File::create(path).unwrap()),
+            None => Box::new(io::stdout()),
+        };
+        metrics::spawn_logger(metrics.clone(), out, queue_depth, shutdown.clone())
+    });
+    let exporter = metrics_addr.map(|addr| {
+        let listener = TcpListener::bind(addr).unwrap();
+        info!("Serving metrics at http://{addr}/metrics");
+        metrics::spawn_exporter(metrics.clone(), listener, queue_depth, shutdown.clone())
+    });
+
+    if args.files > 0 {
+        let dir = std::env::temp_dir().join(format!("rsb-files-{}", std::process::id()));
+        files::create(&dir, args.files, args.file_size).unwrap();
+        info!(
+            "Created {} files of {} bytes in {}",
+            args.files,
+            args.file_size,
+            dir.display()
+        );
+    }
+
+    let mut handler: Arc<dyn Handler> = Arc::new(DoWork);
+    if let (Some(fraction), Some(extra)) = (args.slow_fraction, args.slow_extra_micros) {
+        handler = Arc::new(SlowTail {
+            inner: handler,
+            fraction,
+            extra: Duration::from_micros(extra),
+        });
+    }
+    if let Some(threshold) = args.busy_threshold {
+        handler = Arc::new(Backpressure {
+            inner: handler,
+            threshold,
+        });
+    }
+
+    // Stop early on Ctrl-C
+    let (interrupt_tx, interrupt_rx) = bounded(1);
+    ctrlc::set_handler(move || {
+        let _ = interrupt_tx.try_send(());
+    })
+    .unwrap();
+
+    let uds = matches!(args.kind, Kind::Uds).then(|| args.uds_path.clone());
+    let shutdown_clone = shutdown.clone();
+    let server = std::thread::spawn(move || match args.kind {
         Kind::Epoll => {
-            todo!("not implemented")
+            let cfg = epoll::Config {
+                addr,
+                buffers,
+                n_threads: args.threads,
+                capacity: args.capacity,
+                max_events: args.max_events,
+                read_chunk_size: args.read_chunk_size,
+                wait_timeout,
+                idle_timeout,
+                request_deadline,
+                accept_proxy_protocol: args.accept_proxy_protocol,
+                pin_threads: args.pin_threads,
+                edge_triggered: args.edge_triggered,
+                sharding: args.accept_sharding,
+                nagle: args.nagle,
+            };
+            cfg.run(shutdown_clone, metrics, handler);
         }
         Kind::IOUring => {
-            todo!("not implemented")
+            let cfg = io_uring::Config {
+                addr,
+                buffers,
+                n_threads: args.threads,
+                capacity: args.capacity,
+                sq_depth: args.sq_depth,
+                nagle: args.nagle,
+            };
+            cfg.run(shutdown_clone, metrics, handler);
         }
         Kind::ThreadPool => {
-            threadpool::run(addr, args.tp_size);
+            let cfg = threadpool::Config {
+                addr,
+                buffers,
+                tp_size: args.tp_size.resolve(),
+                acceptors: args.acceptors as usize,
+                idle_timeout,
+                request_deadline,
+                accept_proxy_protocol: args.accept_proxy_protocol,
+                tls,
+                queue_depth: args.queue_depth.map(|depth| depth as usize),
+                when_full: args.when_full,
+                nagle: args.nagle,
+            };
+            cfg.run(shutdown_clone, metrics, handler);
+        }
+        Kind::Mio => {
+            let cfg = mio::Config {
+                addr,
+                buffers,
+                n_threads: args.threads,
+                capacity: args.capacity,
+                max_events: args.max_events,
+                read_chunk_size: args.read_chunk_size,
+                wait_timeout,
+                idle_timeout,
+                request_deadline,
+                accept_proxy_protocol: args.accept_proxy_protocol,
+                nagle: args.nagle,
+            };
+            cfg.run(shutdown_clone, metrics, handler);
+        }
+        Kind::Tokio => {
+            let cfg = tokio::Config {
+                addr,
+                buffers,
+                n_threads: args.threads,
+                nagle: args.nagle,
+            };
+            cfg.run(shutdown_clone, metrics, handler);
+        }
+        Kind::Udp => {
+            let cfg = udp::Config {
+                addr,
+                buffers,
+                n_threads: args.threads,
+            };
+            cfg.run(shutdown_clone, metrics, handler);
+        }
+        Kind::Uds => {
+            let cfg = uds::Config {
+                path: args.uds_path,
+                buffers,
+                idle_timeout,
+            };
+            cfg.run(shutdown_clone, metrics, handler);
         }
     });
 
-    std::thread::sleep(timeout);
+    if interrupt_rx.recv_timeout(timeout).is_ok() {
+        info!("Interrupted, shutting down");
+    }
+    shutdown.store(true, Ordering::SeqCst);
+
+    // Wake up the accept loop so it sees the shutdown
+    match uds {
+        Some(path) => drop(UnixStream::connect(path)),
+        None => drop(TcpStream::connect(addr)),
+    }
+
+    server.join().unwrap();
+    files::remove().unwrap();
+    if let Some(cgroup) = cgroup {
+        cgroup.remove().unwrap();
+    }
+    if let Some(logger) = logger {
+        logger.join().unwrap().unwrap();
+    }
+    if let (Some(exporter), Some(addr)) = (exporter, metrics_addr) {
+        drop(TcpStream::connect(addr));
+        exporter.join().unwrap();
+    }
+    info!("Server shut down cleanly");
 }
@@ -0,0 +1,284 @@
+//! Counters of the server's load, logged once a second so latency spikes seen by
+//! clients can be lined up with what the server was doing at the time, or
+//! scraped by Prometheus.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+
+/// How often the counters are logged.
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// The upper bounds (in nanoseconds) of the service time histogram's buckets,
+/// besides the last one, which holds everything slower.
+const SERVICE_TIME_BOUNDS: [u64; 13] = [
+    1_000,
+    5_000,
+    10_000,
+    50_000,
+    100_000,
+    500_000,
+    1_000_000,
+    5_000_000,
+    10_000_000,
+    50_000_000,
+    100_000_000,
+    500_000_000,
+    1_000_000_000,
+];
+
+/// Counters shared by every server thread. Updates are no-ops unless the
+/// counters are enabled, so servers don't contend on them otherwise.
+#[derive(Default)]
+pub struct Metrics {
+    /// Whether the counters are updated.
+    enabled: bool,
+
+    /// The number of requests handled so far.
+    requests: AtomicU64,
+
+    /// The number of connections currently open.
+    connections: AtomicUsize,
+
+    /// The number of accepted connections waiting for a worker thread
+    /// (threadpool server only).
+    queued: AtomicUsize,
+
+    /// The number of requests whose service time (the time spent doing their
+    /// work, as reported to clients) fell in each bucket of
+    /// `SERVICE_TIME_BOUNDS`, and above the last bound.
+    service_times: [AtomicU64; SERVICE_TIME_BOUNDS.len() + 1],
+
+    /// The total service time of every request, in nanoseconds.
+    service_time_ns: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            ..Self::default()
+        }
+    }
+
+    /// Counts a handled request, whose work took `service_ns` nanoseconds (as
+    /// reported to the client).
+    pub fn request_handled(&self, service_ns: u64) {
+        if self.enabled {
+            self.requests.fetch_add(1, Ordering::Relaxed);
+
+            let bucket = SERVICE_TIME_BOUNDS.partition_point(|&bound| bound < service_ns);
+            self.service_times[bucket].fetch_add(1, Ordering::Relaxed);
+            self.service_time_ns
+                .fetch_add(service_ns, Ordering::Relaxed);
+        }
+    }
+
+    pub fn connection_opened(&self) {
+        if self.enabled {
+            self.connections.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn connection_closed(&self) {
+        if self.enabled {
+            self.connections.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn connection_queued(&self) {
+        if self.enabled {
+            self.queued.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn connection_dequeued(&self) {
+        if self.enabled {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Writes the counters in the Prometheus text format, including queued
+    /// connections if `queue_depth`.
+    fn write_prometheus(&self, out: &mut impl Write, queue_depth: bool) -> io::Result<()> {
+        writeln!(out, "# HELP rsb_requests_total Requests handled.")?;
+        writeln!(out, "# TYPE rsb_requests_total counter")?;
+        writeln!(
+            out,
+            "rsb_requests_total {}",
+            self.requests.load(Ordering::Relaxed)
+        )?;
+
+        writeln!(out, "# HELP rsb_connections Connections currently open.")?;
+        writeln!(out, "# TYPE rsb_connections gauge")?;
+        writeln!(
+            out,
+            "rsb_connections {}",
+            self.connections.load(Ordering::Relaxed)
+        )?;
+
+        if queue_depth {
+            writeln!(
+                out,
+                "# HELP rsb_queued_connections Accepted connections waiting for a worker thread."
+            )?;
+            writeln!(out, "# TYPE rsb_queued_connections gauge")?;
+            writeln!(
+                out,
+                "rsb_queued_connections {}",
+                self.queued.load(Ordering::Relaxed)
+            )?;
+        }
+
+        // Prometheus buckets are cumulative, counting every request at or below their bound
+        writeln!(
+            out,
+            "# HELP rsb_service_time_seconds Time spent doing the work of requests."
+        )?;
+        writeln!(out, "# TYPE rsb_service_time_seconds histogram")?;
+        let mut count = 0;
+        for (i, bucket) in self.service_times.iter().enumerate() {
+            count += bucket.load(Ordering::Relaxed);
+            match SERVICE_TIME_BOUNDS.get(i) {
+                Some(&bound) => writeln!(
+                    out,
+                    "rsb_service_time_seconds_bucket{{le=\"{}\"}} {count}",
+                    bound as f64 / 1e9
+                )?,
+                None => writeln!(
+                    out,
+                    "rsb_service_time_seconds_bucket{{le=\"+Inf\"}} {count}"
+                )?,
+            }
+        }
+        writeln!(
+            out,
+            "rsb_service_time_seconds_sum {}",
+            self.service_time_ns.load(Ordering::Relaxed) as f64 / 1e9
+        )?;
+        writeln!(out, "rsb_service_time_seconds_count {count}")
+    }
+}
+
+/// Spawns a thread that writes a CSV line with the request rate, open
+/// connections, and (if `queue_depth`) queued connections to `out` every
+/// second, until `shutdown` is set.
+pub fn spawn_logger(
+    metrics: Arc<Metrics>,
+    mut out: Box<dyn Write + Send>,
+    queue_depth: bool,
+    shutdown: Arc<AtomicBool>,
+) -> JoinHandle<io::Result<()>> {
+    std::thread::spawn(move || {
+        write!(out, "elapsed_s,requests_per_sec,connections")?;
+        if queue_depth {
+            write!(out, ",queued")?;
+        }
+        writeln!(out)?;
+
+        let start = Instant::now();
+        let mut last = start;
+        let mut last_requests = 0;
+        let mut next = start + INTERVAL;
+
+        loop {
+            std::thread::sleep(next.saturating_duration_since(Instant::now()));
+            next += INTERVAL;
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let now = Instant::now();
+            let requests = metrics.requests.load(Ordering::Relaxed);
+            let rate = (requests - last_requests) as f64 / (now - last).as_secs_f64();
+            last = now;
+            last_requests = requests;
+
+            write!(
+                out,
+                "{:.0},{rate:.0},{}",
+                (now - start).as_secs_f64(),
+                metrics.connections.load(Ordering::Relaxed)
+            )?;
+            if queue_depth {
+                write!(out, ",{}", metrics.queued.load(Ordering::Relaxed))?;
+            }
+            writeln!(out)?;
+        }
+
+        out.flush()
+    })
+}
+
+/// Spawns a thread that answers HTTP requests for `/metrics` on `listener` with
+/// the counters in the Prometheus text format (including queued connections if
+/// `queue_depth`), one connection at a time. It stops at the first connection
+/// accepted after `shutdown` is set.
+pub fn spawn_exporter(
+    metrics: Arc<Metrics>,
+    listener: TcpListener,
+    queue_depth: bool,
+    shutdown: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+
+            // A failed scrape is the scraper's problem, not the server's
+            if let Err(e) = stream.and_then(|stream| scrape(&metrics, stream, queue_depth)) {
+                warn!("failed to serve metrics: {e}");
+            }
+        }
+    })
+}
+
+/// Reads an HTTP request from `stream` and answers it with the metrics, or with
+/// 404 Not Found for anything but `GET /metrics`. The connection is closed
+/// afterwards.
+fn scrape(metrics: &Metrics, stream: TcpStream, queue_depth: bool) -> io::Result<()> {
+    stream.set_read_timeout(Some(INTERVAL))?;
+    let mut reader = BufReader::new(&stream);
+
+    // The request line (e.g. "GET /metrics HTTP/1.1"), then headers up to an empty line
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next();
+    let path = parts.next().and_then(|target| target.split('?').next());
+    let (status, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) => {
+            let mut body = Vec::new();
+            metrics.write_prometheus(&mut body, queue_depth)?;
+            ("200 OK", body)
+        }
+        _ => ("404 Not Found", Vec::new()),
+    };
+
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
@@ -0,0 +1,340 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use ::mio::{Events, Interest, Poll, Token, net};
+
+use crate::{
+    connection::{Action, Connection},
+    handler::Handler,
+    metrics::Metrics,
+};
+use crossbeam_channel::{Receiver, RecvTimeoutError, unbounded};
+use log::{error, info, warn};
+use rust_server_benchmarks::{buffers::BufferSizes, framing, proxy};
+use socket2::SockRef;
+
+#[derive(Clone, Copy)]
+pub struct Config {
+    /// The address to bind to.
+    pub addr: SocketAddr,
+
+    /// The send and receive buffer sizes of accepted connections, which they inherit from the
+    /// listener.
+    pub buffers: BufferSizes,
+
+    /// The number of event loop threads.
+    pub n_threads: usize,
+
+    /// The maximum number of concurrent connections per thread.
+    pub capacity: usize,
+
+    /// The maximum number of events each thread waits for per cycle.
+    pub max_events: usize,
+
+    /// The maximum number of bytes requested from a single `read`.
+    pub read_chunk_size: Option<usize>,
+
+    /// How long a thread waits for events before doing periodic maintenance (checking for
+    /// shutdown and closing idle connections).
+    pub wait_timeout: Duration,
+
+    /// Connections without any activity for this long are closed during maintenance.
+    pub idle_timeout: Option<Duration>,
+
+    /// Connections that started sending a request this long ago without finishing it are closed
+    /// during maintenance.
+    pub request_deadline: Option<Duration>,
+
+    /// Strip a PROXY protocol header from the start of each connection. Headers are read by the
+    /// accepting thread before the connection is handed to an event loop thread.
+    pub accept_proxy_protocol: bool,
+
+    /// Leave Nagle's algorithm enabled on accepted connections instead of setting `TCP_NODELAY`.
+    pub nagle: bool,
+}
+
+impl Config {
+    /// Runs the mio server, which serves connections like the edge-triggered epoll server but
+    /// through mio's portable event loop (epoll on Linux, kqueue on macOS and the BSDs). Once
+    /// `shutdown` is set, the next accepted connection stops the server from accepting, and event
+    /// loop threads close their connections as they finish their current request, exiting once
+    /// they have none left.
+    pub fn run(self, shutdown: Arc<AtomicBool>, metrics: Arc<Metrics>, handler: Arc<dyn Handler>) {
+        let listener = TcpListener::bind(self.addr).unwrap();
+        let granted = self.buffers.apply(SockRef::from(&listener)).unwrap();
+        let (tx, rx) = unbounded::<TcpStream>();
+        info!("Server listening at {}", self.addr);
+        info!("Socket buffers: {granted}");
+
+        // Start each event loop thread
+        let handles = (0..self.n_threads)
+            .map(|_| {
+                let rx = rx.clone();
+                let shutdown = shutdown.clone();
+                let metrics = metrics.clone();
+                let handler = handler.clone();
+                std::thread::spawn(move || {
+                    MioThread::new(&self, rx, shutdown, metrics, handler).run();
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // Accept connections
+        for stream in listener.incoming() {
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let mut stream = stream.unwrap();
+            if self.accept_proxy_protocol {
+                stream
+                    .set_read_timeout(Some(proxy::HEADER_TIMEOUT))
+                    .unwrap();
+                if let Err(e) = proxy::read_header(&mut stream) {
+                    warn!("{e}");
+                    continue;
+                }
+            }
+
+            stream.set_nonblocking(true).unwrap();
+            stream.set_nodelay(!self.nagle).unwrap();
+            tx.send(stream).unwrap();
+        }
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+}
+
+struct MioThread {
+    /// The thread's event loop. Connections are registered for both reads and writes once, and
+    /// mio reports readiness edge-triggered.
+    poll: Poll,
+
+    /// Reusable buffer of events.
+    events: Events,
+
+    /// The ids of the connections with events in the current cycle.
+    ready: Vec<usize>,
+
+    /// The connections, indexed by their token.
+    conns: Vec<Connection<net::TcpStream>>,
+
+    /// Buffer of connections that are available to use.
+    free_conns: Vec<usize>,
+
+    /// The receiving side of a channel of connections.
+    rx_conn: Receiver<TcpStream>,
+
+    /// How long to wait for events before doing maintenance.
+    wait_timeout: Duration,
+
+    /// Connections idle for this long are closed during maintenance.
+    idle_timeout: Option<Duration>,
+
+    /// Connections taking this long to send a request are closed during maintenance.
+    request_deadline: Option<Duration>,
+
+    /// Set when the thread should exit.
+    shutdown: Arc<AtomicBool>,
+
+    /// The server's load counters.
+    metrics: Arc<Metrics>,
+
+    /// Answers requests.
+    handler: Arc<dyn Handler>,
+}
+
+impl MioThread {
+    fn new(
+        cfg: &Config,
+        rx_conn: Receiver<TcpStream>,
+        shutdown: Arc<AtomicBool>,
+        metrics: Arc<Metrics>,
+        handler: Arc<dyn Handler>,
+    ) -> Self {
+        Self {
+            poll: Poll::new().unwrap(),
+            events: Events::with_capacity(cfg.max_events),
+            ready: Vec::with_capacity(cfg.max_events),
+            conns: (0..cfg.capacity)
+                .map(|_| Connection::new(None, cfg.read_chunk_size))
+                .collect(),
+            free_conns: (0..cfg.capacity).collect(),
+            rx_conn,
+            wait_timeout: cfg.wait_timeout,
+            idle_timeout: cfg.idle_timeout,
+            request_deadline: cfg.request_deadline,
+            shutdown,
+            metrics,
+            handler,
+        }
+    }
+
+    /// Adds a connection.
+    fn add(&mut self, stream: TcpStream) -> io::Result<()> {
+        let id = self
+            .free_conns
+            .pop()
+            .expect("cannot add a connection while connection pool is full.");
+
+        let mut stream = net::TcpStream::from_std(stream);
+        self.poll.registry().register(
+            &mut stream,
+            Token(id),
+            Interest::READABLE | Interest::WRITABLE,
+        )?;
+
+        self.conns[id].init(stream);
+        self.metrics.connection_opened();
+
+        Ok(())
+    }
+
+    /// Deletes a connection by id.
+    fn delete(&mut self, id: usize) -> io::Result<()> {
+        let conn = &mut self.conns[id];
+        let mut stream = conn.stream.take().expect("connection not in use.");
+
+        self.poll.registry().deregister(&mut stream)?;
+
+        conn.reset(Action::Read);
+        self.free_conns.push(id);
+        self.metrics.connection_closed();
+
+        Ok(())
+    }
+
+    /// Returns `true` if there are no connections in use.
+    fn is_empty(&self) -> bool {
+        self.free_conns.len() == self.conns.len()
+    }
+
+    /// Returns `true` if the connection pool is at capacity.
+    fn is_full(&self) -> bool {
+        self.free_conns.is_empty()
+    }
+
+    fn run(mut self) {
+        let mut last_maintenance = Instant::now();
+        let mut shutting_down = false;
+
+        loop {
+            // Periodically check for shutdown and close idle connections
+            if last_maintenance.elapsed() >= self.wait_timeout {
+                if self.shutdown.load(Ordering::SeqCst) {
+                    shutting_down = true;
+                    for id in 0..self.conns.len() {
+                        if self.conns[id].is_between_requests() {
+                            self.delete(id).unwrap();
+                        }
+                    }
+                }
+
+                for id in 0..self.conns.len() {
+                    if self.conns[id].is_expired(self.idle_timeout, self.request_deadline) {
+                        self.delete(id).unwrap();
+                    }
+                }
+
+                last_maintenance = Instant::now();
+            }
+
+            if shutting_down && self.is_empty() {
+                return;
+            }
+
+            // We must have at least one connection
+            if self.is_empty() {
+                let stream = match self.rx_conn.recv_timeout(self.wait_timeout) {
+                    Ok(stream) => stream,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                };
+                self.add(stream).unwrap();
+            }
+
+            // Keep accepting connections until we've reached the capacity or there
+            // are no connections ready.
+            while !shutting_down && !self.is_full() {
+                match self.rx_conn.try_recv() {
+                    Ok(stream) => self.add(stream).unwrap(),
+                    _ => break,
+                }
+            }
+
+            // Signals (e.g. SIGINT for shutting down) interrupt the wait
+            match self.poll.poll(&mut self.events, Some(self.wait_timeout)) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => result.unwrap(),
+            }
+
+            self.ready.clear();
+            self.ready
+                .extend(self.events.iter().map(|event| event.token().0));
+
+            for i in 0..self.ready.len() {
+                // Connections that are ready but still waiting to be served
+                let queue_depth = (self.ready.len() - i - 1) as u32;
+                self.serve(self.ready[i], queue_depth, shutting_down);
+            }
+        }
+    }
+
+    /// Serves a connection until it would block, since several requests may have arrived behind
+    /// a single edge. Responses are written right away, only waiting for the socket to become
+    /// writable if it fills up.
+    fn serve(&mut self, id: usize, queue_depth: u32, shutting_down: bool) {
+        loop {
+            let conn = &mut self.conns[id];
+
+            match conn.copy_until_blocked() {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    if !framing::is_disconnect(&e) {
+                        error!("unexpected error: {e}");
+                    }
+
+                    self.delete(id).unwrap();
+                    break;
+                }
+                _ => match conn.action {
+                    Action::Read => {
+                        let request = match conn.deserialize_request() {
+                            Ok(request) => request,
+                            Err(e) => {
+                                warn!("malformed request: {e}");
+                                self.delete(id).unwrap();
+                                break;
+                            }
+                        };
+                        let response = self.handler.handle(&request, queue_depth);
+                        let service_ns = response.server_process_ns;
+
+                        // Resetting the buffer for writing must happen before serializing
+                        conn.reset(Action::Write);
+                        if let Err(e) = conn.serialize_response(response) {
+                            warn!("failed to serialize response: {e}");
+                            self.delete(id).unwrap();
+                            break;
+                        }
+                        self.metrics.request_handled(service_ns);
+                    }
+                    Action::Write if shutting_down => {
+                        self.delete(id).unwrap();
+                        break;
+                    }
+                    Action::Write => conn.reset(Action::Read),
+                },
+            }
+        }
+    }
+}
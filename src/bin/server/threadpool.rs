@@ -1,68 +1,492 @@
-use crossbeam_channel::{SendError, Sender};
-use rust_server_benchmarks::protocol::{Deserialize, Request, Serialize};
-use std::io::ErrorKind;
-use std::net::{SocketAddrV4, TcpListener, TcpStream};
+use crate::handler::{self, Handler, Transport};
+use crate::metrics::Metrics;
+use clap::ValueEnum;
+use crossbeam_channel::{SendError, Sender, TrySendError};
+use log::{error, info, warn};
+use rust_server_benchmarks::{buffers::BufferSizes, proxy, tls::TlsStream};
+use rustls::{ServerConfig, ServerConnection};
+use socket2::{Domain, SockRef, Socket, Type};
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, ErrorKind, IoSlice, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 
-pub fn run(addr: SocketAddrV4, tp_size: usize) {
-    // Create our listener socket
-    let listener = TcpListener::bind(addr).unwrap();
+#[derive(Clone)]
+pub struct Config {
+    /// The address to bind to.
+    pub addr: SocketAddr,
 
-    // Start the threadpool
-    let tp = ThreadPool::spawn(tp_size);
+    /// The send and receive buffer sizes of accepted connections, which they inherit from the
+    /// listener.
+    pub buffers: BufferSizes,
 
-    println!("Server listening at {}", addr);
+    /// The number of worker threads of each acceptor.
+    pub tp_size: usize,
 
-    // Accept connections
-    for stream in listener.incoming() {
-        tp.execute(move || _handle_client(stream.unwrap())).unwrap();
+    /// The number of accept loops, each with its own `SO_REUSEPORT` listener and threadpool.
+    pub acceptors: usize,
+
+    /// Connections without any activity for this long are closed.
+    pub idle_timeout: Option<Duration>,
+
+    /// Connections that started sending a request this long ago without finishing it are closed.
+    pub request_deadline: Option<Duration>,
+
+    /// Strip a PROXY protocol header from the start of each connection.
+    pub accept_proxy_protocol: bool,
+
+    /// Leave Nagle's algorithm enabled on accepted connections instead of setting `TCP_NODELAY`.
+    pub nagle: bool,
+
+    /// Serve connections over TLS with this configuration.
+    pub tls: Option<Arc<ServerConfig>>,
+
+    /// The maximum number of connections each acceptor queues for its workers (unbounded if
+    /// `None`).
+    pub queue_depth: Option<usize>,
+
+    /// What an acceptor does with a connection when its queue is full.
+    pub when_full: WhenFull,
+}
+
+/// What an acceptor does with a connection when its queue is full.
+#[derive(Clone, Copy, Debug, ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WhenFull {
+    /// Wait for a worker to take a connection off the queue, leaving new
+    /// connections in the listen backlog meanwhile.
+    Block,
+
+    /// Close the connection right away.
+    Reject,
+}
+
+/// The number of worker threads of each acceptor: `auto` for one per available CPU, or a fixed
+/// number.
+#[derive(Clone, Copy, Debug)]
+pub enum PoolSize {
+    Auto,
+    Fixed(usize),
+}
+
+impl PoolSize {
+    /// Gets the number of threads, falling back to one if the number of CPUs is unknown.
+    pub fn resolve(self) -> usize {
+        match self {
+            PoolSize::Auto => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            PoolSize::Fixed(size) => size,
+        }
     }
 }
 
-fn _handle_client(mut stream: TcpStream) {
-    stream.set_nodelay(true).unwrap();
+impl FromStr for PoolSize {
+    type Err = String;
 
-    loop {
-        // Deserialize and handle the request
-        let response = match Request::deserialize(&mut stream) {
-            Ok(request) => request.do_work(),
-            Err(e) => {
-                if e.kind() != ErrorKind::UnexpectedEof {
-                    eprintln!("{e}");
-                }
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(PoolSize::Auto);
+        }
+
+        match s.parse() {
+            Ok(0) => Err("the threadpool needs at least 1 thread".to_string()),
+            Ok(size) => Ok(PoolSize::Fixed(size)),
+            Err(_) => Err(format!(
+                "expected \"auto\" or a number of threads, got {s:?}"
+            )),
+        }
+    }
+}
+
+impl serde::Serialize for PoolSize {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            PoolSize::Auto => serializer.serialize_str("auto"),
+            PoolSize::Fixed(size) => serializer.serialize_u64(*size as u64),
+        }
+    }
+}
+
+/// The connections being handled, so they can be woken up on shutdown.
+#[derive(Default)]
+struct OpenConnections {
+    streams: Mutex<HashMap<usize, TcpStream>>,
+
+    /// The id of the next connection.
+    next_id: AtomicUsize,
+}
+
+impl OpenConnections {
+    /// Stops reading from every connection, so handlers waiting for a request see the end of
+    /// the stream while those in the middle of one still finish it.
+    fn close_reads(&self) {
+        for stream in self.streams.lock().unwrap().values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+    }
+}
+
+/// What the acceptors and workers share.
+struct Shared {
+    /// The connections being handled.
+    open: OpenConnections,
+
+    /// Set when the server should stop.
+    shutdown: Arc<AtomicBool>,
+
+    /// The server's load counters.
+    metrics: Arc<Metrics>,
+
+    /// Answers requests.
+    handler: Arc<dyn Handler>,
+}
+
+impl Config {
+    /// Runs the threadpool server. Once `shutdown` is set, the next accepted connection stops
+    /// the server: it stops accepting, lets handlers finish their current request, and waits for
+    /// the workers to drain the queued connections.
+    pub fn run(self, shutdown: Arc<AtomicBool>, metrics: Arc<Metrics>, handler: Arc<dyn Handler>) {
+        // Create our listener sockets, which the kernel balances connections across
+        let listeners = (0..self.acceptors)
+            .map(|_| self.bind())
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        let granted = listeners
+            .iter()
+            .map(|listener| self.buffers.apply(SockRef::from(listener)))
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+
+        let shared = Arc::new(Shared {
+            open: OpenConnections::default(),
+            shutdown,
+            metrics,
+            handler,
+        });
+        let cfg = Arc::new(self);
+
+        info!("Server listening at {}", cfg.addr);
+        info!("Socket buffers: {}", granted[0]);
+
+        std::thread::scope(|s| {
+            for listener in &listeners {
+                s.spawn(|| cfg._accept(listener, &listeners, &shared));
+            }
+        });
+    }
+
+    /// Binds a listener to the server's address, sharing it with the other acceptors.
+    fn bind(&self) -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(self.addr), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        if self.acceptors > 1 {
+            socket.set_reuse_port(true)?;
+        }
+        socket.bind(&self.addr.into())?;
+        socket.listen(1024)?;
+        Ok(socket.into())
+    }
+
+    /// Accepts connections from `listener` into a threadpool of its own until the server shuts
+    /// down.
+    fn _accept(
+        self: &Arc<Self>,
+        listener: &TcpListener,
+        listeners: &[TcpListener],
+        shared: &Arc<Shared>,
+    ) {
+        // Start the threadpool
+        let tp = ThreadPool::spawn(self.tp_size, self.queue_depth);
 
+        // Number of connections waiting for a worker thread
+        let queued = Arc::new(AtomicUsize::new(0));
+
+        // Number of connections closed because the queue was full
+        let mut rejected = 0;
+
+        // Accept connections
+        for stream in listener.incoming() {
+            if shared.shutdown.load(Ordering::SeqCst) {
+                // Wake up the other acceptors, whose accepts fail once their listener is shut
+                // down
+                for listener in listeners {
+                    let _ = SockRef::from(listener).shutdown(Shutdown::Read);
+                }
                 break;
             }
+
+            let id = shared.open.next_id.fetch_add(1, Ordering::SeqCst);
+            let cfg = self.clone();
+            let queued = queued.clone();
+            let shared_clone = shared.clone();
+            queued.fetch_add(1, Ordering::SeqCst);
+            shared.metrics.connection_queued();
+
+            let queued_clone = queued.clone();
+            let job = move || {
+                queued_clone.fetch_sub(1, Ordering::SeqCst);
+                shared_clone.metrics.connection_dequeued();
+                cfg._handle_client(id, stream.unwrap(), &queued_clone, &shared_clone)
+            };
+            match self.when_full {
+                WhenFull::Block => tp.execute(job).unwrap(),
+                WhenFull::Reject => match tp.try_execute(job) {
+                    Ok(()) => {}
+                    // Dropping the job closes the connection
+                    Err(TrySendError::Full(_)) => {
+                        queued.fetch_sub(1, Ordering::SeqCst);
+                        shared.metrics.connection_dequeued();
+                        rejected += 1;
+                    }
+                    Err(TrySendError::Disconnected(_)) => panic!("the threadpool has exited"),
+                },
+            }
+        }
+
+        if rejected > 0 {
+            warn!("Rejected {rejected} connections because the queue was full");
+        }
+
+        shared.open.close_reads();
+        tp.join();
+    }
+
+    fn _handle_client(
+        &self,
+        id: usize,
+        mut stream: TcpStream,
+        queued: &AtomicUsize,
+        shared: &Shared,
+    ) {
+        shared
+            .open
+            .streams
+            .lock()
+            .unwrap()
+            .insert(id, stream.try_clone().unwrap());
+
+        // Connections still queued when the server shuts down are closed without being served
+        if !shared.shutdown.load(Ordering::SeqCst) {
+            shared.metrics.connection_opened();
+            self._serve_client(&mut stream, queued, shared);
+            shared.metrics.connection_closed();
+        }
+
+        shared.open.streams.lock().unwrap().remove(&id);
+    }
+
+    fn _serve_client(&self, stream: &mut TcpStream, queued: &AtomicUsize, shared: &Shared) {
+        let queue_depth = || queued.load(Ordering::SeqCst) as u32;
+        stream.set_nodelay(!self.nagle).unwrap();
+
+        // Reading times out if the client goes silent, which closes the connection
+        stream.set_read_timeout(self.idle_timeout).unwrap();
+
+        if self.accept_proxy_protocol
+            && let Err(e) = proxy::read_header(stream)
+        {
+            warn!("{e}");
+            return;
+        }
+
+        let deadline_stream = DeadlineStream::new(stream, self.idle_timeout, self.request_deadline);
+        match &self.tls {
+            Some(config) => {
+                let conn = match ServerConnection::new(config.clone()) {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        error!("{e}");
+                        return;
+                    }
+                };
+                let mut transport = TlsStream::new(conn, deadline_stream);
+                handler::serve(
+                    &mut transport,
+                    &*shared.handler,
+                    queue_depth,
+                    &shared.metrics,
+                );
+            }
+            None => {
+                // Buffer both directions so a request is usually a single read and a response a
+                // single write
+                let mut transport = Buffered {
+                    reader: BufReader::new(deadline_stream),
+                    writer: BufWriter::new(&*stream),
+                };
+                handler::serve(
+                    &mut transport,
+                    &*shared.handler,
+                    queue_depth,
+                    &shared.metrics,
+                );
+            }
+        }
+    }
+}
+
+/// A plaintext connection, buffered in both directions.
+struct Buffered<'a> {
+    reader: BufReader<DeadlineStream<'a>>,
+    writer: BufWriter<&'a TcpStream>,
+}
+
+impl Read for Buffered<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Write for Buffered<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.writer.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Transport for Buffered<'_> {
+    fn start_request(&mut self) {
+        self.reader.get_mut().start_request();
+    }
+}
+
+impl Transport for TlsStream<ServerConnection, DeadlineStream<'_>> {
+    fn start_request(&mut self) {
+        self.get_mut().start_request();
+    }
+}
+
+/// Reads from a stream, failing with `TimedOut` if a request isn't fully
+/// received within a deadline of its first byte arriving. This keeps slow
+/// clients from holding a worker thread indefinitely. Without a deadline, it
+/// reads from the stream directly. Writes go straight to the stream.
+struct DeadlineStream<'a> {
+    stream: &'a TcpStream,
+
+    /// How long to wait for the first byte of the request.
+    idle_timeout: Option<Duration>,
+
+    /// How long the client has to send the rest of the request.
+    request_deadline: Option<Duration>,
+
+    /// When the request must be fully received, once it has started.
+    deadline: Option<Instant>,
+}
+
+impl<'a> DeadlineStream<'a> {
+    fn new(
+        stream: &'a TcpStream,
+        idle_timeout: Option<Duration>,
+        request_deadline: Option<Duration>,
+    ) -> Self {
+        Self {
+            stream,
+            idle_timeout,
+            request_deadline,
+            deadline: None,
+        }
+    }
+
+    /// Clears the deadline so it restarts on the next byte read.
+    fn start_request(&mut self) {
+        self.deadline = None;
+    }
+}
+
+impl Write for DeadlineStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Read for DeadlineStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(request_deadline) = self.request_deadline else {
+            return self.stream.read(buf);
         };
 
-        // Serialize and send the response
-        if let Err(e) = response.serialize(&mut stream) {
-            eprintln!("{e}");
+        let timeout = match self.deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    return Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        "request was not received before the deadline",
+                    ));
+                }
+                Some(remaining)
+            }
+            None => self.idle_timeout,
+        };
+
+        self.stream.set_read_timeout(timeout)?;
+        let n = self.stream.read(buf)?;
+
+        if self.deadline.is_none() && n > 0 {
+            self.deadline = Some(Instant::now() + request_deadline);
         }
+
+        Ok(n)
     }
 }
 
 struct ThreadPool<F> {
     tx: Sender<F>,
+    handles: Vec<JoinHandle<()>>,
 }
 
 impl<F: FnOnce() + Send + 'static> ThreadPool<F> {
-    fn spawn(size: usize) -> Self {
-        let (tx, rx) = crossbeam_channel::unbounded::<F>();
-
-        for _ in 0..size {
-            let rx_clone = rx.clone();
-            std::thread::spawn(|| {
-                for f in rx_clone {
-                    f();
-                }
-            });
-        }
+    /// Spawns `size` workers that run jobs from a queue of up to `capacity` jobs (unbounded if
+    /// `None`).
+    fn spawn(size: usize, capacity: Option<usize>) -> Self {
+        let (tx, rx) = match capacity {
+            Some(capacity) => crossbeam_channel::bounded::<F>(capacity),
+            None => crossbeam_channel::unbounded::<F>(),
+        };
+
+        let handles = (0..size)
+            .map(|_| {
+                let rx_clone = rx.clone();
+                std::thread::spawn(|| {
+                    for f in rx_clone {
+                        f();
+                    }
+                })
+            })
+            .collect();
 
-        Self { tx }
+        Self { tx, handles }
     }
 
     fn execute(&self, f: F) -> Result<(), SendError<F>> {
         self.tx.send(f)?;
         Ok(())
     }
+
+    /// Queues a job unless the queue is full, in which case the job is handed back.
+    fn try_execute(&self, f: F) -> Result<(), TrySendError<F>> {
+        self.tx.try_send(f)
+    }
+
+    /// Waits for the workers to run every queued job and exit.
+    fn join(self) {
+        drop(self.tx);
+        for handle in self.handles {
+            handle.join().unwrap();
+        }
+    }
 }
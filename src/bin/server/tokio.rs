@@ -0,0 +1,178 @@
+use std::{
+    io::{self, Cursor},
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
+};
+
+use crate::{handler::Handler, metrics::Metrics};
+use ::tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    runtime,
+    sync::watch,
+    task::JoinSet,
+};
+use log::{error, info};
+use rust_server_benchmarks::{
+    buffers::BufferSizes,
+    framing::{self, HEADER_SIZE},
+    protocol::{Deserialize, MAX_REQUEST_BODY, Request, Response, Serialize, Status},
+};
+use socket2::SockRef;
+
+#[derive(Clone, Copy)]
+pub struct Config {
+    /// The address to bind to.
+    pub addr: SocketAddr,
+
+    /// The send and receive buffer sizes of accepted connections, which they inherit from the
+    /// listener.
+    pub buffers: BufferSizes,
+
+    /// The number of runtime worker threads.
+    pub n_threads: usize,
+
+    /// Leave Nagle's algorithm enabled on accepted connections instead of setting `TCP_NODELAY`.
+    pub nagle: bool,
+}
+
+impl Config {
+    /// Runs the Tokio server, which handles every connection in its own task on
+    /// a multi-threaded runtime. Once `shutdown` is set, the next accepted
+    /// connection stops the server: it stops accepting, closes connections
+    /// waiting for a request, and waits for the rest to finish their current one.
+    pub fn run(self, shutdown: Arc<AtomicBool>, metrics: Arc<Metrics>, handler: Arc<dyn Handler>) {
+        let rt = runtime::Builder::new_multi_thread()
+            .worker_threads(self.n_threads)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        rt.block_on(self.serve(shutdown, metrics, handler));
+    }
+
+    async fn serve(
+        self,
+        shutdown: Arc<AtomicBool>,
+        metrics: Arc<Metrics>,
+        handler: Arc<dyn Handler>,
+    ) {
+        let listener = TcpListener::bind(self.addr).await.unwrap();
+        let granted = self.buffers.apply(SockRef::from(&listener)).unwrap();
+        info!("Server listening at {}", self.addr);
+        info!("Socket buffers: {granted}");
+
+        // Wakes up connections waiting for a request once the server shuts down
+        let (closing_tx, closing_rx) = watch::channel(false);
+        let mut conns = JoinSet::new();
+
+        loop {
+            let accepted = listener.accept().await;
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+
+            // Failing to accept one connection (e.g. running out of file descriptors) doesn't
+            // stop the server from accepting the next
+            let stream = match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("failed to accept a connection: {e}");
+                    continue;
+                }
+            };
+
+            // Reap finished connections so the set doesn't grow with every one
+            while conns.try_join_next().is_some() {}
+
+            let closing = closing_rx.clone();
+            let metrics = metrics.clone();
+            let handler = handler.clone();
+            let nagle = self.nagle;
+            conns.spawn(async move {
+                metrics.connection_opened();
+                if let Err(e) = handle_client(stream, closing, nagle, &metrics, &*handler).await
+                    && !framing::is_disconnect(&e)
+                {
+                    error!("{e}");
+                }
+                metrics.connection_closed();
+            });
+        }
+
+        closing_tx.send(true).unwrap();
+        while conns.join_next().await.is_some() {}
+    }
+}
+
+/// Answers requests on a connection until the client closes it or the server
+/// shuts down.
+async fn handle_client(
+    mut stream: TcpStream,
+    mut closing: watch::Receiver<bool>,
+    nagle: bool,
+    metrics: &Metrics,
+    handler: &dyn Handler,
+) -> io::Result<()> {
+    stream.set_nodelay(!nagle)?;
+    let mut buf = Vec::new();
+
+    loop {
+        let request = ::tokio::select! {
+            request = read_request(&mut stream, &mut buf) => request?,
+            _ = closing.wait_for(|closing| *closing) => return Ok(()),
+        };
+
+        let response = do_work(request, handler).await;
+        metrics.request_handled(response.server_process_ns);
+
+        buf.clear();
+        response.serialize(&mut buf)?;
+        stream.write_all(&buf).await?;
+    }
+}
+
+/// Reads a whole request into `buf` and deserializes it.
+async fn read_request(stream: &mut TcpStream, buf: &mut Vec<u8>) -> io::Result<Request> {
+    buf.resize(HEADER_SIZE, 0);
+    stream.read_exact(buf).await?;
+
+    let len = framing::decode_header(buf[..HEADER_SIZE].try_into().unwrap());
+    if len == 0 || len > MAX_REQUEST_BODY {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid request length {len}"),
+        ));
+    }
+
+    buf.resize(HEADER_SIZE + len, 0);
+    stream.read_exact(&mut buf[HEADER_SIZE..]).await?;
+
+    Request::deserialize(&mut Cursor::new(buf))
+}
+
+/// Does the work a request asks for. Sleeps (random ones included) yield to the
+/// runtime instead of blocking its thread, while everything else is handled
+/// inline by `handler`.
+async fn do_work(request: Request, handler: &dyn Handler) -> Response {
+    let Some(duration) = request.work.sleep_duration() else {
+        return handler.handle(&request, 0);
+    };
+
+    let start = Instant::now();
+    ::tokio::time::sleep(duration).await;
+    Response {
+        client_send_time: request.send_time,
+        req_id: request.req_id,
+        queue_depth: 0,
+        server_process_ns: start.elapsed().as_nanos() as u64,
+        payload_len: request.work.payload_len(),
+        checksum: request.response_checksum(),
+        body: request.body,
+        status: Status::Ok,
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::{handler::Handler, metrics::Metrics};
+use log::{info, warn};
+use rust_server_benchmarks::{
+    buffers::BufferSizes,
+    protocol::{Deserialize, Request, Serialize},
+};
+use socket2::SockRef;
+
+/// The largest datagram a request can arrive in.
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// How long a thread waits for a request before checking for shutdown.
+const WAIT_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy)]
+pub struct Config {
+    /// The address to bind to.
+    pub addr: SocketAddr,
+
+    /// The send and receive buffer sizes of the socket.
+    pub buffers: BufferSizes,
+
+    /// The number of threads receiving from the socket.
+    pub n_threads: usize,
+}
+
+impl Config {
+    /// Runs the UDP server. Every request arrives as a single datagram and its
+    /// response is sent back as one to the address it came from. Every thread
+    /// receives from the same socket, exiting once `shutdown` is set.
+    pub fn run(self, shutdown: Arc<AtomicBool>, metrics: Arc<Metrics>, handler: Arc<dyn Handler>) {
+        let socket = UdpSocket::bind(self.addr).unwrap();
+        socket.set_read_timeout(Some(WAIT_TIMEOUT)).unwrap();
+        let granted = self.buffers.apply(SockRef::from(&socket)).unwrap();
+        let socket = Arc::new(socket);
+        info!("Server listening at {} (UDP)", self.addr);
+        info!("Socket buffers: {granted}");
+
+        let handles = (0..self.n_threads)
+            .map(|_| {
+                let socket = socket.clone();
+                let shutdown = shutdown.clone();
+                let metrics = metrics.clone();
+                let handler = handler.clone();
+                std::thread::spawn(move || serve(&socket, &shutdown, &metrics, &*handler))
+            })
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+}
+
+/// Answers requests until `shutdown` is set.
+fn serve(socket: &UdpSocket, shutdown: &AtomicBool, metrics: &Metrics, handler: &dyn Handler) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut out = Vec::new();
+
+    while !shutdown.load(Ordering::SeqCst) {
+        let (n, src) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue;
+            }
+            Err(e) => panic!("failed to receive request: {e}"),
+        };
+
+        let request = match Request::deserialize(&mut &buf[..n]) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("malformed request from {src}: {e}");
+                continue;
+            }
+        };
+
+        out.clear();
+        let response = handler.handle(&request, 0);
+        let service_ns = response.server_process_ns;
+        response.serialize(&mut out).unwrap();
+        metrics.request_handled(service_ns);
+
+        // Responses too large for a datagram are lost, like any other
+        if let Err(e) = socket.send_to(&out, src) {
+            warn!("failed to send response to {src}: {e}");
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufReader, BufWriter, IoSlice, Read, Write},
+    net::Shutdown,
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    handler::{self, Handler, Transport},
+    metrics::Metrics,
+};
+use log::info;
+use rust_server_benchmarks::buffers::BufferSizes;
+use socket2::SockRef;
+
+#[derive(Clone)]
+pub struct Config {
+    /// The path of the socket to bind to.
+    pub path: PathBuf,
+
+    /// The send and receive buffer sizes of accepted connections.
+    pub buffers: BufferSizes,
+
+    /// Connections without any activity for this long are closed.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Config {
+    /// Runs the Unix domain socket server, which serves every connection on a thread of its own,
+    /// so requests skip the TCP/IP stack entirely. A stale socket left at the path by an earlier
+    /// run is replaced, and the socket is removed once the server stops. Once `shutdown` is set,
+    /// the next accepted connection stops the server: it stops accepting and lets connections
+    /// finish their current request.
+    pub fn run(self, shutdown: Arc<AtomicBool>, metrics: Arc<Metrics>, handler: Arc<dyn Handler>) {
+        if let Ok(metadata) = fs::symlink_metadata(&self.path)
+            && metadata.file_type().is_socket()
+        {
+            fs::remove_file(&self.path).unwrap();
+        }
+        let listener = UnixListener::bind(&self.path).unwrap();
+        info!(
+            "Server listening at {} (Unix domain socket)",
+            self.path.display()
+        );
+
+        // The connections being handled, so they can be woken up on shutdown
+        let open = Arc::new(Mutex::new(HashMap::<usize, UnixStream>::new()));
+        let mut handles: Vec<JoinHandle<()>> = Vec::new();
+
+        for (id, stream) in listener.incoming().enumerate() {
+            if shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+
+            // Reap finished connections so the list doesn't grow with every one
+            handles.retain(|handle| !handle.is_finished());
+
+            // Unix domain sockets don't inherit their buffer sizes from the listener
+            let stream = stream.unwrap();
+            stream.set_read_timeout(self.idle_timeout).unwrap();
+            let granted = self.buffers.apply(SockRef::from(&stream)).unwrap();
+            if id == 0 {
+                info!("Socket buffers: {granted}");
+            }
+            open.lock().unwrap().insert(id, stream.try_clone().unwrap());
+
+            let open = open.clone();
+            let metrics = metrics.clone();
+            let handler = handler.clone();
+            handles.push(thread::spawn(move || {
+                metrics.connection_opened();
+                let mut transport = Buffered {
+                    reader: BufReader::new(&stream),
+                    writer: BufWriter::new(&stream),
+                };
+                handler::serve(&mut transport, &*handler, || 0, &metrics);
+                metrics.connection_closed();
+                open.lock().unwrap().remove(&id);
+            }));
+        }
+
+        // Handlers waiting for a request see the end of the stream, while those in the middle of
+        // one still finish it
+        for stream in open.lock().unwrap().values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        fs::remove_file(&self.path).unwrap();
+    }
+}
+
+/// A connection, buffered in both directions so a request is usually a single read and a
+/// response a single write.
+struct Buffered<'a> {
+    reader: BufReader<&'a UnixStream>,
+    writer: BufWriter<&'a UnixStream>,
+}
+
+impl Read for Buffered<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Write for Buffered<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.writer.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Transport for Buffered<'_> {
+    // Requests have no deadline here (--request-deadline is rejected for this server), so there
+    // is nothing to restart
+    fn start_request(&mut self) {}
+}
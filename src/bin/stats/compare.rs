@@ -0,0 +1,174 @@
+//! Compares the statistics of two runs (as `write_stats` writes them), e.g.
+//! before and after a change.
+
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+/// The statistics of a run that are compared.
+pub struct Summary {
+    /// Labeled latency percentiles in microseconds (e.g. `("p99", 52.2)`).
+    pub latencies: Vec<(String, f64)>,
+
+    /// The mean latency in microseconds.
+    pub mean_us: f64,
+
+    /// Requests sent per second.
+    pub offered_rps: f64,
+
+    /// Responses received per second.
+    pub achieved_rps: f64,
+}
+
+impl Summary {
+    /// Reads statistics written in either format. Files that parse as JSON are read as JSON, and
+    /// everything else as text.
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        match serde_json::from_str::<serde_json::Value>(&contents) {
+            Ok(json) => Self::from_json(&json),
+            Err(_) => Self::from_text(&contents),
+        }
+    }
+
+    /// Parses the text format: the percentiles on the first line, the throughputs on the second,
+    /// and the mean latency leading the fourth.
+    fn from_text(contents: &str) -> Result<Self> {
+        let lines = contents.lines().collect::<Vec<_>>();
+        if lines.len() < 4 {
+            return Err(invalid(format!(
+                "expected 4 lines of statistics, got {}",
+                lines.len()
+            )));
+        }
+
+        let latencies = lines[0]
+            .split(", ")
+            .map(|field| match field.split_once(": ") {
+                Some((label, latency)) => Ok((label.to_string(), number(latency)?)),
+                None => Err(invalid(format!(
+                    "expected a labeled percentile, got {field:?}"
+                ))),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let rps = lines[1].split(", ").collect::<Vec<_>>();
+        if rps.len() != 2 {
+            return Err(invalid(format!(
+                "expected the offered and achieved throughput, got {:?}",
+                lines[1]
+            )));
+        }
+
+        let mean_us = number(lines[3].split(", ").next().unwrap())?;
+        Ok(Self {
+            latencies,
+            mean_us,
+            offered_rps: number(rps[0])?,
+            achieved_rps: number(rps[1])?,
+        })
+    }
+
+    /// Parses the JSON format, ignoring the fields that aren't compared.
+    fn from_json(json: &serde_json::Value) -> Result<Self> {
+        let field = |value: &serde_json::Value, name: &str| {
+            value[name]
+                .as_f64()
+                .ok_or_else(|| invalid(format!("missing numeric field {name:?}")))
+        };
+
+        let latencies = json["latencies"]
+            .as_array()
+            .ok_or_else(|| invalid("missing the latency percentiles".to_string()))?
+            .iter()
+            .map(|p| {
+                Ok((
+                    format!("p{}", field(p, "percentile")?),
+                    field(p, "latency_us")?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            latencies,
+            mean_us: field(json, "mean_us")?,
+            offered_rps: field(json, "offered_rps")?,
+            achieved_rps: field(json, "achieved_rps")?,
+        })
+    }
+}
+
+/// A statistic of both runs.
+pub struct Change {
+    pub name: String,
+    pub baseline: f64,
+    pub candidate: f64,
+
+    /// Whether a larger value is better (e.g. throughput), or worse (e.g. latency).
+    pub higher_is_better: bool,
+}
+
+impl Change {
+    /// Gets the change from the baseline in percent, or `None` if the baseline is 0.
+    pub fn percent(&self) -> Option<f64> {
+        (self.baseline != 0.0).then(|| (self.candidate - self.baseline) / self.baseline * 100.0)
+    }
+
+    /// Returns `true` if the candidate is more than `threshold` percent worse than the baseline.
+    pub fn is_regression(&self, threshold: f64) -> bool {
+        match self.percent() {
+            Some(percent) if self.higher_is_better => percent < -threshold,
+            Some(percent) => percent > threshold,
+            None => false,
+        }
+    }
+}
+
+/// Pairs up the latencies and achieved throughput of two runs. Percentiles only reported for one
+/// of the runs are skipped. The offered throughput is an input to the run rather than a result,
+/// so it isn't compared.
+pub fn changes(baseline: &Summary, candidate: &Summary) -> Vec<Change> {
+    let mut changes = baseline
+        .latencies
+        .iter()
+        .filter_map(|(name, latency)| {
+            let (_, other) = candidate
+                .latencies
+                .iter()
+                .find(|(other, _)| other == name)?;
+            Some(Change {
+                name: name.clone(),
+                baseline: *latency,
+                candidate: *other,
+                higher_is_better: false,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    changes.push(Change {
+        name: "mean".to_string(),
+        baseline: baseline.mean_us,
+        candidate: candidate.mean_us,
+        higher_is_better: false,
+    });
+    changes.push(Change {
+        name: "achieved rps".to_string(),
+        baseline: baseline.achieved_rps,
+        candidate: candidate.achieved_rps,
+        higher_is_better: true,
+    });
+    changes
+}
+
+fn number(field: &str) -> Result<f64> {
+    field
+        .trim()
+        .parse()
+        .map_err(|_| invalid(format!("expected a number, got {field:?}")))
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,153 @@
+mod compare;
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::PathBuf,
+    process,
+};
+
+use clap::{CommandFactory, Parser, Subcommand, error::ErrorKind};
+use compare::Summary;
+use rust_server_benchmarks::{
+    histogram::Histogram,
+    protocol::{Deserialize, Serialize},
+};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Merge histograms (histogram.bin) from several clients and print the
+    /// global 50, 95, 99, and 99.9th percentile latencies.
+    MergeHistograms {
+        /// The histogram files to merge.
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// Write the merged histogram to this file.
+        #[arg(short, long)]
+        out: Option<PathBuf>,
+    },
+
+    /// Compare the statistics (stats.txt or stats.json) of a baseline and a
+    /// candidate run, printing the change in each latency percentile, the mean
+    /// latency, and the achieved throughput. It exits with status 1 if any of
+    /// them is worse by more than the threshold, so it can gate CI.
+    Compare {
+        /// The statistics of the baseline run.
+        baseline: PathBuf,
+
+        /// The statistics of the candidate run.
+        candidate: PathBuf,
+
+        /// The largest change for the worse (in percent) that isn't flagged as
+        /// a regression.
+        #[arg(short, long, default_value_t = 5.0)]
+        threshold: f64,
+    },
+}
+
+fn main() {
+    let args = Args::parse();
+
+    match args.command {
+        Command::MergeHistograms { files, out } => {
+            let mut merged = Histogram::new();
+            for path in files {
+                let histogram = File::open(&path)
+                    .and_then(|file| Histogram::deserialize(&mut BufReader::new(file)))
+                    .unwrap_or_else(|e| {
+                        Args::command()
+                            .error(
+                                ErrorKind::Io,
+                                format!("failed to read {}: {e}", path.display()),
+                            )
+                            .exit()
+                    });
+                merged.merge(&histogram);
+            }
+
+            let percentile = |p| merged.percentile(p).unwrap_or(0) as f64 / 1000.0;
+            println!(
+                "{}, {}, {}, {}",
+                percentile(0.5),
+                percentile(0.95),
+                percentile(0.99),
+                percentile(0.999)
+            );
+
+            if let Some(out) = out {
+                let mut writer = BufWriter::new(File::create(out).unwrap());
+                (&merged).serialize(&mut writer).unwrap();
+                writer.flush().unwrap();
+            }
+        }
+        Command::Compare {
+            baseline,
+            candidate,
+            threshold,
+        } => {
+            let read = |path: &PathBuf| {
+                Summary::read(path).unwrap_or_else(|e| {
+                    Args::command()
+                        .error(
+                            ErrorKind::Io,
+                            format!("failed to read {}: {e}", path.display()),
+                        )
+                        .exit()
+                })
+            };
+            let (baseline, candidate) = (read(&baseline), read(&candidate));
+
+            // Latencies at different loads aren't comparable
+            let offered = compare::Change {
+                name: "offered rps".to_string(),
+                baseline: baseline.offered_rps,
+                candidate: candidate.offered_rps,
+                higher_is_better: true,
+            };
+            if offered
+                .percent()
+                .is_some_and(|percent| percent.abs() > threshold)
+            {
+                eprintln!(
+                    "warning: the runs offered different loads ({:.2} and {:.2} requests per \
+                     second)",
+                    offered.baseline, offered.candidate
+                );
+            }
+
+            let mut regressed = false;
+            println!(
+                "{:<14}{:>14}{:>14}{:>10}",
+                "", "baseline", "candidate", "change"
+            );
+            for change in compare::changes(&baseline, &candidate) {
+                let percent = match change.percent() {
+                    Some(percent) => format!("{percent:+.2}%"),
+                    None => "-".to_string(),
+                };
+                let flag = if change.is_regression(threshold) {
+                    regressed = true;
+                    "  regression"
+                } else {
+                    ""
+                };
+                println!(
+                    "{:<14}{:>14.3}{:>14.3}{:>10}{flag}",
+                    change.name, change.baseline, change.candidate, percent
+                );
+            }
+
+            if regressed {
+                process::exit(1);
+            }
+        }
+    }
+}
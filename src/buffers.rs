@@ -0,0 +1,64 @@
+use std::{fmt, io::Result};
+
+use socket2::SockRef;
+
+/// Requested sizes of a socket's send and receive buffers (`SO_SNDBUF` and
+/// `SO_RCVBUF`), leaving the OS default for either if `None`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BufferSizes {
+    pub send: Option<usize>,
+    pub recv: Option<usize>,
+}
+
+impl BufferSizes {
+    /// Returns `true` if neither size was requested.
+    pub fn is_default(&self) -> bool {
+        self.send.is_none() && self.recv.is_none()
+    }
+
+    /// Sets the requested sizes on `socket`, returning the sizes the kernel
+    /// granted. They may differ from the requested ones, e.g. Linux doubles
+    /// them to leave room for bookkeeping and caps them at `net.core.wmem_max`
+    /// and `net.core.rmem_max`.
+    pub fn apply(self, socket: SockRef) -> Result<Granted> {
+        if let Some(size) = self.send {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv {
+            socket.set_recv_buffer_size(size)?;
+        }
+
+        Ok(Granted {
+            requested: self,
+            send: socket.send_buffer_size()?,
+            recv: socket.recv_buffer_size()?,
+        })
+    }
+}
+
+/// The buffer sizes the kernel granted a socket.
+#[derive(Clone, Copy, Debug)]
+pub struct Granted {
+    pub requested: BufferSizes,
+    pub send: usize,
+    pub recv: usize,
+}
+
+/// Writes the granted sizes, along with the requested ones, e.g. "send buffer
+/// 8192 bytes (requested 4096), receive buffer 131072 bytes (default)".
+impl fmt::Display for Granted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let requested = |size: Option<usize>| match size {
+            Some(size) => format!("requested {size}"),
+            None => "default".to_string(),
+        };
+        write!(
+            f,
+            "send buffer {} bytes ({}), receive buffer {} bytes ({})",
+            self.send,
+            requested(self.requested.send),
+            self.recv,
+            requested(self.requested.recv)
+        )
+    }
+}
@@ -0,0 +1,83 @@
+//! The files `Work::FileRead` reads from. Servers create them once at startup,
+//! so requests only pay for opening and reading a file, never for writing it.
+
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+/// The directory the files were created in and their number.
+static FILES: OnceLock<(PathBuf, u64)> = OnceLock::new();
+
+/// The size of the buffer files are written and read with.
+const CHUNK_SIZE: usize = 8192;
+
+/// Creates `count` files of `size` bytes each in `dir`, named by their path id
+/// (`0` to `count - 1`). It can only be called once per process.
+pub fn create(dir: &Path, count: u64, size: u64) -> Result<()> {
+    fs::create_dir_all(dir)?;
+
+    let chunk = [0xa5u8; CHUNK_SIZE];
+    for id in 0..count {
+        let mut file = BufWriter::new(File::create(dir.join(id.to_string()))?);
+        let mut remaining = size;
+        while remaining > 0 {
+            let n = remaining.min(CHUNK_SIZE as u64) as usize;
+            file.write_all(&chunk[..n])?;
+            remaining -= n as u64;
+        }
+        file.into_inner()?.sync_all()?;
+    }
+
+    FILES
+        .set((dir.to_path_buf(), count))
+        .map_err(|_| Error::other("the files were already created"))
+}
+
+/// Opens the file with id `path_id` and reads `bytes` bytes from it, starting
+/// over from the beginning whenever it runs out.
+pub fn read(path_id: u64, bytes: u64) -> Result<()> {
+    let Some((dir, count)) = FILES.get() else {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            "the server wasn't started with any files to read",
+        ));
+    };
+    if path_id >= *count {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("there is no file {path_id}, the server has {count}"),
+        ));
+    }
+
+    let mut file = File::open(dir.join(path_id.to_string()))?;
+    let mut buf = [0u8; CHUNK_SIZE];
+    let mut remaining = bytes;
+    while remaining > 0 {
+        let n = remaining.min(CHUNK_SIZE as u64) as usize;
+        match file.read(&mut buf[..n])? {
+            0 if file.stream_position()? == 0 => {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("file {path_id} is empty"),
+                ));
+            }
+            0 => {
+                file.seek(SeekFrom::Start(0))?;
+            }
+            read => remaining -= read as u64,
+        }
+    }
+
+    Ok(())
+}
+
+/// Deletes the files and the directory they were created in.
+pub fn remove() -> Result<()> {
+    match FILES.get() {
+        Some((dir, _)) => fs::remove_dir_all(dir),
+        None => Ok(()),
+    }
+}
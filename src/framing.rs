@@ -0,0 +1,286 @@
+use std::io::{Error, ErrorKind, IoSlice, Read, Result, Write};
+
+/// The size of the length that prefixes every message.
+pub const HEADER_SIZE: usize = 4;
+
+/// The order multi-byte integers are sent in.
+#[derive(Clone, Copy)]
+enum ByteOrder {
+    Big,
+    Little,
+}
+
+/// The wire byte order. Messages are big-endian, or little-endian with the
+/// `little-endian` feature, which skips the byte swap on little-endian hosts.
+/// Clients and servers must be built with the same order.
+const WIRE_ORDER: ByteOrder = if cfg!(feature = "little-endian") {
+    ByteOrder::Little
+} else {
+    ByteOrder::Big
+};
+
+/// Encodes a `u32` in the wire byte order.
+pub fn encode_u32(value: u32) -> [u8; 4] {
+    encode_u32_in(WIRE_ORDER, value)
+}
+
+/// Decodes a `u32` from the wire byte order.
+pub fn decode_u32(bytes: [u8; 4]) -> u32 {
+    decode_u32_in(WIRE_ORDER, bytes)
+}
+
+/// Encodes a `u64` in the wire byte order.
+pub fn encode_u64(value: u64) -> [u8; 8] {
+    encode_u64_in(WIRE_ORDER, value)
+}
+
+/// Decodes a `u64` from the wire byte order.
+pub fn decode_u64(bytes: [u8; 8]) -> u64 {
+    decode_u64_in(WIRE_ORDER, bytes)
+}
+
+fn encode_u32_in(order: ByteOrder, value: u32) -> [u8; 4] {
+    match order {
+        ByteOrder::Big => value.to_be_bytes(),
+        ByteOrder::Little => value.to_le_bytes(),
+    }
+}
+
+fn decode_u32_in(order: ByteOrder, bytes: [u8; 4]) -> u32 {
+    match order {
+        ByteOrder::Big => u32::from_be_bytes(bytes),
+        ByteOrder::Little => u32::from_le_bytes(bytes),
+    }
+}
+
+fn encode_u64_in(order: ByteOrder, value: u64) -> [u8; 8] {
+    match order {
+        ByteOrder::Big => value.to_be_bytes(),
+        ByteOrder::Little => value.to_le_bytes(),
+    }
+}
+
+fn decode_u64_in(order: ByteOrder, bytes: [u8; 8]) -> u64 {
+    match order {
+        ByteOrder::Big => u64::from_be_bytes(bytes),
+        ByteOrder::Little => u64::from_le_bytes(bytes),
+    }
+}
+
+/// Reads a `u32` in the wire byte order.
+pub fn read_u32<T: Read>(bytes: &mut T) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    bytes.read_exact(&mut buf)?;
+    Ok(decode_u32(buf))
+}
+
+/// Reads a `u64` in the wire byte order.
+pub fn read_u64<T: Read>(bytes: &mut T) -> Result<u64> {
+    let mut buf = [0u8; 8];
+    bytes.read_exact(&mut buf)?;
+    Ok(decode_u64(buf))
+}
+
+/// Fills `buf` like `read_exact`, but tells a peer that closed the connection
+/// between messages (`UnexpectedEof`) apart from one that stopped in the
+/// middle of a message (`InvalidData`), which is a protocol error.
+pub fn read_message<T: Read>(bytes: &mut T, buf: &mut [u8]) -> Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match bytes.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "the connection was closed",
+                ));
+            }
+            Ok(0) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "the connection was closed {filled} bytes into a {}-byte message",
+                        buf.len()
+                    ),
+                ));
+            }
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns `true` if `e` means the peer closed the connection, whether
+/// cleanly between messages or by resetting it in the middle of one. Servers
+/// treat these as ordinary disconnects rather than errors.
+pub fn is_disconnect(e: &Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+    )
+}
+
+/// The CRC-32 (IEEE) lookup table, one entry per byte value.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Computes the CRC-32 (IEEE) of `bytes`.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &b| {
+        CRC32_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Writes all of `bufs`, with as few vectored writes as the writer allows (one
+/// `writev` on a socket, unless it only accepts part of them). This is
+/// `Write::write_all_vectored`, which isn't stable yet.
+pub fn write_all_vectored<T: Write>(bytes: &mut T, mut bufs: &mut [IoSlice]) -> Result<()> {
+    // Skip leading empty buffers, so an empty write means the writer is stuck
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match bytes.write_vectored(bufs) {
+            Ok(0) => {
+                return Err(Error::new(
+                    ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ));
+            }
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the prefix of a message whose body is `len` bytes long.
+pub fn write_header<T: Write>(bytes: &mut T, len: usize) -> Result<()> {
+    let len = u32::try_from(len).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("a {len}-byte message is too large to frame"),
+        )
+    })?;
+    bytes.write_all(&encode_u32(len))
+}
+
+/// Decodes the body length from a message prefix.
+pub fn decode_header(header: [u8; HEADER_SIZE]) -> usize {
+    decode_u32(header) as usize
+}
+
+/// Reads a message prefix and then its whole body, which may be at most `max`
+/// bytes long, with a single call before parsing it with `f`. Bodies of at
+/// most `N` bytes are read into a stack buffer, and longer ones into a heap
+/// buffer.
+pub fn read_small_frame<T: Read, U, const N: usize>(
+    bytes: &mut T,
+    max: usize,
+    f: impl FnOnce(&mut &[u8]) -> Result<U>,
+) -> Result<U> {
+    let mut header = [0u8; HEADER_SIZE];
+    bytes.read_exact(&mut header)?;
+
+    let len = decode_header(header);
+    let limit = N.max(max);
+    if len > limit {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("a {len}-byte message is longer than the {limit}-byte limit"),
+        ));
+    }
+
+    let mut stack = [0u8; N];
+    let mut heap = Vec::new();
+    let buf = if len <= N {
+        &mut stack[..len]
+    } else {
+        heap.resize(len, 0);
+        &mut heap[..]
+    };
+    bytes.read_exact(buf)?;
+
+    let mut body = &buf[..];
+    let message = f(&mut body)?;
+    if !body.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("a {len}-byte message has {} unread bytes left", body.len()),
+        ));
+    }
+
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for value in [0, 1, 0x0102_0304, u32::MAX] {
+            assert_eq!(decode_u32(encode_u32(value)), value);
+        }
+        for value in [0, 1, 0x0102_0304_0506_0708, u64::MAX] {
+            assert_eq!(decode_u64(encode_u64(value)), value);
+            assert_eq!(read_u64(&mut &encode_u64(value)[..]).unwrap(), value);
+        }
+
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, 5).unwrap();
+        bytes.extend_from_slice(b"hello");
+        let body = read_small_frame::<_, _, 8>(&mut &bytes[..], 8, |body| {
+            let mut buf = [0; 5];
+            body.read_exact(&mut buf)?;
+            Ok(buf)
+        });
+        assert_eq!(&body.unwrap(), b"hello");
+    }
+
+    /// Both orders are tested whichever one the build sends, so neither goes untested.
+    #[test]
+    fn byte_orders() {
+        for order in [ByteOrder::Big, ByteOrder::Little] {
+            for value in [0, 1, 0x0102_0304, u32::MAX] {
+                assert_eq!(decode_u32_in(order, encode_u32_in(order, value)), value);
+            }
+            for value in [0, 1, 0x0102_0304_0506_0708, u64::MAX] {
+                assert_eq!(decode_u64_in(order, encode_u64_in(order, value)), value);
+            }
+        }
+
+        assert_eq!(encode_u32_in(ByteOrder::Big, 0x0102_0304), [1, 2, 3, 4]);
+        assert_eq!(
+            encode_u64_in(ByteOrder::Big, 0x0102_0304_0506_0708),
+            [1, 2, 3, 4, 5, 6, 7, 8]
+        );
+        assert_eq!(encode_u32_in(ByteOrder::Little, 0x0102_0304), [4, 3, 2, 1]);
+        assert_eq!(
+            encode_u64_in(ByteOrder::Little, 0x0102_0304_0506_0708),
+            [8, 7, 6, 5, 4, 3, 2, 1]
+        );
+    }
+}
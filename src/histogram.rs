@@ -0,0 +1,251 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use crate::protocol::{Deserialize, Serialize};
+
+/// Number of sub-buckets per power of two. Values are recorded with a relative
+/// error of at most `1 / SUB_BUCKETS`.
+const SUB_BUCKETS: u64 = 64;
+
+/// Total number of buckets needed to cover every `u64`.
+const NUM_BUCKETS: usize = (2 * SUB_BUCKETS + 57 * SUB_BUCKETS) as usize;
+
+/// A fixed-size log-linear histogram of `u64` values (e.g. latencies in
+/// nanoseconds). Memory usage is constant regardless of how many values are
+/// recorded.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Histogram {
+    /// Creates an empty histogram.
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; NUM_BUCKETS],
+            total: 0,
+        }
+    }
+
+    /// Records a value.
+    pub fn record(&mut self, value: u64) {
+        self.counts[Self::index(value)] += 1;
+        self.total += 1;
+    }
+
+    /// Returns the number of recorded values.
+    pub fn len(&self) -> u64 {
+        self.total
+    }
+
+    /// Returns `true` if no values have been recorded.
+    pub fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+
+    /// Adds the counts of another histogram to this one.
+    pub fn merge(&mut self, other: &Histogram) {
+        for (count, other_count) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other_count;
+        }
+        self.total += other.total;
+    }
+
+    /// Gets the (approximate) `p`th percentile (`0.0..=1.0`), or `None` if the
+    /// histogram is empty.
+    pub fn percentile(&self, p: f64) -> Option<u64> {
+        if self.total == 0 {
+            return None;
+        }
+
+        // The rank of the value, computed the same way as for a sorted vector
+        let rank = ((self.total as f64 * p) as u64).min(self.total - 1);
+
+        let mut seen = 0;
+        for (idx, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen > rank {
+                return Some(Self::value(idx));
+            }
+        }
+
+        unreachable!("rank is less than the total count")
+    }
+
+    /// Gets the (approximate) mean and population standard deviation, or
+    /// `None` if the histogram is empty. Every value counts as its bucket's
+    /// midpoint.
+    pub fn mean_stddev(&self) -> Option<(f64, f64)> {
+        if self.total == 0 {
+            return None;
+        }
+
+        let values = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(idx, &count)| (Self::value(idx) as f64, count as f64));
+
+        let total = self.total as f64;
+        let mean = values
+            .clone()
+            .map(|(value, count)| value * count)
+            .sum::<f64>()
+            / total;
+        let variance = values
+            .map(|(value, count)| (value - mean).powi(2) * count)
+            .sum::<f64>()
+            / total;
+
+        Some((mean, variance.sqrt()))
+    }
+
+    /// Maps a value to its bucket.
+    fn index(value: u64) -> usize {
+        if value < 2 * SUB_BUCKETS {
+            return value as usize;
+        }
+
+        // Shift the value so that it falls in [SUB_BUCKETS, 2 * SUB_BUCKETS)
+        let exp = 63 - value.leading_zeros() as u64;
+        let shift = exp - SUB_BUCKETS.trailing_zeros() as u64;
+        let sub = (value >> shift) - SUB_BUCKETS;
+
+        (2 * SUB_BUCKETS + (shift - 1) * SUB_BUCKETS + sub) as usize
+    }
+
+    /// Maps a bucket to the midpoint of the values it covers.
+    fn value(idx: usize) -> u64 {
+        let idx = idx as u64;
+        if idx < 2 * SUB_BUCKETS {
+            return idx;
+        }
+
+        let k = idx - 2 * SUB_BUCKETS;
+        let shift = k / SUB_BUCKETS + 1;
+        let low = (k % SUB_BUCKETS + SUB_BUCKETS) << shift;
+
+        low + (1 << shift) / 2
+    }
+}
+
+/// Histograms are serialized sparsely: the number of non-empty buckets
+/// followed by an `(index, count)` pair for each of them.
+impl<T: Write> Serialize<T> for &Histogram {
+    fn serialize(self, bytes: &mut T) -> Result<()> {
+        let buckets: Vec<_> = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .collect();
+
+        bytes.write_all(&(buckets.len() as u32).to_be_bytes())?;
+        for (idx, count) in buckets {
+            bytes.write_all(&(idx as u32).to_be_bytes())?;
+            bytes.write_all(&count.to_be_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: Read> Deserialize<T> for Histogram {
+    fn deserialize(bytes: &mut T) -> Result<Self> {
+        let mut u32_bytes = [0u8; 4];
+        let mut u64_bytes = [0u8; 8];
+
+        bytes.read_exact(&mut u32_bytes)?;
+        let num_buckets = u32::from_be_bytes(u32_bytes);
+
+        let mut hist = Histogram::new();
+        for _ in 0..num_buckets {
+            bytes.read_exact(&mut u32_bytes)?;
+            let idx = u32::from_be_bytes(u32_bytes) as usize;
+
+            bytes.read_exact(&mut u64_bytes)?;
+            let count = u64::from_be_bytes(u64_bytes);
+
+            if idx >= NUM_BUCKETS {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("failed to deserialize histogram: {idx} is an invalid bucket"),
+                ));
+            }
+
+            let (Some(bucket), Some(total)) = (
+                hist.counts[idx].checked_add(count),
+                hist.total.checked_add(count),
+            ) else {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "failed to deserialize histogram: the counts overflow",
+                ));
+            };
+            hist.counts[idx] = bucket;
+            hist.total = total;
+        }
+
+        Ok(hist)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Round-trips a histogram through its serialized form, as `histogram.bin` files are.
+    fn round_trip(hist: &Histogram) -> Histogram {
+        let mut bytes = Vec::new();
+        hist.serialize(&mut bytes).unwrap();
+        Histogram::deserialize(&mut &bytes[..]).unwrap()
+    }
+
+    #[test]
+    fn merge_serialized() {
+        // One client saw 100 us, the other mostly 200 us with a 5 ms tail in its 99th percentile
+        let mut first = Histogram::new();
+        let mut second = Histogram::new();
+        for _ in 0..1000 {
+            first.record(100_000);
+        }
+        for i in 0..1000 {
+            second.record(if i < 990 { 200_000 } else { 5_000_000 });
+        }
+        assert_eq!(
+            second.percentile(0.99),
+            Some(Histogram::value(Histogram::index(5_000_000)))
+        );
+
+        let mut merged = round_trip(&first);
+        merged.merge(&round_trip(&second));
+
+        // Over both clients the tail is under 1%, so the global p99 is 200 us
+        assert_eq!(merged.len(), 2000);
+        let p_99 = merged.percentile(0.99).unwrap();
+        assert!(
+            p_99.abs_diff(200_000) <= 200_000 / SUB_BUCKETS,
+            "p99 is {p_99}"
+        );
+    }
+
+    #[test]
+    fn overflowing_counts_are_invalid() {
+        // Two entries for the same bucket whose counts sum past u64::MAX
+        let mut bytes = 2u32.to_be_bytes().to_vec();
+        for _ in 0..2 {
+            bytes.extend(0u32.to_be_bytes());
+            bytes.extend(u64::MAX.to_be_bytes());
+        }
+
+        let err = Histogram::deserialize(&mut &bytes[..]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
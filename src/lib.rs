@@ -1,49 +1,782 @@
+pub mod buffers;
+pub mod files;
+pub mod framing;
+pub mod histogram;
+pub mod mix;
 pub mod protocol;
+pub mod proxy;
+pub mod sink;
+pub mod tls;
 
 use std::{
-    fs::{self, File},
-    io::{Result, Write},
+    collections::BTreeMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{Error, ErrorKind, Result, Write},
     path::PathBuf,
-    time::{SystemTime, UNIX_EPOCH},
+    time::Duration,
 };
 
-use crate::protocol::LatencyRecord;
+use nix::time::{ClockId, clock_gettime};
 
-/// Gets the current time (in nanoseconds) since the UNIX epoch.
+use clap::ValueEnum;
+
+use crate::{histogram::Histogram, mix::Mix, protocol::LatencyRecord};
+
+/// Gets the current time (in nanoseconds) on the monotonic clock
+/// (`CLOCK_MONOTONIC`), which NTP can't step backward, so a latency is never
+/// negative.
+///
+/// Requests carry their send time to the server and back, but the server only
+/// echoes it, so every time is read on the client's host. The clock's epoch is
+/// shared by all processes on a host but not across hosts, and it isn't wall
+/// clock time: recorded times (e.g. in raw.csv) count from an unspecified
+/// point, typically boot.
 pub fn get_time() -> u64 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_nanos() as u64
+    let now = clock_gettime(ClockId::CLOCK_MONOTONIC).unwrap();
+    now.tv_sec() as u64 * 1_000_000_000 + now.tv_nsec() as u64
+}
+
+/// Measures the median cost (in nanoseconds) of a `get_time` call over
+/// `iterations` back-to-back pairs of calls. Every measured latency includes
+/// roughly one such call, between reading the clock and it returning.
+pub fn clock_overhead(iterations: usize) -> u64 {
+    let mut costs: Vec<u64> = (0..iterations)
+        .map(|_| {
+            let first = get_time();
+            get_time().saturating_sub(first)
+        })
+        .collect();
+    costs.sort_unstable();
+    costs.get(costs.len() / 2).copied().unwrap_or(0)
+}
+
+/// The format statistics are written in.
+#[derive(Clone, Copy, Debug, ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Comma-separated, labeled percentiles on one line (e.g. `p99: 52.2`),
+    /// throughputs on the next, the numbers of timeouts, dropped responses,
+    /// unacknowledged requests, corrupted requests, requests failed by
+    /// connection errors, and requests turned away by a busy server on the
+    /// third, and the mean, minimum, maximum, and standard deviation of
+    /// the latencies on the last.
+    Text,
+
+    /// A JSON object.
+    Json,
+}
+
+impl Format {
+    /// Gets the file extension of this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Text => "txt",
+            Format::Json => "json",
+        }
+    }
+}
+
+/// How summary statistics are written.
+#[derive(Clone, Debug)]
+pub struct StatsOptions {
+    /// The latency percentiles to report, in percent (e.g. 99.9).
+    pub percentiles: Vec<f64>,
+
+    /// The output format.
+    pub format: Format,
+
+    /// Also append a summary row to a CSV file.
+    pub append: Option<AppendOptions>,
+}
+
+/// Where runs of a sweep append their summary rows.
+#[derive(Clone, Debug)]
+pub struct AppendOptions {
+    /// The CSV file. The header is written when the file is empty.
+    pub path: PathBuf,
+
+    /// The leading columns that describe the run (e.g. the delay), as names and values.
+    pub params: Vec<(String, String)>,
+}
+
+/// Requests that didn't get a response, or were turned away.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Failures {
+    /// Number of requests that timed out waiting on the server.
+    pub timeouts: usize,
+
+    /// Number of responses lost in transit (UDP only).
+    pub dropped: usize,
+
+    /// Number of requests still waiting for a response when a client gave up
+    /// on the server (open loop only).
+    pub unacknowledged: usize,
+
+    /// Number of requests the server received corrupted, as told by their
+    /// checksums (only verified with --verify-checksums).
+    pub corrupted: usize,
+
+    /// Number of requests that failed because the connection was reset or
+    /// closed under them.
+    pub errors: usize,
+
+    /// Number of requests the server turned away without doing their work
+    /// because it was busy.
+    pub busy: usize,
+}
+
+/// A latency percentile.
+#[derive(Debug, serde::Serialize)]
+pub struct Percentile {
+    /// The percentile, in percent (e.g. 99.9).
+    pub percentile: f64,
+
+    /// The latency in microseconds.
+    pub latency_us: f64,
+}
+
+/// Summary statistics of a run.
+#[derive(Debug, serde::Serialize)]
+pub struct Stats {
+    /// The requested latency percentiles.
+    pub latencies: Vec<Percentile>,
+
+    /// The mean latency in microseconds.
+    pub mean_us: f64,
+
+    /// The minimum latency in microseconds.
+    pub min_us: f64,
+
+    /// The maximum latency in microseconds.
+    pub max_us: f64,
+
+    /// The (population) standard deviation of the latencies in microseconds.
+    pub stddev_us: f64,
+
+    /// Requests sent per second.
+    pub offered_rps: f64,
+
+    /// Responses received per second.
+    pub achieved_rps: f64,
+
+    /// Number of requests sent.
+    pub num_requests: usize,
+
+    /// Number of requests that timed out waiting on the server.
+    pub timeouts: usize,
+
+    /// Number of responses lost in transit (UDP only).
+    pub dropped: usize,
+
+    /// Number of requests still waiting for a response when a client gave up on the server
+    /// (open loop only).
+    pub unacknowledged: usize,
+
+    /// Number of requests the server received corrupted, as told by their checksums.
+    pub corrupted: usize,
+
+    /// Number of requests that failed because the connection was reset or closed under them.
+    pub errors: usize,
+
+    /// Number of requests the server turned away because it was busy.
+    pub busy: usize,
+
+    /// Total runtime in seconds.
+    pub runtime_secs: f64,
+
+    /// The work the server did for each request.
+    pub workload: Mix,
+}
+
+impl Stats {
+    /// Writes the statistics to `path` in the given format.
+    pub fn write(&self, path: &PathBuf, format: Format) -> Result<()> {
+        fs::create_dir_all(path.parent().expect("file path is missing directory"))?;
+        let mut file = File::create(path)?;
+
+        match format {
+            Format::Text => {
+                let latencies = self
+                    .latencies
+                    .iter()
+                    .map(|p| format!("p{}: {}", p.percentile, p.latency_us))
+                    .collect::<Vec<_>>();
+                writeln!(file, "{}", latencies.join(", "))?;
+                writeln!(file, "{:.2}, {:.2}", self.offered_rps, self.achieved_rps)?;
+                writeln!(
+                    file,
+                    "{}, {}, {}, {}, {}, {}",
+                    self.timeouts,
+                    self.dropped,
+                    self.unacknowledged,
+                    self.corrupted,
+                    self.errors,
+                    self.busy
+                )?;
+                writeln!(
+                    file,
+                    "{:.3}, {}, {}, {:.3}",
+                    self.mean_us, self.min_us, self.max_us, self.stddev_us
+                )?;
+            }
+            Format::Json => {
+                serde_json::to_writer_pretty(&mut file, self)?;
+                writeln!(file)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Appends the statistics as a CSV row led by the run's parameters, the workload, and the
+    /// sink that computed them. The file is locked while writing, so several clients can share
+    /// it without interleaving rows.
+    pub fn append(&self, append: &AppendOptions, sink: &str) -> Result<()> {
+        if let Some(dir) = append.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&append.path)?;
+        file.lock()?;
+
+        let mut out = String::new();
+        if file.metadata()?.len() == 0 {
+            let mut header: Vec<_> = append.params.iter().map(|(name, _)| name.clone()).collect();
+            header.extend(["workload".to_string(), "sink".to_string()]);
+            header.extend(self.latencies.iter().map(|p| format!("p{}", p.percentile)));
+            header.extend(
+                [
+                    "mean_us",
+                    "min_us",
+                    "max_us",
+                    "stddev_us",
+                    "offered_rps",
+                    "achieved_rps",
+                    "num_requests",
+                    "timeouts",
+                    "dropped",
+                    "unacknowledged",
+                    "corrupted",
+                    "errors",
+                    "busy",
+                    "runtime_secs",
+                ]
+                .map(String::from),
+            );
+            out += &header.join(",");
+            out += "\n";
+        }
+
+        let mut row: Vec<_> = append
+            .params
+            .iter()
+            .map(|(_, value)| csv_field(value))
+            .collect();
+        row.extend([csv_field(&self.workload.to_string()), sink.to_string()]);
+        row.extend(self.latencies.iter().map(|p| p.latency_us.to_string()));
+        row.extend([
+            self.mean_us.to_string(),
+            self.min_us.to_string(),
+            self.max_us.to_string(),
+            self.stddev_us.to_string(),
+            format!("{:.2}", self.offered_rps),
+            format!("{:.2}", self.achieved_rps),
+            self.num_requests.to_string(),
+            self.timeouts.to_string(),
+            self.dropped.to_string(),
+            self.unacknowledged.to_string(),
+            self.corrupted.to_string(),
+            self.errors.to_string(),
+            self.busy.to_string(),
+            self.runtime_secs.to_string(),
+        ]);
+        out += &row.join(",");
+        out += "\n";
+
+        // The lock is released when the file is closed
+        file.write_all(out.as_bytes())
+    }
+}
+
+/// Quotes a CSV field if it contains a separator or quote.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
 }
 
-/// Saves performance statistics.
+/// Saves performance statistics. It fails if there are no latency records.
 ///
 /// # Arguments
 ///
 /// * `lrs` - The latency records.
 /// * `n` - Number of requests sent (this should match `lrs.len()` for a closed
-///    loop request generator).
-/// * `runtime` - Total runtime in microseconds.
+///   loop request generator).
+/// * `discarded` - Older records dropped to keep only the most recent ones,
+///   which still count towards the achieved throughput.
+/// * `failures` - Requests that didn't get a response.
+/// * `runtime` - Total runtime.
+/// * `work` - The work the server did for each request.
+/// * `options` - The percentiles to report, the output format, and where to
+///   append a summary row.
 /// * `path` - The destination file path.
-pub fn write_stats(lrs: Vec<LatencyRecord>, n: usize, runtime: u64, path: &PathBuf) -> Result<()> {
-    // Calculate the 50, 95, and 99th percentile latencies
+#[allow(clippy::too_many_arguments)]
+pub fn write_stats(
+    lrs: Vec<LatencyRecord>,
+    n: usize,
+    discarded: usize,
+    failures: Failures,
+    runtime: Duration,
+    work: Mix,
+    options: &StatsOptions,
+    path: &PathBuf,
+) -> Result<()> {
+    if lrs.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "no latency records to compute statistics from",
+        ));
+    }
+
+    // Calculate the requested percentile latencies
     let mut latencies: Vec<_> = lrs.iter().map(|lr| lr.recv_time - lr.send_time).collect();
 
     latencies.sort();
-    let p_50 = latencies[latencies.len() / 2] as f64 / 1000.0;
-    let p_95 = latencies[(latencies.len() as f64 * 0.95 as f64) as usize] as f64 / 1000.0;
-    let p_99 = latencies[(latencies.len() as f64 * 0.99 as f64) as usize] as f64 / 1000.0;
+    let (mean, stddev) = mean_stddev(&latencies);
+    let stats = Stats {
+        latencies: percentiles(&options.percentiles, |p| percentile(&latencies, p)),
+        mean_us: mean / 1000.0,
+        min_us: latencies[0] as f64 / 1000.0,
+        max_us: latencies[latencies.len() - 1] as f64 / 1000.0,
+        stddev_us: stddev / 1000.0,
+
+        // Calculate the offered and achieved throughput (requests per second)
+        offered_rps: n as f64 / runtime.as_secs_f64(),
+        achieved_rps: (latencies.len() + discarded) as f64 / runtime.as_secs_f64(),
+
+        num_requests: n,
+        timeouts: failures.timeouts,
+        dropped: failures.dropped,
+        unacknowledged: failures.unacknowledged,
+        corrupted: failures.corrupted,
+        errors: failures.errors,
+        busy: failures.busy,
+        runtime_secs: runtime.as_secs_f64(),
+        workload: work,
+    };
+
+    stats.write(path, options.format)?;
+    match &options.append {
+        Some(append) => stats.append(append, "exact"),
+        None => Ok(()),
+    }
+}
+
+/// Saves performance statistics from a latency histogram, in the same format
+/// as `write_stats`.
+///
+/// # Arguments
+///
+/// * `hist` - The latency histogram (in nanoseconds).
+/// * `n` - Number of requests sent.
+/// * `failures` - Requests that didn't get a response.
+/// * `runtime` - Total runtime.
+/// * `work` - The work the server did for each request.
+/// * `options` - The percentiles to report, the output format, and where to
+///   append a summary row.
+/// * `path` - The destination file path.
+pub fn write_histogram_stats(
+    hist: &Histogram,
+    n: usize,
+    failures: Failures,
+    runtime: Duration,
+    work: Mix,
+    options: &StatsOptions,
+    path: &PathBuf,
+) -> Result<()> {
+    let (mean, stddev) = hist.mean_stddev().unwrap_or((0.0, 0.0));
+    let stats = Stats {
+        latencies: percentiles(&options.percentiles, |p| hist.percentile(p).unwrap_or(0)),
+        mean_us: mean / 1000.0,
+        min_us: hist.percentile(0.0).unwrap_or(0) as f64 / 1000.0,
+        max_us: hist.percentile(1.0).unwrap_or(0) as f64 / 1000.0,
+        stddev_us: stddev / 1000.0,
+        offered_rps: n as f64 / runtime.as_secs_f64(),
+        achieved_rps: hist.len() as f64 / runtime.as_secs_f64(),
+        num_requests: n,
+        timeouts: failures.timeouts,
+        dropped: failures.dropped,
+        unacknowledged: failures.unacknowledged,
+        corrupted: failures.corrupted,
+        errors: failures.errors,
+        busy: failures.busy,
+        runtime_secs: runtime.as_secs_f64(),
+        workload: work,
+    };
+
+    stats.write(path, options.format)?;
+    match &options.append {
+        Some(append) => stats.append(append, "histogram"),
+        None => Ok(()),
+    }
+}
+
+/// Computes each of `percentiles` (in percent) with `f`, which takes a
+/// fraction and returns nanoseconds.
+fn percentiles(percentiles: &[f64], f: impl Fn(f64) -> u64) -> Vec<Percentile> {
+    percentiles
+        .iter()
+        .map(|&p| Percentile {
+            percentile: p,
+            latency_us: f(p / 100.0) as f64 / 1000.0,
+        })
+        .collect()
+}
 
-    // Calculate the attempted, offered, and achieved throughput
-    let offered = n as u64 / runtime;
-    let achieved = latencies.len() as u64 / runtime;
+/// Gets the mean and population standard deviation of a non-empty slice in a
+/// single pass (Welford's algorithm).
+fn mean_stddev(values: &[u64]) -> (f64, f64) {
+    let mut mean = 0.0;
+    let mut m2 = 0.0;
+    for (i, &value) in values.iter().enumerate() {
+        let value = value as f64;
+        let delta = value - mean;
+        mean += delta / (i + 1) as f64;
+        m2 += delta * (value - mean);
+    }
+
+    (mean, (m2 / values.len() as f64).sqrt())
+}
+
+/// Saves throughput statistics, for runs that don't record latencies.
+///
+/// # Arguments
+///
+/// * `n` - Number of requests sent.
+/// * `completed` - Number of responses received.
+/// * `runtime` - Total runtime.
+/// * `path` - The destination file path.
+pub fn write_throughput(
+    n: usize,
+    completed: usize,
+    runtime: Duration,
+    path: &PathBuf,
+) -> Result<()> {
+    let offered = n as f64 / runtime.as_secs_f64();
+    let achieved = completed as f64 / runtime.as_secs_f64();
+
+    fs::create_dir_all(path.parent().expect("file path is missing directory"))?;
+    let mut file = File::create(path)?;
+
+    writeln!(file, "{offered:.2}, {achieved:.2}")?;
+
+    Ok(())
+}
+
+/// The results of one trial of a multi-trial run.
+#[derive(Clone, Debug)]
+pub struct Trial {
+    /// The requested latency percentiles, in microseconds.
+    pub latencies_us: Vec<f64>,
+
+    /// Responses received per second.
+    pub achieved_rps: f64,
+}
+
+/// The mean of a statistic across trials and the margin of its 95% confidence
+/// interval.
+#[derive(Debug, serde::Serialize)]
+pub struct Estimate {
+    pub mean: f64,
+    pub margin: f64,
+}
+
+impl Estimate {
+    /// Estimates the mean of `values` with Student's t-distribution, which
+    /// accounts for the few trials a run usually has. The margin is zero for a
+    /// single value.
+    pub fn new(values: &[f64]) -> Self {
+        // Two-sided 95% critical values for 1 to 30 degrees of freedom
+        const T_95: [f64; 30] = [
+            12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179,
+            2.160, 2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064,
+            2.060, 2.056, 2.052, 2.048, 2.045, 2.042,
+        ];
+
+        let n = values.len();
+        let mean = values.iter().sum::<f64>() / n as f64;
+        if n < 2 {
+            return Self { mean, margin: 0.0 };
+        }
+
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1) as f64;
+        let t = T_95.get(n - 2).copied().unwrap_or(1.96);
+        Self {
+            mean,
+            margin: t * (variance / n as f64).sqrt(),
+        }
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.3} ± {:.3}", self.mean, self.margin)
+    }
+}
+
+/// A latency percentile estimated across trials.
+#[derive(Debug, serde::Serialize)]
+pub struct PercentileEstimate {
+    /// The percentile, in percent (e.g. 99.9).
+    pub percentile: f64,
+
+    /// The latency in microseconds.
+    pub latency_us: Estimate,
+}
+
+/// Summary statistics of a multi-trial run.
+#[derive(Debug, serde::Serialize)]
+pub struct TrialStats {
+    /// The number of trials.
+    pub trials: usize,
+
+    /// The requested latency percentiles.
+    pub latencies: Vec<PercentileEstimate>,
+
+    /// Responses received per second.
+    pub achieved_rps: Estimate,
+}
+
+/// Saves the mean and 95% confidence interval of each percentile and of the
+/// achieved throughput across trials, and prints them. In text, the
+/// percentiles are on the first line (e.g. `p99: 52.200 ± 1.300`) and the
+/// throughput on the second.
+///
+/// # Arguments
+///
+/// * `trials` - The results of every trial.
+/// * `options` - The percentiles that were reported and the output format.
+/// * `path` - The destination file path.
+pub fn write_trial_stats(trials: &[Trial], options: &StatsOptions, path: &PathBuf) -> Result<()> {
+    let stats = TrialStats {
+        trials: trials.len(),
+        latencies: options
+            .percentiles
+            .iter()
+            .enumerate()
+            .map(|(i, &p)| PercentileEstimate {
+                percentile: p,
+                latency_us: Estimate::new(
+                    &trials.iter().map(|t| t.latencies_us[i]).collect::<Vec<_>>(),
+                ),
+            })
+            .collect(),
+        achieved_rps: Estimate::new(&trials.iter().map(|t| t.achieved_rps).collect::<Vec<_>>()),
+    };
+
+    let latencies = stats
+        .latencies
+        .iter()
+        .map(|p| format!("p{}: {}", p.percentile, p.latency_us))
+        .collect::<Vec<_>>()
+        .join(", ");
+    println!("over {} trials: {latencies}", stats.trials);
+    println!("achieved throughput: {} req/s", stats.achieved_rps);
 
     fs::create_dir_all(path.parent().expect("file path is missing directory"))?;
-    let mut file = File::create(path).unwrap();
+    let mut file = File::create(path)?;
+    match options.format {
+        Format::Text => {
+            writeln!(file, "{latencies}")?;
+            writeln!(file, "{}", stats.achieved_rps)?;
+        }
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut file, &stats)?;
+            writeln!(file)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Saves the spread of per-client percentiles.
+///
+/// Each line corresponds to one of the 50, 95, and 99th percentile latencies
+/// and contains the minimum, median, 99th percentile, and maximum of that
+/// percentile across clients (in microseconds).
+///
+/// # Arguments
+///
+/// * `lrs` - The latency records, tagged by client.
+/// * `path` - The destination file path.
+pub fn write_client_stats(lrs: &[LatencyRecord], path: &PathBuf) -> Result<()> {
+    // Group the latencies by client
+    let mut clients: BTreeMap<usize, Vec<u64>> = BTreeMap::new();
+    for lr in lrs {
+        clients
+            .entry(lr.client_id)
+            .or_default()
+            .push(lr.recv_time - lr.send_time);
+    }
+
+    fs::create_dir_all(path.parent().expect("file path is missing directory"))?;
+    let mut file = File::create(path)?;
+
+    for p in [0.5, 0.95, 0.99] {
+        // Compute this percentile for every client
+        let mut per_client: Vec<_> = clients
+            .values_mut()
+            .map(|latencies| {
+                latencies.sort();
+                percentile(latencies, p)
+            })
+            .collect();
+
+        if per_client.is_empty() {
+            continue;
+        }
+
+        per_client.sort();
+        let min = per_client[0] as f64 / 1000.0;
+        let p_50 = percentile(&per_client, 0.5) as f64 / 1000.0;
+        let p_99 = percentile(&per_client, 0.99) as f64 / 1000.0;
+        let max = per_client[per_client.len() - 1] as f64 / 1000.0;
 
-    writeln!(file, "{p_50}, {p_95}, {p_99}")?;
-    writeln!(file, "{offered}, {achieved}")?;
+        writeln!(file, "{}: {min}, {p_50}, {p_99}, {max}", p * 100.0)?;
+    }
 
     Ok(())
 }
+
+/// Gets the `p`th percentile (`0.0..=1.0`) of a sorted, non-empty slice. The
+/// rank is floored and clamped to the last element, so high percentiles of
+/// small samples are the maximum.
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    let idx = (sorted.len() as f64 * p).floor() as usize;
+    sorted[idx.min(sorted.len() - 1)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A record of a request that took `latency_us` microseconds, sent by `client_id`.
+    fn record(client_id: usize, send_time: u64, latency_us: u64) -> LatencyRecord {
+        LatencyRecord {
+            send_time,
+            recv_time: send_time + latency_us * 1000,
+            client_id,
+            queue_depth: 0,
+            server_process_ns: 0,
+            inflight: 1,
+        }
+    }
+
+    /// Writes the text statistics of `lrs` over `runtime`, reporting the 50th, 95th, and 99th
+    /// percentiles, and reads them back a line at a time.
+    fn stats(lrs: Vec<LatencyRecord>, runtime: Duration) -> Result<Vec<String>> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.txt");
+        let options = StatsOptions {
+            percentiles: vec![50.0, 95.0, 99.0],
+            format: Format::Text,
+            append: None,
+        };
+        let n = lrs.len();
+        write_stats(
+            lrs,
+            n,
+            0,
+            Failures::default(),
+            runtime,
+            Mix::from(protocol::Work::Constant),
+            &options,
+            &path,
+        )?;
+        Ok(fs::read_to_string(&path)?
+            .lines()
+            .map(String::from)
+            .collect())
+    }
+
+    /// Records of `n` requests a millisecond apart that took 1, 2, ..., `n` microseconds.
+    fn records(n: u64) -> Vec<LatencyRecord> {
+        (0..n).map(|i| record(0, i * 1_000_000, i + 1)).collect()
+    }
+
+    #[test]
+    fn stats_without_records() {
+        let e = stats(Vec::new(), Duration::from_secs(1)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn stats_of_one_record() {
+        // Every percentile is the only latency
+        let lines = stats(records(1), Duration::from_secs(1)).unwrap();
+        assert_eq!(lines[0], "p50: 1, p95: 1, p99: 1");
+        assert_eq!(lines[3], "1.000, 1, 1, 0.000");
+    }
+
+    #[test]
+    fn stats_of_100_records() {
+        // The 99th percentile's rank is the last record's
+        let lines = stats(records(100), Duration::from_secs(1)).unwrap();
+        assert_eq!(lines[0], "p50: 51, p95: 96, p99: 100");
+        assert_eq!(lines[1], "100.00, 100.00");
+    }
+
+    #[test]
+    fn stats_of_101_records() {
+        let lines = stats(records(101), Duration::from_secs(1)).unwrap();
+        assert_eq!(lines[0], "p50: 51, p95: 96, p99: 100");
+        assert_eq!(lines[3].split(", ").nth(2), Some("101"));
+    }
+
+    #[test]
+    fn stats_throughput() {
+        // 6000 requests over 6 seconds
+        let lines = stats(records(6000), Duration::from_secs(6)).unwrap();
+        assert_eq!(lines[1], "1000.00, 1000.00");
+    }
+
+    #[test]
+    fn percentile_is_clamped() {
+        assert_eq!(percentile(&[7], 0.0), 7);
+        assert_eq!(percentile(&[7], 1.0), 7);
+
+        let sorted: Vec<u64> = (1..=100).collect();
+        assert_eq!(percentile(&sorted, 0.99), 100);
+        assert_eq!(percentile(&sorted, 1.0), 100);
+    }
+
+    #[test]
+    fn client_stats_spread() {
+        // Client 0 is fast and client 1 is slow, so every percentile ranges from one to the other
+        let mut lrs = Vec::new();
+        for i in 0..100 {
+            lrs.push(record(0, i * 1000, 100 + i));
+            lrs.push(record(1, i * 1000, 500 + i));
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clients.txt");
+        write_client_stats(&lrs, &path).unwrap();
+
+        let lines = fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = lines.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "50: 150, 550, 550, 550",
+                "95: 195, 595, 595, 595",
+                "99: 199, 599, 599, 599",
+            ]
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    fmt,
+    io::{Error, ErrorKind, Result},
+    str::FromStr,
+};
+
+use clap::ValueEnum;
+use rand::Rng;
+
+use crate::protocol::{Response, SleepDistribution, Work};
+
+/// A weighted set of work that requests are sampled from, e.g. mostly cheap
+/// requests with the occasional expensive one.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Mix {
+    choices: Vec<Choice>,
+
+    /// The sum of the weights.
+    #[serde(skip)]
+    total: u64,
+}
+
+/// A single entry of a mix.
+#[derive(Clone, Copy, Debug, serde::Serialize)]
+struct Choice {
+    work: Work,
+    weight: u32,
+}
+
+impl Mix {
+    /// Samples the work for the next request. Random work gets a seed from `rng` too.
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Work {
+        if let [choice] = self.choices.as_slice() {
+            return choice.work.seeded(rng);
+        }
+
+        let mut x = rng.random_range(0..self.total);
+        for choice in &self.choices {
+            if x < choice.weight as u64 {
+                return choice.work.seeded(rng);
+            }
+            x -= choice.weight as u64;
+        }
+
+        unreachable!("sample is always below the total weight")
+    }
+
+    /// Gets the largest number of padding bytes a response can carry.
+    pub fn max_payload_len(&self) -> u64 {
+        self.choices
+            .iter()
+            .map(|choice| choice.work.payload_len())
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Checks that the payload has a length some work in the mix asks for. This
+    /// is for when the work a response belongs to isn't known.
+    pub fn verify(&self, response: &Response) -> Result<()> {
+        if let [choice] = self.choices.as_slice() {
+            return response.verify(&choice.work);
+        }
+
+        if self
+            .choices
+            .iter()
+            .any(|choice| response.verify(&choice.work).is_ok())
+        {
+            return Ok(());
+        }
+
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "a {}-byte payload doesn't match any work in the mix",
+                response.payload_len
+            ),
+        ))
+    }
+}
+
+impl From<Work> for Mix {
+    fn from(work: Work) -> Self {
+        Self {
+            choices: vec![Choice { work, weight: 1 }],
+            total: 1,
+        }
+    }
+}
+
+/// Formats the mix the way it is parsed, e.g. `constant:90,busy:9:1000`.
+impl fmt::Display for Mix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, Choice { work, weight }) in self.choices.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+
+            match work {
+                Work::Constant => write!(f, "constant:{weight}")?,
+                Work::Busy { amt } => write!(f, "busy:{weight}:{amt}")?,
+                Work::Sleep { micros } => write!(f, "sleep:{weight}:{micros}")?,
+                Work::Echo { bytes } => write!(f, "echo:{weight}:{bytes}")?,
+                Work::Matmul { dim } => write!(f, "matmul:{weight}:{dim}")?,
+                Work::MemChase { size, hops } => write!(f, "memchase:{weight}:{size}/{hops}")?,
+                Work::FileRead { path_id, bytes } => {
+                    write!(f, "fileread:{weight}:{path_id}/{bytes}")?
+                }
+                Work::RandomSleep {
+                    mean_micros, dist, ..
+                } => {
+                    let dist = dist.to_possible_value().unwrap();
+                    write!(f, "randomsleep:{weight}:{mean_micros}/{}", dist.get_name())?
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses a comma-separated list of `<work>:<weight>[:<param>]` entries, e.g.
+/// `constant:90,busy:9:1000,sleep:1:500`. The parameter is the loop count for
+/// `busy`, microseconds for `sleep`, bytes for `echo`, the matrix dimension for
+/// `matmul`, `<size>/<hops>` for `memchase`, `<path_id>/<bytes>` for
+/// `fileread`, and `<mean micros>/<exponential|lognormal>` for `randomsleep`.
+impl FromStr for Mix {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parse_u64 = |v: &str| {
+            v.trim()
+                .parse::<u64>()
+                .map_err(|e| format!("invalid number '{v}': {e}"))
+        };
+
+        let choices = s
+            .split(',')
+            .map(|entry| {
+                let mut parts = entry.split(':');
+                let kind = parts.next().unwrap_or("").trim();
+                let weight = parts
+                    .next()
+                    .ok_or(format!("expected <work>:<weight>, got '{entry}'"))?;
+                let weight = weight
+                    .trim()
+                    .parse::<u32>()
+                    .map_err(|e| format!("invalid weight '{weight}': {e}"))?;
+                let param = parts.next();
+                if parts.next().is_some() {
+                    return Err(format!("too many fields in '{entry}'"));
+                }
+
+                let param = |name| param.ok_or(format!("{kind} needs a {name} in '{entry}'"));
+                let work = match kind {
+                    "constant" => Work::Constant,
+                    "busy" => Work::Busy {
+                        amt: parse_u64(param("loop count")?)?,
+                    },
+                    "sleep" => Work::Sleep {
+                        micros: parse_u64(param("duration")?)?,
+                    },
+                    "echo" => Work::Echo {
+                        bytes: parse_u64(param("size")?)?,
+                    },
+                    "matmul" => Work::Matmul {
+                        dim: parse_u64(param("dimension")?)?,
+                    },
+                    "memchase" => {
+                        let (size, hops) = param("size/hops pair")?
+                            .split_once('/')
+                            .ok_or(format!("memchase needs <size>/<hops> in '{entry}'"))?;
+                        Work::MemChase {
+                            size: parse_u64(size)?,
+                            hops: parse_u64(hops)?,
+                        }
+                    }
+                    "fileread" => {
+                        let (path_id, bytes) = param("path_id/bytes pair")?
+                            .split_once('/')
+                            .ok_or(format!("fileread needs <path_id>/<bytes> in '{entry}'"))?;
+                        Work::FileRead {
+                            path_id: parse_u64(path_id)?,
+                            bytes: parse_u64(bytes)?,
+                        }
+                    }
+                    "randomsleep" => {
+                        let (mean_micros, dist) = param("mean/distribution pair")?
+                            .split_once('/')
+                            .ok_or(format!(
+                                "randomsleep needs <mean micros>/<distribution> in '{entry}'"
+                            ))?;
+                        Work::RandomSleep {
+                            mean_micros: parse_u64(mean_micros)?,
+                            dist: <SleepDistribution as ValueEnum>::from_str(dist.trim(), true)?,
+                            seed: 0,
+                        }
+                    }
+                    _ => return Err(format!("unknown work '{kind}'")),
+                };
+                work.validate().map_err(|e| format!("{e} in '{entry}'"))?;
+
+                Ok(Choice { work, weight })
+            })
+            .collect::<std::result::Result<Vec<_>, String>>()?;
+
+        let total = choices.iter().map(|choice| choice.weight as u64).sum();
+        if total == 0 {
+            return Err("the weights of a mix must not all be zero".to_string());
+        }
+
+        Ok(Self { choices, total })
+    }
+}
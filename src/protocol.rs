@@ -1,19 +1,88 @@
 use std::{
-    io::{Error, ErrorKind, Read, Result, Write},
-    thread,
-    time::Duration,
+    cell::RefCell,
+    f64::consts::PI,
+    hint,
+    io::{self, Error, ErrorKind, IoSlice, Read, Result, Write},
+    mem, thread,
+    time::{Duration, Instant},
 };
 
-use clap::Subcommand;
+use clap::{Subcommand, ValueEnum};
+use log::warn;
+use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
 
-use crate::get_time;
+use crate::{
+    files,
+    framing::{self, HEADER_SIZE},
+    get_time,
+};
+
+/// The size of the largest request, including its length prefix.
+pub const REQUEST_SIZE: usize = HEADER_SIZE + 20 + Work::MAX_SIZE;
+/// The size of a response without its body or payload, including its length prefix.
+pub const RESPONSE_SIZE: usize = HEADER_SIZE + 45;
+
+/// The largest request body servers buffer. Longer requests are rejected.
+pub const MAX_REQUEST_BODY: usize = 4096;
+
+/// The largest body a request can carry along with any work, so that the whole request fits in
+/// `MAX_REQUEST_BODY`.
+pub const MAX_REQUEST_PAYLOAD: usize = MAX_REQUEST_BODY - (REQUEST_SIZE - HEADER_SIZE);
+
+/// The largest payload echo work can ask for. Event loop servers buffer whole responses, so
+/// requests for more are rejected.
+pub const MAX_ECHO_BYTES: u64 = 64 << 20;
+
+/// The most entries the matrices of matmul work may have (2048 x 2048), since the server
+/// allocates three of them.
+pub const MAX_MATMUL_ENTRIES: u64 = 1 << 22;
 
-pub const REQUEST_SIZE: usize = 17;
-pub const RESPONSE_SIZE: usize = 8;
+/// The largest array memchase work may chase pointers through. Every thread that does it keeps
+/// an array of its own, so this is well past the last-level cache but not unbounded.
+pub const MAX_CHASE_BYTES: u64 = 256 << 20;
 
+/// Zeros that response payloads are written from.
+const PADDING: [u8; 4096] = [0; 4096];
+
+/// The most buffers a response is written with at once: the fixed-size part and up to 64 KiB of
+/// padding.
+const MAX_SLICES: usize = 17;
+
+#[derive(Clone, Copy, Debug)]
 pub struct LatencyRecord {
     pub send_time: u64,
     pub recv_time: u64,
+
+    /// The index of the client that sent the request.
+    pub client_id: usize,
+
+    /// The server's queue depth when it handled the request.
+    pub queue_depth: u32,
+
+    /// The time (in nanoseconds) the server spent doing the work.
+    pub server_process_ns: u64,
+
+    /// The number of requests in flight (sent but not yet answered, across all clients) when the
+    /// request was sent, itself included.
+    pub inflight: u64,
+}
+
+impl LatencyRecord {
+    /// Takes `overhead` nanoseconds of clock reading off the latency, without
+    /// making it negative.
+    pub fn subtract_overhead(&mut self, overhead: u64) {
+        self.recv_time = self.recv_time.saturating_sub(overhead).max(self.send_time);
+    }
+}
+
+/// A latency record tagged with the number of clients running when its request was sent, so a
+/// ramped closed loop run can report latency against concurrency.
+#[derive(Clone, Copy, Debug)]
+pub struct RampRecord {
+    pub lr: LatencyRecord,
+
+    /// The number of clients running when the request was sent.
+    pub clients: usize,
 }
 
 pub trait Serialize<T> {
@@ -31,34 +100,144 @@ pub struct Request {
     /// The time (in nanoseconds) the request was sent.
     pub send_time: u64,
 
+    /// Identifies the request among those sent on the same connection. Clients number them in
+    /// the order they are sent.
+    pub req_id: u64,
+
+    /// The CRC-32 of the rest of the request (see `compute_checksum`), or 0 if the client doesn't
+    /// verify checksums.
+    pub checksum: u32,
+
     /// The work to do.
     pub work: Work,
+
+    /// Bytes that follow the work, which the server echoes back ahead of the response's padding
+    /// (e.g. a realistic payload loaded from a file). Usually empty.
+    pub body: Vec<u8>,
 }
 
+/// Requests are packed into a stack buffer and written with a single call, so an unbuffered
+/// stream sends them with one syscall. A body is written from its own buffer in the same
+/// vectored write.
 impl<T: Write> Serialize<T> for Request {
     fn serialize(self, bytes: &mut T) -> Result<()> {
-        bytes.write_all(&self.send_time.to_be_bytes())?;
-        self.work.serialize(bytes)?;
-        Ok(())
+        let len = HEADER_SIZE + 20 + self.work.size();
+        let mut buf = [0u8; REQUEST_SIZE];
+
+        let mut packed = &mut buf[..];
+        framing::write_header(&mut packed, len - HEADER_SIZE + self.body.len())?;
+        packed.write_all(&framing::encode_u64(self.send_time))?;
+        packed.write_all(&framing::encode_u64(self.req_id))?;
+        packed.write_all(&framing::encode_u32(self.checksum))?;
+        self.work.serialize(&mut packed)?;
+
+        if self.body.is_empty() {
+            return bytes.write_all(&buf[..len]);
+        }
+        framing::write_all_vectored(
+            bytes,
+            &mut [IoSlice::new(&buf[..len]), IoSlice::new(&self.body)],
+        )
     }
 }
 
+/// The body of a request is read with a single call and parsed from a stack buffer, unless the
+/// request carries a body too long to fit in one.
 impl<T: Read> Deserialize<T> for Request {
     fn deserialize(bytes: &mut T) -> Result<Self> {
-        let mut send_time_bytes = [0u8; 8];
-        bytes.read_exact(&mut send_time_bytes)?;
-
-        let send_time = u64::from_be_bytes(send_time_bytes);
-        let work = Work::deserialize(bytes)?;
-        Ok(Self { send_time, work })
+        framing::read_small_frame::<_, _, { REQUEST_SIZE - HEADER_SIZE }>(
+            bytes,
+            MAX_REQUEST_BODY,
+            |body| {
+                let send_time = framing::read_u64(body)?;
+                let req_id = framing::read_u64(body)?;
+                let checksum = framing::read_u32(body)?;
+                let work = Work::deserialize(body)?;
+                Ok(Self {
+                    send_time,
+                    req_id,
+                    checksum,
+                    work,
+                    body: mem::take(body).to_vec(),
+                })
+            },
+        )
     }
 }
 
 impl Request {
-    pub fn do_work(self) -> Response {
+    /// Creates a request, with a checksum the server echoes back if `checksum` is set.
+    pub fn new(send_time: u64, req_id: u64, work: Work, checksum: bool) -> Self {
+        let mut request = Self {
+            send_time,
+            req_id,
+            checksum: 0,
+            work,
+            body: Vec::new(),
+        };
+        if checksum {
+            request.checksum = request.compute_checksum();
+        }
+        request
+    }
+
+    /// Attaches a body to the request, for the server to echo back.
+    pub fn with_body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+
+    pub fn do_work(&self) -> Response {
+        let start = Instant::now();
         self.work.do_work();
         Response {
             client_send_time: self.send_time,
+            req_id: self.req_id,
+            queue_depth: 0,
+            server_process_ns: start.elapsed().as_nanos() as u64,
+            payload_len: self.work.payload_len(),
+            checksum: self.response_checksum(),
+            body: self.body.clone(),
+            status: Status::Ok,
+        }
+    }
+
+    /// Turns the request away as busy without doing its work, answering it with neither its body
+    /// nor a payload.
+    pub fn reject(&self) -> Response {
+        Response {
+            client_send_time: self.send_time,
+            req_id: self.req_id,
+            queue_depth: 0,
+            server_process_ns: 0,
+            payload_len: 0,
+            checksum: self.response_checksum(),
+            body: Vec::new(),
+            status: Status::Busy,
+        }
+    }
+
+    /// Computes the CRC-32 of the send time, id, and work, as they are encoded on the wire.
+    pub fn compute_checksum(&self) -> u32 {
+        let mut buf = [0u8; 16 + Work::MAX_SIZE];
+        let mut packed = &mut buf[..];
+        packed
+            .write_all(&framing::encode_u64(self.send_time))
+            .unwrap();
+        packed.write_all(&framing::encode_u64(self.req_id)).unwrap();
+        self.work.serialize(&mut packed).unwrap();
+
+        framing::crc32(&buf[..16 + self.work.size()])
+    }
+
+    /// Gets the checksum the response to this request carries: the checksum of the request as
+    /// the server received it, so the client can tell if it was corrupted on the way, or 0 if the
+    /// client doesn't verify checksums.
+    pub fn response_checksum(&self) -> u32 {
+        if self.checksum == 0 {
+            0
+        } else {
+            self.compute_checksum()
         }
     }
 }
@@ -67,43 +246,245 @@ impl Request {
 pub struct Response {
     /// The time (in nanoseconds) the request was sent by the client.
     pub client_send_time: u64,
+
+    /// The id of the request this answers.
+    pub req_id: u64,
+
+    /// The server's queue depth when it handled the request (e.g. connections waiting for a
+    /// thread, or connections with pending reads).
+    pub queue_depth: u32,
+
+    /// The time (in nanoseconds) the server spent doing the work, so the rest of the latency is
+    /// queueing and the network.
+    pub server_process_ns: u64,
+
+    /// The number of padding bytes that follow the response.
+    pub payload_len: u64,
+
+    /// The checksum of the request as the server received it, or 0 if the client doesn't verify
+    /// checksums.
+    pub checksum: u32,
+
+    /// The body of the request, echoed back ahead of the padding.
+    pub body: Vec<u8>,
+
+    /// Whether the server did the work or turned the request away.
+    pub status: Status,
+}
+
+/// Whether the server did the work a request asked for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// The work was done.
+    Ok = 0,
+
+    /// The server was overloaded and turned the request away without doing its work, telling the
+    /// client to back off.
+    Busy = 1,
 }
 
 impl Response {
-    pub fn to_latency_record(&self) -> LatencyRecord {
-        let send_time = self.client_send_time;
-        let recv_time = get_time();
+    /// Returns `true` if the server turned the request away.
+    pub fn is_busy(&self) -> bool {
+        self.status == Status::Busy
+    }
 
-        if recv_time < send_time {
-            panic!("error: send/recv times are inconsistent")
+    /// Checks that the payload has the length `work` asked for. Busy responses carry no payload,
+    /// so they always pass.
+    pub fn verify(&self, work: &Work) -> Result<()> {
+        if !self.is_busy() && self.payload_len != work.payload_len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "expected a {}-byte payload, got {} bytes",
+                    work.payload_len(),
+                    self.payload_len
+                ),
+            ));
         }
 
+        Ok(())
+    }
+
+    /// Checks that the server echoed back the body it was sent. Busy responses carry no body, so
+    /// they always pass.
+    pub fn verify_body(&self, body: &[u8]) -> Result<()> {
+        if !self.is_busy() && self.body != body {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "the {}-byte body echoed back doesn't match the {}-byte body sent",
+                    self.body.len(),
+                    body.len()
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the response answers request `req_id`, so a response is never matched to the
+    /// wrong request.
+    pub fn verify_id(&self, req_id: u64) -> Result<()> {
+        if self.req_id != req_id {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "expected a response to request {req_id}, got one to request {}",
+                    self.req_id
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the server received the request with checksum `checksum` intact. Responses to
+    /// requests without a checksum always pass.
+    pub fn verify_checksum(&self, checksum: u32) -> bool {
+        self.checksum == checksum
+    }
+
+    /// Creates the latency record of the request this answers, received now. `inflight` is the
+    /// number of requests that were in flight when it was sent.
+    pub fn to_latency_record(&self, client_id: usize, inflight: u64) -> LatencyRecord {
+        let send_time = self.client_send_time;
+        let recv_time = get_time();
+
         LatencyRecord {
-            send_time: self.client_send_time,
-            recv_time: get_time(),
+            send_time,
+            recv_time,
+            client_id,
+            queue_depth: self.queue_depth,
+            server_process_ns: self.server_process_ns,
+            inflight,
         }
     }
 }
 
+/// The fixed-size part of a response is packed into a stack buffer and written together with the
+/// echoed body and payload in a single vectored write (one `writev` on an unbuffered socket), or
+/// a few of them for payloads over 64 KiB.
 impl<T: Write> Serialize<T> for Response {
     fn serialize(self, bytes: &mut T) -> Result<()> {
-        bytes.write_all(&self.client_send_time.to_be_bytes())?;
-        Ok(())
+        let len = RESPONSE_SIZE - HEADER_SIZE + self.body.len() + self.payload_len as usize;
+        let mut buf = [0u8; RESPONSE_SIZE];
+
+        let mut packed = &mut buf[..];
+        framing::write_header(&mut packed, len)?;
+        packed.write_all(&framing::encode_u64(self.client_send_time))?;
+        packed.write_all(&framing::encode_u64(self.req_id))?;
+        packed.write_all(&framing::encode_u32(self.queue_depth))?;
+        packed.write_all(&framing::encode_u64(self.server_process_ns))?;
+        packed.write_all(&framing::encode_u64(self.payload_len))?;
+        packed.write_all(&framing::encode_u32(self.checksum))?;
+        packed.write_all(&framing::encode_u32(self.body.len() as u32))?;
+        packed.write_all(&[self.status as u8])?;
+
+        let mut slices = [IoSlice::new(&[]); MAX_SLICES];
+        slices[0] = IoSlice::new(&buf);
+        let mut n = 1;
+        if !self.body.is_empty() {
+            slices[1] = IoSlice::new(&self.body);
+            n += 1;
+        }
+        let mut remaining = self.payload_len as usize;
+        loop {
+            while n < MAX_SLICES && remaining > 0 {
+                let len = remaining.min(PADDING.len());
+                slices[n] = IoSlice::new(&PADDING[..len]);
+                remaining -= len;
+                n += 1;
+            }
+
+            framing::write_all_vectored(bytes, &mut slices[..n])?;
+            if remaining == 0 {
+                return Ok(());
+            }
+            n = 0;
+        }
     }
 }
 
+/// The length prefix and fixed-size part of a response are read with a single call, since every
+/// response has them. A connection closed before the response starts is `UnexpectedEof`, while
+/// one closed part way through it is `InvalidData`.
 impl<T: Read> Deserialize<T> for Response {
     fn deserialize(bytes: &mut T) -> Result<Self> {
-        let mut send_time_bytes = [0u8; 8];
-        bytes.read_exact(&mut send_time_bytes)?;
+        let mut buf = [0u8; RESPONSE_SIZE];
+        framing::read_message(bytes, &mut buf)?;
 
-        let client_send_time = u64::from_be_bytes(send_time_bytes);
-        Ok(Self { client_send_time })
+        let (header, mut fixed) = buf.split_at(HEADER_SIZE);
+        let len = framing::decode_header(header.try_into().unwrap());
+        let client_send_time = framing::read_u64(&mut fixed)?;
+        let req_id = framing::read_u64(&mut fixed)?;
+        let queue_depth = framing::read_u32(&mut fixed)?;
+        let server_process_ns = framing::read_u64(&mut fixed)?;
+        let payload_len = framing::read_u64(&mut fixed)?;
+        let checksum = framing::read_u32(&mut fixed)?;
+        let body_len = framing::read_u32(&mut fixed)?;
+        let status = match fixed[0] {
+            0 => Status::Ok,
+            1 => Status::Busy,
+            n => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{n} is an invalid response status"),
+                ));
+            }
+        };
+
+        let expected =
+            ((RESPONSE_SIZE - HEADER_SIZE) as u64 + body_len as u64).checked_add(payload_len);
+        if Some(len as u64) != expected {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "a {len}-byte response can't carry a {body_len}-byte body and a \
+                     {payload_len}-byte payload"
+                ),
+            ));
+        }
+
+        // Bodies are never longer than the largest request
+        if body_len as usize > MAX_REQUEST_BODY {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("a {body_len}-byte body is longer than any request"),
+            ));
+        }
+        let mut body = vec![0u8; body_len as usize];
+        bytes.read_exact(&mut body).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => {
+                Error::new(ErrorKind::InvalidData, "response body is truncated")
+            }
+            _ => e,
+        })?;
+
+        // Discard the payload without buffering it
+        let skipped = io::copy(&mut bytes.take(payload_len), &mut io::sink())?;
+        if skipped != payload_len {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "response payload is truncated",
+            ));
+        }
+
+        Ok(Self {
+            client_send_time,
+            req_id,
+            queue_depth,
+            server_process_ns,
+            payload_len,
+            checksum,
+            body,
+            status,
+        })
     }
 }
 
 /// Work for a client request.
-#[derive(Clone, Copy, Debug, Subcommand)]
+#[derive(Clone, Copy, Debug, Subcommand, serde::Serialize)]
 pub enum Work {
     /// Do nothing.
     Constant,
@@ -113,6 +494,32 @@ pub enum Work {
 
     /// Sleep for a specified number of microseconds.
     Sleep { micros: u64 },
+
+    /// Sleep for a random number of microseconds, drawn from `dist` with a mean of
+    /// `mean_micros`. The client gives every request its own seed, so the server's draws follow
+    /// from the run's seed.
+    RandomSleep {
+        mean_micros: u64,
+        dist: SleepDistribution,
+
+        #[arg(skip)]
+        #[serde(skip)]
+        seed: u64,
+    },
+
+    /// Respond with a specified number of bytes of padding, at most 64 MiB.
+    Echo { bytes: u64 },
+
+    /// Multiply two `dim` x `dim` matrices of floats, with `dim` at most 2048.
+    Matmul { dim: u64 },
+
+    /// Follow `hops` pointers through a randomly linked array of `size` bytes, at most 256 MiB.
+    MemChase { size: u64, hops: u64 },
+
+    /// Open the server's file `path_id` and read `bytes` bytes from it, blocking the thread
+    /// doing the work. Reads are usually served from the page cache once the file has been
+    /// read.
+    FileRead { path_id: u64, bytes: u64 },
 }
 
 impl Work {
@@ -120,9 +527,101 @@ impl Work {
         match self {
             Work::Constant => {}
             Work::Busy { amt } => for _ in 0..amt {},
-            Work::Sleep { micros } => {
-                thread::sleep(Duration::from_micros(micros));
+            Work::Sleep { .. } | Work::RandomSleep { .. } => {
+                thread::sleep(self.sleep_duration().unwrap());
+            }
+            Work::Echo { .. } => {}
+            Work::Matmul { dim } => {
+                hint::black_box(matmul(dim as usize));
+            }
+            Work::MemChase { size, hops } => {
+                hint::black_box(chase(size as usize, hops));
+            }
+            Work::FileRead { path_id, bytes } => {
+                if let Err(e) = files::read(path_id, bytes) {
+                    warn!("failed to read file {path_id}: {e}");
+                }
+            }
+        }
+    }
+
+    /// Gets how long sleep work sleeps for, drawing it from the seed for random sleeps, or `None`
+    /// for other work.
+    pub fn sleep_duration(&self) -> Option<Duration> {
+        match *self {
+            Work::Sleep { micros } => Some(Duration::from_micros(micros)),
+            Work::RandomSleep {
+                mean_micros,
+                dist,
+                seed,
+            } => {
+                let micros = dist.sample(mean_micros as f64, &mut StdRng::seed_from_u64(seed));
+                Some(Duration::from_nanos((micros * 1000.0) as u64))
+            }
+            _ => None,
+        }
+    }
+
+    /// Gives random work a seed of its own from `rng`, leaving other work as it is.
+    pub fn seeded<R: Rng>(self, rng: &mut R) -> Self {
+        match self {
+            Work::RandomSleep {
+                mean_micros, dist, ..
+            } => Work::RandomSleep {
+                mean_micros,
+                dist,
+                seed: rng.random(),
+            },
+            work => work,
+        }
+    }
+
+    /// The serialized size of the largest work.
+    const MAX_SIZE: usize = 18;
+
+    /// Gets the serialized size of the work.
+    pub fn size(&self) -> usize {
+        match self {
+            Work::RandomSleep { .. } => 18,
+            Work::MemChase { .. } | Work::FileRead { .. } => 17,
+            _ => 9,
+        }
+    }
+
+    /// Checks that the work is within the limits servers do work up to, so a request can't make a
+    /// server allocate without bound.
+    pub fn validate(&self) -> Result<()> {
+        match *self {
+            Work::Echo { bytes } if bytes > MAX_ECHO_BYTES => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("a {bytes}-byte echo is larger than the {MAX_ECHO_BYTES}-byte maximum"),
+            )),
+            Work::Matmul { dim }
+                if dim
+                    .checked_mul(dim)
+                    .is_none_or(|entries| entries > MAX_MATMUL_ENTRIES) =>
+            {
+                Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "a {dim} x {dim} matmul has more than the {MAX_MATMUL_ENTRIES}-entry \
+                         maximum"
+                    ),
+                ))
             }
+            Work::MemChase { size, .. } if size > MAX_CHASE_BYTES => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("a {size}-byte chase is larger than the {MAX_CHASE_BYTES}-byte maximum"),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Gets the number of padding bytes the response carries.
+    pub fn payload_len(&self) -> u64 {
+        match self {
+            Work::Echo { bytes } => *bytes,
+            _ => 0,
         }
     }
 }
@@ -136,11 +635,38 @@ impl<T: Write> Serialize<T> for Work {
             }
             Work::Busy { amt } => {
                 bytes.write_all(&[1])?;
-                bytes.write_all(&amt.to_be_bytes())?;
+                bytes.write_all(&framing::encode_u64(amt))?;
             }
             Work::Sleep { micros } => {
                 bytes.write_all(&[2])?;
-                bytes.write_all(&micros.to_be_bytes())?;
+                bytes.write_all(&framing::encode_u64(micros))?;
+            }
+            Work::Echo { bytes: n } => {
+                bytes.write_all(&[3])?;
+                bytes.write_all(&framing::encode_u64(n))?;
+            }
+            Work::Matmul { dim } => {
+                bytes.write_all(&[4])?;
+                bytes.write_all(&framing::encode_u64(dim))?;
+            }
+            Work::MemChase { size, hops } => {
+                bytes.write_all(&[5])?;
+                bytes.write_all(&framing::encode_u64(size))?;
+                bytes.write_all(&framing::encode_u64(hops))?;
+            }
+            Work::FileRead { path_id, bytes: n } => {
+                bytes.write_all(&[6])?;
+                bytes.write_all(&framing::encode_u64(path_id))?;
+                bytes.write_all(&framing::encode_u64(n))?;
+            }
+            Work::RandomSleep {
+                mean_micros,
+                dist,
+                seed,
+            } => {
+                bytes.write_all(&[7, dist as u8])?;
+                bytes.write_all(&framing::encode_u64(mean_micros))?;
+                bytes.write_all(&framing::encode_u64(seed))?;
             }
         }
 
@@ -153,29 +679,233 @@ impl<T: Read> Deserialize<T> for Work {
         let mut id = [0u8; 1];
         bytes.read_exact(&mut id)?;
 
-        match id[0] {
+        let work = match id[0] {
             0 => {
                 bytes.read_exact(&mut [0u8; 8])?;
-                Ok(Work::Constant)
+                Work::Constant
             }
-            1 => {
-                let mut amt_bytes = [0u8; 8];
-                bytes.read_exact(&mut amt_bytes)?;
-                Ok(Work::Busy {
-                    amt: u64::from_be_bytes(amt_bytes),
-                })
+            1 => Work::Busy {
+                amt: framing::read_u64(bytes)?,
+            },
+            2 => Work::Sleep {
+                micros: framing::read_u64(bytes)?,
+            },
+            3 => Work::Echo {
+                bytes: framing::read_u64(bytes)?,
+            },
+            4 => Work::Matmul {
+                dim: framing::read_u64(bytes)?,
+            },
+            5 => Work::MemChase {
+                size: framing::read_u64(bytes)?,
+                hops: framing::read_u64(bytes)?,
+            },
+            6 => Work::FileRead {
+                path_id: framing::read_u64(bytes)?,
+                bytes: framing::read_u64(bytes)?,
+            },
+            7 => {
+                let mut dist = [0u8; 1];
+                bytes.read_exact(&mut dist)?;
+                Work::RandomSleep {
+                    dist: SleepDistribution::from_id(dist[0])?,
+                    mean_micros: framing::read_u64(bytes)?,
+                    seed: framing::read_u64(bytes)?,
+                }
             }
-            2 => {
-                let mut micros_bytes = [0u8; 8];
-                bytes.read_exact(&mut micros_bytes)?;
-                Ok(Work::Sleep {
-                    micros: u64::from_be_bytes(micros_bytes),
-                })
+            n => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("failed to deserialize work message: {n} is an invalid work id"),
+                ));
             }
+        };
+
+        work.validate()?;
+        Ok(work)
+    }
+}
+
+/// The distribution random sleeps are drawn from.
+#[derive(Clone, Copy, Debug, ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SleepDistribution {
+    /// Memoryless, as if requests finished at a constant rate.
+    Exponential = 0,
+
+    /// Heavy-tailed, the exponential of a normal distribution with a standard deviation of 1, so
+    /// the 99th percentile is about 6x the mean.
+    Lognormal = 1,
+}
+
+/// The standard deviation of the normal distribution whose exponential lognormal sleeps are.
+const LOGNORMAL_SIGMA: f64 = 1.0;
+
+impl SleepDistribution {
+    fn from_id(id: u8) -> Result<Self> {
+        match id {
+            0 => Ok(SleepDistribution::Exponential),
+            1 => Ok(SleepDistribution::Lognormal),
             n => Err(Error::new(
                 ErrorKind::InvalidData,
-                format!("failed to deserialize work message: {n} is an invalid work id"),
+                format!("failed to deserialize work message: {n} is an invalid distribution id"),
             )),
         }
     }
+
+    /// Draws a value with a mean of `mean`.
+    fn sample<R: Rng>(self, mean: f64, rng: &mut R) -> f64 {
+        // In (0, 1], so its logarithm is finite
+        let u = 1.0 - rng.random::<f64>();
+        match self {
+            SleepDistribution::Exponential => -mean * u.ln(),
+            SleepDistribution::Lognormal => {
+                // A standard normal draw by the Box-Muller transform
+                let z = (-2.0 * u.ln()).sqrt() * (2.0 * PI * rng.random::<f64>()).cos();
+                let mu = mean.ln() - LOGNORMAL_SIGMA * LOGNORMAL_SIGMA / 2.0;
+                (mu + LOGNORMAL_SIGMA * z).exp()
+            }
+        }
+    }
+}
+
+/// Multiplies two `dim` x `dim` matrices and returns the sum of the product's
+/// entries, so the multiplication can't be optimized away.
+fn matmul(dim: usize) -> f64 {
+    let a: Vec<f64> = (0..dim * dim).map(|i| (i % 7) as f64).collect();
+    let b: Vec<f64> = (0..dim * dim).map(|i| (i % 5) as f64).collect();
+    let mut c = vec![0.0; dim * dim];
+
+    for i in 0..dim {
+        for k in 0..dim {
+            let a_ik = a[i * dim + k];
+            for j in 0..dim {
+                c[i * dim + j] += a_ik * b[k * dim + j];
+            }
+        }
+    }
+
+    c.iter().sum()
+}
+
+thread_local! {
+    /// The array each thread chases pointers through, reused while the size
+    /// stays the same.
+    static CHASE: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Follows `hops` pointers through an array of `size` bytes linked into a
+/// single random cycle, so every hop is a dependent load the prefetcher can't
+/// predict. It returns the final index.
+fn chase(size: usize, hops: u64) -> usize {
+    let len = (size / size_of::<usize>()).max(1);
+
+    CHASE.with_borrow_mut(|next| {
+        if next.len() != len {
+            // Link a random order of the slots into a cycle
+            let mut order: Vec<usize> = (0..len).collect();
+            order.shuffle(&mut rand::rng());
+
+            next.clear();
+            next.resize(len, 0);
+            for (i, &slot) in order.iter().enumerate() {
+                next[slot] = order[(i + 1) % len];
+            }
+        }
+
+        let mut idx = 0;
+        for _ in 0..hops {
+            idx = next[idx];
+        }
+        idx
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use super::*;
+    use crate::{
+        Failures,
+        sink::{RawSink, StatsSink},
+    };
+
+    #[test]
+    fn response_round_trip() {
+        let mut response = Request::new(42, 7, Work::Echo { bytes: 100 }, true)
+            .with_body(b"body".to_vec())
+            .do_work();
+        response.queue_depth = 12;
+
+        let mut bytes = Vec::new();
+        response.serialize(&mut bytes).unwrap();
+        let response = Response::deserialize(&mut &bytes[..]).unwrap();
+
+        assert_eq!(response.client_send_time, 42);
+        assert_eq!(response.req_id, 7);
+        assert_eq!(response.queue_depth, 12);
+        assert_eq!(response.payload_len, 100);
+        assert_eq!(response.body, b"body");
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn latency_record_keeps_recv_time() {
+        let send_time = get_time();
+        let response = Request::new(send_time, 0, Work::Constant, false).do_work();
+        let before = get_time();
+        let lr = response.to_latency_record(3, 1);
+        let after = get_time();
+        assert_eq!(lr.send_time, send_time);
+        assert!(before <= lr.recv_time && lr.recv_time <= after, "{lr:?}");
+
+        // The time written out, and the latency computed from it, are the recorded ones
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("raw.csv");
+        let mut sink = Box::new(RawSink::new(&path).unwrap());
+        sink.record(&lr);
+        sink.finish(1, Failures::default(), Duration::from_secs(1))
+            .unwrap();
+        let raw = fs::read_to_string(&path).unwrap();
+        let expected = format!(
+            "{},{},{},1",
+            lr.send_time,
+            lr.recv_time,
+            lr.recv_time - lr.send_time
+        );
+        assert_eq!(raw.lines().nth(1), Some(expected.as_str()));
+    }
+
+    #[test]
+    fn oversized_work_is_invalid() {
+        let deserialize = |work: Work| {
+            let mut bytes = Vec::new();
+            work.serialize(&mut bytes).unwrap();
+            Work::deserialize(&mut &bytes[..])
+        };
+
+        assert!(
+            deserialize(Work::Echo {
+                bytes: MAX_ECHO_BYTES
+            })
+            .is_ok()
+        );
+        let err = deserialize(Work::Echo {
+            bytes: MAX_ECHO_BYTES + 1,
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        assert!(deserialize(Work::Matmul { dim: 2048 }).is_ok());
+        for dim in [2049, u64::MAX] {
+            let err = deserialize(Work::Matmul { dim }).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+
+        let chase = |size| Work::MemChase { size, hops: 1 };
+        assert!(deserialize(chase(MAX_CHASE_BYTES)).is_ok());
+        let err = deserialize(chase(MAX_CHASE_BYTES + 1)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
 }
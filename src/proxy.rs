@@ -0,0 +1,151 @@
+use std::{
+    io::{Error, ErrorKind, Read, Result, Write},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use clap::ValueEnum;
+
+/// The signature that starts every v2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The maximum length of a v1 header, including the trailing CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// How long a server waits for the header of a new connection before dropping
+/// it. Proxies send the header as soon as they connect, so a server reading it
+/// on its accept thread is only held up briefly by a client that never does.
+pub const HEADER_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// A version of the PROXY protocol, which load balancers use to pass the
+/// original client address to the server ahead of the proxied data.
+#[derive(Clone, Copy, Debug, ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Version {
+    /// The human-readable header.
+    V1,
+
+    /// The binary header.
+    V2,
+}
+
+/// Writes a PROXY protocol header for a connection from `src` to `dst`.
+pub fn write_header<T: Write>(
+    bytes: &mut T,
+    version: Version,
+    src: SocketAddr,
+    dst: SocketAddr,
+) -> Result<()> {
+    match version {
+        Version::V1 => {
+            let family = match src {
+                SocketAddr::V4(_) => "TCP4",
+                SocketAddr::V6(_) => "TCP6",
+            };
+            write!(
+                bytes,
+                "PROXY {family} {} {} {} {}\r\n",
+                src.ip(),
+                dst.ip(),
+                src.port(),
+                dst.port()
+            )
+        }
+        Version::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+
+            // Version 2, PROXY command
+            header.push(0x21);
+
+            let mut addrs = Vec::new();
+            match (src, dst) {
+                (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+                    // TCP over IPv4
+                    header.push(0x11);
+                    addrs.extend_from_slice(&src.ip().octets());
+                    addrs.extend_from_slice(&dst.ip().octets());
+                }
+                (SocketAddr::V6(src), SocketAddr::V6(dst)) => {
+                    // TCP over IPv6
+                    header.push(0x21);
+                    addrs.extend_from_slice(&src.ip().octets());
+                    addrs.extend_from_slice(&dst.ip().octets());
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "source and destination address families differ",
+                    ));
+                }
+            }
+            addrs.extend_from_slice(&src.port().to_be_bytes());
+            addrs.extend_from_slice(&dst.port().to_be_bytes());
+
+            header.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+            header.extend_from_slice(&addrs);
+            bytes.write_all(&header)
+        }
+    }
+}
+
+/// Reads and discards a v1 or v2 PROXY protocol header. The header is read a
+/// byte at a time (v1) or by its declared length (v2), so nothing after it is
+/// consumed.
+pub fn read_header<T: Read>(bytes: &mut T) -> Result<()> {
+    let mut first = [0u8; 1];
+    bytes.read_exact(&mut first)?;
+
+    match first[0] {
+        b'P' => {
+            let mut header = vec![b'P'];
+            while !header.ends_with(b"\r\n") {
+                if header.len() == V1_MAX_LEN {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "PROXY v1 header is too long",
+                    ));
+                }
+
+                bytes.read_exact(&mut first)?;
+                header.push(first[0]);
+            }
+
+            if !header.starts_with(b"PROXY ") {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "invalid PROXY v1 header",
+                ));
+            }
+
+            Ok(())
+        }
+        b'\r' => {
+            let mut header = [0u8; 16];
+            header[0] = b'\r';
+            bytes.read_exact(&mut header[1..])?;
+
+            if header[..12] != V2_SIGNATURE || header[12] >> 4 != 2 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "invalid PROXY v2 header",
+                ));
+            }
+
+            // Skip the addresses and any TLVs
+            let len = u16::from_be_bytes([header[14], header[15]]) as u64;
+            let skipped = std::io::copy(&mut bytes.take(len), &mut std::io::sink())?;
+            if skipped != len {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "PROXY v2 header is truncated",
+                ));
+            }
+
+            Ok(())
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "connection did not start with a PROXY header",
+        )),
+    }
+}
@@ -0,0 +1,884 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{self, File},
+    io::{BufWriter, Result, Write},
+    path::{Path, PathBuf},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crossbeam_channel::{Sender, unbounded};
+
+use crate::{
+    Failures, StatsOptions, Trial,
+    histogram::Histogram,
+    mix::Mix,
+    protocol::{LatencyRecord, RampRecord, Serialize},
+    write_client_stats, write_histogram_stats, write_stats,
+};
+
+/// A consumer of latency records.
+pub trait StatsSink: Send {
+    /// Records a single latency record as it arrives.
+    fn record(&mut self, lr: &LatencyRecord);
+
+    /// Writes out the collected statistics.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - Number of requests sent.
+    /// * `failures` - Requests that didn't get a response.
+    /// * `runtime` - How long the requests were sent for.
+    fn finish(self: Box<Self>, n: usize, failures: Failures, runtime: Duration) -> Result<()>;
+}
+
+/// Spawns a thread that feeds every latency record sent on the returned
+/// channel through `sinks`, after taking `clock_overhead` nanoseconds off its
+/// latency. The thread returns the sinks once all senders have been dropped.
+pub fn spawn_collector(
+    mut sinks: Vec<Box<dyn StatsSink>>,
+    clock_overhead: u64,
+) -> (Sender<LatencyRecord>, JoinHandle<Vec<Box<dyn StatsSink>>>) {
+    let (tx, rx) = unbounded::<LatencyRecord>();
+
+    let handle = std::thread::spawn(move || {
+        for mut lr in rx {
+            lr.subtract_overhead(clock_overhead);
+            for sink in sinks.iter_mut() {
+                sink.record(&lr);
+            }
+        }
+
+        sinks
+    });
+
+    (tx, handle)
+}
+
+/// Keeps every record, or only the most recent ones of each client, and
+/// writes exact percentiles (`stats.txt` or `stats.json`) and the per-client
+/// breakdown (`clients.txt`).
+pub struct ExactSink {
+    lrs: Vec<LatencyRecord>,
+
+    /// The most recent records of each client, if only the last `keep_last`
+    /// are kept.
+    recent: BTreeMap<usize, VecDeque<LatencyRecord>>,
+
+    /// How many records to keep per client, or `None` to keep every record.
+    keep_last: Option<usize>,
+
+    /// The number of older records dropped to make room for newer ones.
+    discarded: usize,
+
+    /// The work the server did for each request.
+    work: Mix,
+
+    /// The percentiles to report and their format.
+    options: StatsOptions,
+
+    /// The output directory.
+    dir: PathBuf,
+}
+
+impl ExactSink {
+    pub fn new(dir: &Path, work: Mix, options: StatsOptions, keep_last: Option<usize>) -> Self {
+        Self {
+            lrs: Vec::new(),
+            recent: BTreeMap::new(),
+            keep_last,
+            discarded: 0,
+            work,
+            options,
+            dir: dir.to_path_buf(),
+        }
+    }
+}
+
+impl StatsSink for ExactSink {
+    fn record(&mut self, lr: &LatencyRecord) {
+        let Some(keep_last) = self.keep_last else {
+            self.lrs.push(*lr);
+            return;
+        };
+
+        let recent = self
+            .recent
+            .entry(lr.client_id)
+            .or_insert_with(|| VecDeque::with_capacity(keep_last));
+        if recent.len() == keep_last {
+            recent.pop_front();
+            self.discarded += 1;
+        }
+        recent.push_back(*lr);
+    }
+
+    fn finish(mut self: Box<Self>, n: usize, failures: Failures, runtime: Duration) -> Result<()> {
+        if self.keep_last.is_some() {
+            self.lrs = std::mem::take(&mut self.recent)
+                .into_values()
+                .flatten()
+                .collect();
+            println!(
+                "kept the last {} latency records, discarding {} older ones",
+                self.lrs.len(),
+                self.discarded
+            );
+        }
+
+        write_client_stats(&self.lrs, &self.dir.join("clients.txt"))?;
+        let path = self
+            .dir
+            .join(format!("stats.{}", self.options.format.extension()));
+        write_stats(
+            self.lrs,
+            n,
+            self.discarded,
+            failures,
+            runtime,
+            self.work,
+            &self.options,
+            &path,
+        )
+    }
+}
+
+/// Records latencies into a fixed-size histogram, so memory stays constant
+/// regardless of the run length. Percentiles are written to `histogram.txt`
+/// (or `histogram.json`) and the histogram itself to `histogram.bin` so it can
+/// be merged with others.
+pub struct HistogramSink {
+    hist: Histogram,
+
+    /// The work the server did for each request.
+    work: Mix,
+
+    /// The percentiles to report and their format.
+    options: StatsOptions,
+
+    /// The output directory.
+    dir: PathBuf,
+}
+
+impl HistogramSink {
+    pub fn new(dir: &Path, work: Mix, options: StatsOptions) -> Self {
+        Self {
+            hist: Histogram::new(),
+            work,
+            options,
+            dir: dir.to_path_buf(),
+        }
+    }
+}
+
+impl StatsSink for HistogramSink {
+    fn record(&mut self, lr: &LatencyRecord) {
+        self.hist.record(lr.recv_time - lr.send_time);
+    }
+
+    fn finish(self: Box<Self>, n: usize, failures: Failures, runtime: Duration) -> Result<()> {
+        let path = self
+            .dir
+            .join(format!("histogram.{}", self.options.format.extension()));
+        write_histogram_stats(
+            &self.hist,
+            n,
+            failures,
+            runtime,
+            self.work,
+            &self.options,
+            &path,
+        )?;
+
+        let mut file = BufWriter::new(File::create(self.dir.join("histogram.bin"))?);
+        (&self.hist).serialize(&mut file)?;
+        file.flush()
+    }
+}
+
+/// Records latencies into a histogram and sends the run's percentiles and
+/// achieved throughput on a channel, so several trials can be summarized
+/// together.
+pub struct TrialSink {
+    hist: Histogram,
+
+    /// The percentiles to report, in percent.
+    percentiles: Vec<f64>,
+
+    /// Where the results are sent.
+    tx: Sender<Trial>,
+}
+
+impl TrialSink {
+    pub fn new(percentiles: Vec<f64>, tx: Sender<Trial>) -> Self {
+        Self {
+            hist: Histogram::new(),
+            percentiles,
+            tx,
+        }
+    }
+}
+
+impl StatsSink for TrialSink {
+    fn record(&mut self, lr: &LatencyRecord) {
+        self.hist.record(lr.recv_time - lr.send_time);
+    }
+
+    fn finish(self: Box<Self>, _n: usize, _failures: Failures, runtime: Duration) -> Result<()> {
+        let latencies_us = self
+            .percentiles
+            .iter()
+            .map(|p| self.hist.percentile(p / 100.0).unwrap_or(0) as f64 / 1000.0)
+            .collect();
+        let trial = Trial {
+            latencies_us,
+            achieved_rps: self.hist.len() as f64 / runtime.as_secs_f64(),
+        };
+
+        // The receiver outlives every trial
+        self.tx.send(trial).map_err(std::io::Error::other)
+    }
+}
+
+/// Records the server's reported queue depth into a histogram and writes its
+/// 50, 95, and 99th percentiles and maximum (`queue_depth.txt`).
+pub struct QueueDepthSink {
+    hist: Histogram,
+
+    /// The output file.
+    path: PathBuf,
+}
+
+impl QueueDepthSink {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            hist: Histogram::new(),
+            path: dir.join("queue_depth.txt"),
+        }
+    }
+}
+
+impl StatsSink for QueueDepthSink {
+    fn record(&mut self, lr: &LatencyRecord) {
+        self.hist.record(lr.queue_depth as u64);
+    }
+
+    fn finish(self: Box<Self>, _n: usize, _failures: Failures, _runtime: Duration) -> Result<()> {
+        fs::create_dir_all(self.path.parent().expect("file path is missing directory"))?;
+        let mut file = File::create(&self.path)?;
+
+        let percentile = |p| self.hist.percentile(p).unwrap_or(0);
+        writeln!(
+            file,
+            "{}, {}, {}, {}",
+            percentile(0.5),
+            percentile(0.95),
+            percentile(0.99),
+            percentile(1.0)
+        )
+    }
+}
+
+/// Records latencies into a histogram per number of requests in flight when
+/// they were sent, writing each level's share of the requests and percentiles
+/// in microseconds, one level per line (`concurrency.txt`, e.g.
+/// "12: 0.0841, p50: 80.1, p99: 152.3"). The last line is the mean number of
+/// requests in flight, to compare against the throughput times the mean latency
+/// (Little's law).
+pub struct ConcurrencySink {
+    levels: BTreeMap<u64, Histogram>,
+
+    /// The percentiles to report, in percent.
+    percentiles: Vec<f64>,
+
+    /// The output file.
+    path: PathBuf,
+}
+
+impl ConcurrencySink {
+    pub fn new(dir: &Path, percentiles: Vec<f64>) -> Self {
+        Self {
+            levels: BTreeMap::new(),
+            percentiles,
+            path: dir.join("concurrency.txt"),
+        }
+    }
+}
+
+impl StatsSink for ConcurrencySink {
+    fn record(&mut self, lr: &LatencyRecord) {
+        self.levels
+            .entry(lr.inflight)
+            .or_default()
+            .record(lr.recv_time - lr.send_time);
+    }
+
+    fn finish(self: Box<Self>, _n: usize, _failures: Failures, _runtime: Duration) -> Result<()> {
+        fs::create_dir_all(self.path.parent().expect("file path is missing directory"))?;
+        let mut file = File::create(&self.path)?;
+
+        let total = self.levels.values().map(Histogram::len).sum::<u64>();
+        let mut weighted = 0;
+        for (inflight, hist) in &self.levels {
+            weighted += inflight * hist.len();
+            let share = hist.len() as f64 / total as f64;
+            let latencies = self
+                .percentiles
+                .iter()
+                .map(|p| {
+                    let latency = hist.percentile(p / 100.0).unwrap_or(0) as f64 / 1000.0;
+                    format!("p{p}: {latency}")
+                })
+                .collect::<Vec<_>>();
+            writeln!(file, "{inflight}: {share:.4}, {}", latencies.join(", "))?;
+        }
+
+        let mean = if total == 0 {
+            0.0
+        } else {
+            weighted as f64 / total as f64
+        };
+        writeln!(file, "mean: {mean:.3}")
+    }
+}
+
+/// Splits latencies into the time the server spent doing the work and the rest
+/// (queueing and the network), writing the 50, 95, and 99th percentiles of each
+/// in microseconds, one per line (`service_time.txt`).
+pub struct ServiceTimeSink {
+    service: Histogram,
+    queueing: Histogram,
+
+    /// The output file.
+    path: PathBuf,
+}
+
+impl ServiceTimeSink {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            service: Histogram::new(),
+            queueing: Histogram::new(),
+            path: dir.join("service_time.txt"),
+        }
+    }
+}
+
+impl StatsSink for ServiceTimeSink {
+    fn record(&mut self, lr: &LatencyRecord) {
+        let latency = lr.recv_time - lr.send_time;
+        self.service.record(lr.server_process_ns);
+        self.queueing
+            .record(latency.saturating_sub(lr.server_process_ns));
+    }
+
+    fn finish(self: Box<Self>, _n: usize, _failures: Failures, _runtime: Duration) -> Result<()> {
+        fs::create_dir_all(self.path.parent().expect("file path is missing directory"))?;
+        let mut file = File::create(&self.path)?;
+
+        for hist in [&self.service, &self.queueing] {
+            let percentile = |p| hist.percentile(p).unwrap_or(0) as f64 / 1000.0;
+            writeln!(
+                file,
+                "{}, {}, {}",
+                percentile(0.5),
+                percentile(0.95),
+                percentile(0.99)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Summarizes the run for capacity planning: the sustained throughput, the
+/// 99th percentile latency at that throughput, and the headroom to an optional
+/// p99 SLA. The summary is printed and written to `capacity.txt`.
+pub struct CapacitySink {
+    hist: Histogram,
+
+    /// The 99th percentile latency target.
+    sla: Option<Duration>,
+
+    /// The output file.
+    path: PathBuf,
+}
+
+impl CapacitySink {
+    pub fn new(dir: &Path, sla: Option<Duration>) -> Self {
+        Self {
+            hist: Histogram::new(),
+            sla,
+            path: dir.join("capacity.txt"),
+        }
+    }
+}
+
+impl StatsSink for CapacitySink {
+    fn record(&mut self, lr: &LatencyRecord) {
+        self.hist.record(lr.recv_time - lr.send_time);
+    }
+
+    fn finish(self: Box<Self>, _n: usize, _failures: Failures, runtime: Duration) -> Result<()> {
+        let throughput = self.hist.len() as f64 / runtime.as_secs_f64();
+        let p_99 = self.hist.percentile(0.99).unwrap_or(0) as f64 / 1000.0;
+
+        println!("sustained throughput: {throughput:.0} req/s");
+        println!("p99 latency: {p_99} us");
+
+        fs::create_dir_all(self.path.parent().expect("file path is missing directory"))?;
+        let mut file = File::create(&self.path)?;
+
+        match self.sla {
+            Some(sla) => {
+                let sla = sla.as_nanos() as f64 / 1000.0;
+                let headroom = sla - p_99;
+                println!(
+                    "SLA headroom: {headroom} us ({:.1}% of the {sla} us target)",
+                    headroom / sla * 100.0
+                );
+                writeln!(file, "{throughput:.2}, {p_99}, {sla}, {headroom}")
+            }
+            None => writeln!(file, "{throughput:.2}, {p_99}"),
+        }
+    }
+}
+
+/// Streams every record to a CSV file (`raw.csv` by default) as it arrives.
+pub struct RawSink {
+    writer: BufWriter<File>,
+}
+
+impl RawSink {
+    pub fn new(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "send_time,recv_time,latency_ns,inflight")?;
+        Ok(Self { writer })
+    }
+}
+
+impl StatsSink for RawSink {
+    fn record(&mut self, lr: &LatencyRecord) {
+        // Write errors resurface when the writer is flushed in `finish`
+        let _ = writeln!(
+            self.writer,
+            "{},{},{},{}",
+            lr.send_time,
+            lr.recv_time,
+            lr.recv_time - lr.send_time,
+            lr.inflight
+        );
+    }
+
+    fn finish(
+        mut self: Box<Self>,
+        _n: usize,
+        _failures: Failures,
+        _runtime: Duration,
+    ) -> Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Groups records into fixed intervals by their receive time and writes the
+/// throughput and 99th percentile latency of each interval (`timeseries.csv`).
+pub struct TimeseriesSink {
+    /// Latencies of each interval.
+    buckets: BTreeMap<u64, Vec<u64>>,
+
+    /// The time (in nanoseconds) the first interval starts.
+    start: u64,
+
+    /// The length of each interval.
+    interval: Duration,
+
+    /// The output file.
+    path: PathBuf,
+}
+
+impl TimeseriesSink {
+    pub fn new(dir: &Path, start: u64, interval: Duration) -> Self {
+        Self {
+            buckets: BTreeMap::new(),
+            start,
+            interval,
+            path: dir.join("timeseries.csv"),
+        }
+    }
+}
+
+impl StatsSink for TimeseriesSink {
+    fn record(&mut self, lr: &LatencyRecord) {
+        let bucket = lr.recv_time.saturating_sub(self.start) / self.interval.as_nanos() as u64;
+        self.buckets
+            .entry(bucket)
+            .or_default()
+            .push(lr.recv_time - lr.send_time);
+    }
+
+    fn finish(self: Box<Self>, _n: usize, _failures: Failures, _runtime: Duration) -> Result<()> {
+        fs::create_dir_all(self.path.parent().expect("file path is missing directory"))?;
+        let mut file = BufWriter::new(File::create(&self.path)?);
+
+        writeln!(file, "start_ms,throughput,p99")?;
+        for (bucket, mut latencies) in self.buckets {
+            latencies.sort();
+
+            let start_ms = bucket * self.interval.as_millis() as u64;
+            let throughput = latencies.len() as f64 / self.interval.as_secs_f64();
+            let idx = ((latencies.len() as f64 * 0.99) as usize).min(latencies.len() - 1);
+            let p_99 = latencies[idx] as f64 / 1000.0;
+
+            writeln!(file, "{start_ms},{throughput},{p_99}")?;
+        }
+
+        file.flush()
+    }
+}
+
+/// Records the latencies of a ramped closed loop run into a histogram per
+/// number of running clients, writing each level's achieved throughput and
+/// percentiles in microseconds, one level per line (`ramp.txt`, e.g.
+/// "4: 41230.50, p50: 80.1, p99: 152.3").
+pub struct RampSink {
+    levels: BTreeMap<usize, Histogram>,
+
+    /// How long each number of clients ran for.
+    interval: Duration,
+
+    /// The percentiles to report, in percent.
+    percentiles: Vec<f64>,
+
+    /// The output file.
+    path: PathBuf,
+}
+
+impl RampSink {
+    pub fn new(dir: &Path, interval: Duration, percentiles: Vec<f64>) -> Self {
+        Self {
+            levels: BTreeMap::new(),
+            interval,
+            percentiles,
+            path: dir.join("ramp.txt"),
+        }
+    }
+
+    /// Records a single ramp record as it arrives.
+    pub fn record(&mut self, rr: &RampRecord) {
+        self.levels
+            .entry(rr.clients)
+            .or_default()
+            .record(rr.lr.recv_time - rr.lr.send_time);
+    }
+
+    /// Writes out the statistics of every level.
+    pub fn finish(self) -> Result<()> {
+        fs::create_dir_all(self.path.parent().expect("file path is missing directory"))?;
+        let mut file = File::create(&self.path)?;
+
+        for (clients, hist) in &self.levels {
+            let throughput = hist.len() as f64 / self.interval.as_secs_f64();
+            let latencies = self
+                .percentiles
+                .iter()
+                .map(|p| {
+                    let latency = hist.percentile(p / 100.0).unwrap_or(0) as f64 / 1000.0;
+                    format!("p{p}: {latency}")
+                })
+                .collect::<Vec<_>>();
+            writeln!(file, "{clients}: {throughput:.2}, {}", latencies.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Spawns a thread that feeds every ramp record sent on the returned channel
+/// through `sink`, after taking `clock_overhead` nanoseconds off its latency.
+/// The thread returns the sink once all senders have been dropped.
+pub fn spawn_ramp_collector(
+    mut sink: RampSink,
+    clock_overhead: u64,
+) -> (Sender<RampRecord>, JoinHandle<RampSink>) {
+    let (tx, rx) = unbounded::<RampRecord>();
+
+    let handle = std::thread::spawn(move || {
+        for mut rr in rx {
+            rr.lr.subtract_overhead(clock_overhead);
+            sink.record(&rr);
+        }
+
+        sink
+    });
+
+    (tx, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        Format,
+        protocol::{Deserialize, Work},
+    };
+
+    use super::*;
+
+    /// A record of a request sent at `send_ms` milliseconds that took `latency_us` microseconds.
+    fn record(send_ms: u64, latency_us: u64) -> LatencyRecord {
+        LatencyRecord {
+            send_time: send_ms * 1_000_000,
+            recv_time: send_ms * 1_000_000 + latency_us * 1000,
+            client_id: 0,
+            queue_depth: 0,
+            server_process_ns: 0,
+            inflight: 1,
+        }
+    }
+
+    /// Records of 100 requests sent 10 ms apart that took 1 to 100 microseconds.
+    fn records() -> Vec<LatencyRecord> {
+        (1..=100).map(|i| record(i * 10, i)).collect()
+    }
+
+    fn options() -> StatsOptions {
+        StatsOptions {
+            percentiles: vec![50.0, 99.0],
+            format: Format::Text,
+            append: None,
+        }
+    }
+
+    /// Feeds `lrs` through `sink` and finishes a one-second run of 100 requests.
+    fn run(mut sink: Box<dyn StatsSink>, lrs: &[LatencyRecord]) {
+        for lr in lrs {
+            sink.record(lr);
+        }
+        sink.finish(100, Failures::default(), Duration::from_secs(1))
+            .unwrap();
+    }
+
+    fn lines(path: &Path) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn exact() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = ExactSink::new(dir.path(), Work::Constant.into(), options(), None);
+        run(Box::new(sink), &records());
+
+        let stats = lines(&dir.path().join("stats.txt"));
+        assert_eq!(stats[0], "p50: 51, p99: 100");
+        assert_eq!(stats[1], "100.00, 100.00");
+        assert_eq!(stats[2], "0, 0, 0, 0, 0, 0");
+        assert_eq!(stats[3], "50.500, 1, 100, 28.866");
+        assert!(dir.path().join("clients.txt").exists());
+    }
+
+    #[test]
+    fn exact_keeps_last() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = ExactSink::new(dir.path(), Work::Constant.into(), options(), Some(10));
+        run(Box::new(sink), &records());
+
+        // Only the last 10 latencies are kept, but every response counts towards the throughput
+        let stats = lines(&dir.path().join("stats.txt"));
+        assert_eq!(stats[0], "p50: 96, p99: 100");
+        assert_eq!(stats[1], "100.00, 100.00");
+    }
+
+    #[test]
+    fn histogram() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = HistogramSink::new(dir.path(), Work::Constant.into(), options());
+        run(Box::new(sink), &records());
+
+        // Percentiles are within the histogram's relative error of the exact ones
+        let stats = lines(&dir.path().join("histogram.txt"));
+        let percentiles: Vec<f64> = stats[0]
+            .split(", ")
+            .map(|p| p.split_once(": ").unwrap().1.parse().unwrap())
+            .collect();
+        assert!((percentiles[0] - 51.0).abs() < 51.0 / 64.0, "{}", stats[0]);
+        assert!(
+            (percentiles[1] - 100.0).abs() < 100.0 / 64.0,
+            "{}",
+            stats[0]
+        );
+        assert_eq!(stats[1], "100.00, 100.00");
+
+        let mut file = File::open(dir.path().join("histogram.bin")).unwrap();
+        assert_eq!(Histogram::deserialize(&mut file).unwrap().len(), 100);
+    }
+
+    #[test]
+    fn trial() {
+        let (tx, rx) = unbounded();
+        run(Box::new(TrialSink::new(vec![50.0, 99.0], tx)), &records());
+
+        let trial = rx.recv().unwrap();
+        assert_eq!(trial.achieved_rps, 100.0);
+        assert_eq!(trial.latencies_us.len(), 2);
+        assert!((trial.latencies_us[1] - 100.0).abs() < 100.0 / 64.0);
+    }
+
+    #[test]
+    fn queue_depth() {
+        let dir = tempfile::tempdir().unwrap();
+        let lrs: Vec<_> = (0..100)
+            .map(|i| LatencyRecord {
+                queue_depth: i,
+                ..record(0, 1)
+            })
+            .collect();
+        run(Box::new(QueueDepthSink::new(dir.path())), &lrs);
+
+        assert_eq!(
+            lines(&dir.path().join("queue_depth.txt")),
+            ["50, 95, 99, 99"]
+        );
+    }
+
+    #[test]
+    fn concurrency() {
+        let dir = tempfile::tempdir().unwrap();
+        let lrs: Vec<_> = records()
+            .into_iter()
+            .map(|lr| LatencyRecord {
+                inflight: 1 + lr.send_time / 1_000_000 % 20 / 10,
+                ..lr
+            })
+            .collect();
+        run(Box::new(ConcurrencySink::new(dir.path(), vec![50.0])), &lrs);
+
+        // Every other record was sent with two requests in flight
+        let lines = lines(&dir.path().join("concurrency.txt"));
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("1: 0.5000, p50: "), "{}", lines[0]);
+        assert!(lines[1].starts_with("2: 0.5000, p50: "), "{}", lines[1]);
+        assert_eq!(lines[2], "mean: 1.500");
+    }
+
+    #[test]
+    fn service_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let lrs: Vec<_> = (0..100)
+            .map(|_| LatencyRecord {
+                server_process_ns: 30_000,
+                ..record(0, 100)
+            })
+            .collect();
+        run(Box::new(ServiceTimeSink::new(dir.path())), &lrs);
+
+        // The server spent 30 of every 100 microseconds doing the work
+        let lines = lines(&dir.path().join("service_time.txt"));
+        let p50 = |line: &str| line.split(", ").next().unwrap().parse::<f64>().unwrap();
+        assert!((p50(&lines[0]) - 30.0).abs() < 1.0, "{}", lines[0]);
+        assert!((p50(&lines[1]) - 70.0).abs() < 2.0, "{}", lines[1]);
+    }
+
+    #[test]
+    fn capacity() {
+        let dir = tempfile::tempdir().unwrap();
+        let sla = Some(Duration::from_micros(200));
+        run(Box::new(CapacitySink::new(dir.path(), sla)), &records());
+
+        // The throughput, the p99, the SLA, and the headroom to it
+        let lines = lines(&dir.path().join("capacity.txt"));
+        let fields: Vec<f64> = lines[0].split(", ").map(|f| f.parse().unwrap()).collect();
+        assert_eq!(fields.len(), 4);
+        assert_eq!(fields[0], 100.0);
+        assert!((fields[1] - 100.0).abs() < 100.0 / 64.0);
+        assert_eq!(fields[2], 200.0);
+        assert_eq!(fields[3], fields[2] - fields[1]);
+    }
+
+    #[test]
+    fn capacity_without_sla() {
+        let dir = tempfile::tempdir().unwrap();
+        run(Box::new(CapacitySink::new(dir.path(), None)), &records());
+
+        // Only the throughput and the p99, with no target to measure headroom to
+        let lines = lines(&dir.path().join("capacity.txt"));
+        let fields: Vec<f64> = lines[0].split(", ").map(|f| f.parse().unwrap()).collect();
+        assert_eq!(fields.len(), 2);
+        assert_eq!(fields[0], 100.0);
+        assert!((fields[1] - 100.0).abs() < 100.0 / 64.0);
+    }
+
+    #[test]
+    fn raw() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("raw.csv");
+        run(Box::new(RawSink::new(&path).unwrap()), &records()[..2]);
+
+        assert_eq!(
+            lines(&path),
+            [
+                "send_time,recv_time,latency_ns,inflight",
+                "10000000,10001000,1000,1",
+                "20000000,20002000,2000,1",
+            ]
+        );
+    }
+
+    #[test]
+    fn timeseries() {
+        let dir = tempfile::tempdir().unwrap();
+        let sink = TimeseriesSink::new(dir.path(), 0, Duration::from_millis(500));
+        run(Box::new(sink), &records());
+
+        // Responses received in the first half second, and in the second (ending at 1000 ms)
+        let lines = lines(&dir.path().join("timeseries.csv"));
+        assert_eq!(
+            lines,
+            [
+                "start_ms,throughput,p99",
+                "0,98,49",
+                "500,100,99",
+                "1000,2,100"
+            ]
+        );
+    }
+
+    #[test]
+    fn timeseries_windows() {
+        // 6000 responses a millisecond apart, over 6 one-second windows
+        let dir = tempfile::tempdir().unwrap();
+        let sink = TimeseriesSink::new(dir.path(), 0, Duration::from_secs(1));
+        let lrs: Vec<_> = (0..6000).map(|i| record(i, 1)).collect();
+        run(Box::new(sink), &lrs);
+
+        let lines = lines(&dir.path().join("timeseries.csv"));
+        assert_eq!(lines.len(), 7);
+        for (i, line) in lines[1..].iter().enumerate() {
+            let fields: Vec<_> = line.split(',').collect();
+            assert_eq!(fields[0], (i * 1000).to_string());
+            assert_eq!(fields[1], "1000");
+        }
+    }
+
+    #[test]
+    fn ramp() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sink = RampSink::new(dir.path(), Duration::from_secs(1), vec![50.0]);
+        for (i, lr) in records().into_iter().enumerate() {
+            sink.record(&RampRecord {
+                lr,
+                clients: 1 + i / 50,
+            });
+        }
+        sink.finish().unwrap();
+
+        let lines = lines(&dir.path().join("ramp.txt"));
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("1: 50.00, p50: "), "{}", lines[0]);
+        assert!(lines[1].starts_with("2: 50.00, p50: "), "{}", lines[1]);
+    }
+}
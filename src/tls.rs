@@ -0,0 +1,177 @@
+use std::{
+    io::{Error, ErrorKind, Read, Result, Write},
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+use rustls::{
+    ClientConfig, ConnectionCommon, DigitallySignedStruct, HandshakeKind, ServerConfig, SideData,
+    SignatureScheme, StreamOwned,
+    client::{
+        Resumption,
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    },
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+};
+
+/// Builds a server configuration with a freshly generated self-signed
+/// certificate.
+pub fn server_config() -> Result<Arc<ServerConfig>> {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+        .map_err(|e| Error::other(e.to_string()))?;
+    let key = PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der());
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![certified.cert.der().clone()],
+            PrivateKeyDer::Pkcs8(key),
+        )
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+    Ok(Arc::new(config))
+}
+
+/// Builds a client configuration that accepts any server certificate, since
+/// servers use self-signed ones. It offers only TLS 1.2 if `tls12_only` is
+/// set. Sessions are cached in memory, so later connections to the same server
+/// resume them.
+pub fn client_config(tls12_only: bool) -> Arc<ClientConfig> {
+    let builder = if tls12_only {
+        ClientConfig::builder_with_protocol_versions(&[&rustls::version::TLS12])
+    } else {
+        ClientConfig::builder()
+    };
+    let config = builder
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(NoVerification))
+        .with_no_client_auth();
+
+    Arc::new(config)
+}
+
+/// Copies `config` with session resumption disabled, so every connection
+/// does a full handshake.
+pub fn without_resumption(config: &ClientConfig) -> Arc<ClientConfig> {
+    let mut config = config.clone();
+    config.resumption = Resumption::disabled();
+    Arc::new(config)
+}
+
+/// Accepts every certificate and signature.
+#[derive(Debug)]
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        _message: &[u8],
+        _cert: &CertificateDer<'_>,
+        _dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        Ok(HandshakeSignatureValid::assertion())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        rustls::crypto::CryptoProvider::get_default()
+            .map(|provider| {
+                provider
+                    .signature_verification_algorithms
+                    .supported_schemes()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// A TLS connection over `T`. Writes are buffered until the stream is flushed,
+/// so a message serialized in several pieces is sent as a single record.
+pub struct TlsStream<C, T: Read + Write> {
+    inner: StreamOwned<C, T>,
+
+    /// Plaintext written since the last flush.
+    buf: Vec<u8>,
+}
+
+impl<C, T, S> TlsStream<C, T>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    T: Read + Write,
+    S: SideData,
+{
+    pub fn new(conn: C, sock: T) -> Self {
+        Self {
+            inner: StreamOwned::new(conn, sock),
+            buf: Vec::new(),
+        }
+    }
+
+    /// Gets a reference to the underlying transport.
+    pub fn get_ref(&self) -> &T {
+        &self.inner.sock
+    }
+
+    /// Gets a mutable reference to the underlying transport.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner.sock
+    }
+
+    /// Completes the handshake now instead of on the first read or write. It
+    /// returns which kind of handshake it was, e.g. whether the session was
+    /// resumed.
+    pub fn handshake(&mut self) -> Result<Option<HandshakeKind>> {
+        let StreamOwned { conn, sock } = &mut self.inner;
+        while conn.is_handshaking() {
+            conn.complete_io(sock)?;
+        }
+        Ok(conn.handshake_kind())
+    }
+}
+
+impl<C, T, S> Read for TlsStream<C, T>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    T: Read + Write,
+    S: SideData,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<C, T, S> Write for TlsStream<C, T>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    T: Read + Write,
+    S: SideData,
+{
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.write_all(&self.buf)?;
+        self.buf.clear();
+        self.inner.flush()
+    }
+}
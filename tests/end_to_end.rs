@@ -0,0 +1,875 @@
+//! Runs the servers and the client's request generators against each other in-process, covering
+//! a request's whole trip: serializing it, doing its work, and deserializing the response.
+//!
+//! The server and client are binaries, so their modules are compiled into this test directly.
+//! Only part of each is used here.
+#![allow(dead_code)]
+
+#[path = "../src/bin/server/connection.rs"]
+mod connection;
+#[path = "../src/bin/server/epoll.rs"]
+mod epoll;
+#[path = "../src/bin/server/handler.rs"]
+mod handler;
+#[path = "../src/bin/server/metrics.rs"]
+mod metrics;
+#[path = "../src/bin/server/threadpool.rs"]
+mod threadpool;
+
+#[path = "../src/bin/client/closed_loop.rs"]
+mod closed_loop;
+#[path = "../src/bin/client/counters.rs"]
+mod counters;
+#[path = "../src/bin/client/open_loop.rs"]
+mod open_loop;
+#[path = "../src/bin/client/partial_open_loop.rs"]
+mod partial_open_loop;
+#[path = "../src/bin/client/rate.rs"]
+mod rate;
+#[path = "../src/bin/client/split.rs"]
+mod split;
+#[path = "../src/bin/client/stream.rs"]
+mod stream;
+#[path = "../src/bin/client/timestamp.rs"]
+mod timestamp;
+
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{Receiver, unbounded};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use rust_server_benchmarks::{
+    buffers::BufferSizes,
+    framing, get_time,
+    protocol::{
+        Deserialize, MAX_ECHO_BYTES, REQUEST_SIZE, RESPONSE_SIZE, Request, Response, Serialize,
+        Work,
+    },
+    proxy, tls,
+};
+
+use counters::Counters;
+use epoll::AcceptSharding;
+use handler::DoWork;
+use metrics::Metrics;
+use rate::Arrival;
+use socket2::SockRef;
+use stream::{Retry, Transport};
+use threadpool::WhenFull;
+
+/// Gets a port nothing is listening on. Another process could take it before the server binds
+/// it, but that's unlikely on a test machine.
+fn free_port() -> u16 {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// The number of `error!` records logged by any test so far.
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+/// A logger that only counts errors, for tests that check something isn't logged as one.
+struct ErrorCounter;
+
+impl Log for ErrorCounter {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() == Level::Error
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            ERRORS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Gets the number of `error!` records logged so far, installing the counting logger on the
+/// first call. No other test logs errors, so a test can compare the counts before and after it.
+fn errors_logged() -> usize {
+    if log::set_logger(&ErrorCounter).is_ok() {
+        log::set_max_level(LevelFilter::Error);
+    }
+    ERRORS.load(Ordering::SeqCst)
+}
+
+/// A server running in the background until it's stopped.
+struct Server {
+    addr: SocketAddr,
+    shutdown: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl Server {
+    /// Runs `run` in the background, passing it the flag that stops the server.
+    fn start(addr: SocketAddr, run: impl FnOnce(Arc<AtomicBool>) + Send + 'static) -> Self {
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || run(shutdown))
+        };
+        Self {
+            addr,
+            shutdown,
+            handle,
+        }
+    }
+
+    /// Runs a threadpool server with `cfg`, doing the work requests ask for.
+    fn threadpool(cfg: threadpool::Config) -> Self {
+        Self::start(cfg.addr, move |shutdown| {
+            cfg.run(shutdown, Arc::new(Metrics::new(false)), Arc::new(DoWork))
+        })
+    }
+
+    /// Runs an epoll server with `cfg`, doing the work requests ask for.
+    fn epoll(cfg: epoll::Config) -> Self {
+        Self::start(cfg.addr, move |shutdown| {
+            cfg.run(shutdown, Arc::new(Metrics::new(false)), Arc::new(DoWork))
+        })
+    }
+
+    /// Stops the server, waking up its accept loop so it sees the shutdown.
+    fn stop(self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        drop(TcpStream::connect(self.addr));
+        self.handle.join().unwrap();
+    }
+}
+
+/// Connects to `addr`, retrying until the server is listening.
+fn connect(addr: SocketAddr) -> TcpStream {
+    for _ in 0..100 {
+        if let Ok(stream) = TcpStream::connect(addr) {
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            return stream;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("the server never listened at {addr}");
+}
+
+/// Sends request `req_id` of constant work on `stream` and reads its response.
+fn exchange(stream: &mut TcpStream, req_id: u64) -> io::Result<Response> {
+    Request::new(get_time(), req_id, Work::Constant, true).serialize(&mut *stream)?;
+    Response::deserialize(stream)
+}
+
+/// Checks that the server closed `stream`, reading nothing more from it.
+fn is_closed(stream: &mut TcpStream) -> bool {
+    match stream.read(&mut [0; 1]) {
+        Ok(n) => n == 0,
+        Err(e) => framing::is_disconnect(&e),
+    }
+}
+
+/// A server that accepts connections and reads their requests but never answers them. It
+/// runs until the test ends.
+fn stalled_server() -> SocketAddr {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || io::copy(&mut stream, &mut io::sink()));
+        }
+    });
+    addr
+}
+
+/// A server that closes every connection once it has read part of its first request. It runs
+/// until the test ends.
+fn closing_server() -> SocketAddr {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let _ = stream.unwrap().read_exact(&mut [0; 4]);
+        }
+    });
+    addr
+}
+
+/// Relays connections to `target`, holding the `i`th back for `i` times `stagger` before
+/// connecting it. It runs until the test ends, and returns its address and the time (as read by
+/// `get_time`) the last connection so far was relayed.
+fn staggered_relay(target: SocketAddr, stagger: Duration) -> (SocketAddr, Arc<AtomicU64>) {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let relayed = Arc::new(AtomicU64::new(0));
+    {
+        let relayed = relayed.clone();
+        thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut client = stream.unwrap();
+                let relayed = relayed.clone();
+                thread::spawn(move || {
+                    thread::sleep(stagger * i as u32);
+                    let mut server = connect(target);
+                    relayed.fetch_max(get_time(), Ordering::SeqCst);
+
+                    let (mut client_rx, mut server_tx) = (client.try_clone()?, server.try_clone()?);
+                    thread::spawn(move || io::copy(&mut client_rx, &mut server_tx));
+                    io::copy(&mut server, &mut client)
+                });
+            }
+        });
+    }
+    (addr, relayed)
+}
+
+/// A threadpool server on a free port with two threads and no limits.
+fn threadpool_config() -> threadpool::Config {
+    threadpool::Config {
+        addr: SocketAddr::from((Ipv4Addr::LOCALHOST, free_port())),
+        buffers: BufferSizes::default(),
+        tp_size: 2,
+        acceptors: 1,
+        idle_timeout: None,
+        request_deadline: None,
+        accept_proxy_protocol: false,
+        nagle: false,
+        tls: None,
+        queue_depth: None,
+        when_full: WhenFull::Block,
+    }
+}
+
+/// An epoll server on a free port with two threads and no limits, doing maintenance every 10 ms.
+fn epoll_config() -> epoll::Config {
+    epoll::Config {
+        addr: SocketAddr::from((Ipv4Addr::LOCALHOST, free_port())),
+        buffers: BufferSizes::default(),
+        n_threads: 2,
+        capacity: 16,
+        max_events: 16,
+        read_chunk_size: None,
+        wait_timeout: Duration::from_millis(10),
+        idle_timeout: None,
+        request_deadline: None,
+        accept_proxy_protocol: false,
+        nagle: false,
+        pin_threads: false,
+        edge_triggered: false,
+        sharding: AcceptSharding::Shared,
+    }
+}
+
+/// A closed loop of two clients sending 200 requests of constant work to `addr`. The clients
+/// retry until the server is listening.
+fn closed_loop_config(addr: SocketAddr) -> closed_loop::Config {
+    closed_loop::Config {
+        addr,
+        runtime: Duration::from_secs(10),
+        warmup: Duration::ZERO,
+        work: Work::Constant.into(),
+        num_clients: 2,
+        throughput_only: false,
+        kernel_timestamps: false,
+        warmup_requests: 0,
+        request_timeout: Some(Duration::from_secs(5)),
+        proxy_protocol: None,
+        tls: None,
+        transport: Transport::Tcp,
+        uds_path: PathBuf::new(),
+        connections_per_client: 1,
+        pipeline_depth: 1,
+        retry: Retry {
+            retries: 10,
+            backoff: Duration::from_millis(10),
+        },
+        seed: 0,
+        nagle: false,
+        buffers: BufferSizes::default(),
+        verify_checksums: true,
+        payload: Arc::from([]),
+        total_requests: Some(200),
+        ramp: None,
+    }
+}
+
+/// An open loop of one client sending 200 requests of constant work to `addr` as fast as it
+/// can. The client retries until the server is listening.
+fn open_loop_config(addr: SocketAddr) -> open_loop::Config {
+    open_loop::Config {
+        addr,
+        runtime: Duration::from_secs(10),
+        warmup: Duration::ZERO,
+        delay: Duration::ZERO,
+        arrival: Arrival::Fixed,
+        target_rate: false,
+        seed: 0,
+        work: Work::Constant.into(),
+        num_clients: 1,
+        max_inflight_bytes: None,
+        max_inflight: None,
+        throughput_only: false,
+        correct_co: false,
+        request_timeout: Some(Duration::from_secs(5)),
+        proxy_protocol: None,
+        transport: Transport::Tcp,
+        uds_path: PathBuf::new(),
+        retry: Retry {
+            retries: 10,
+            backoff: Duration::from_millis(10),
+        },
+        nagle: false,
+        buffers: BufferSizes::default(),
+        verify_checksums: true,
+        payload: Arc::from([]),
+        total_requests: Some(200),
+        liveness_window: None,
+    }
+}
+
+/// A partial open loop sending a batch of 10 requests of constant work to `addr` every
+/// millisecond for 200 ms, from at most two threads that give up on the server after 300 ms.
+fn partial_open_loop_config(addr: SocketAddr) -> partial_open_loop::Config {
+    partial_open_loop::Config {
+        addr,
+        runtime: Duration::from_millis(200),
+        warmup: Duration::ZERO,
+        delay: Duration::from_millis(1),
+        arrival: Arrival::Fixed,
+        seed: 0,
+        work: Work::Constant.into(),
+        max_threads: 2,
+        num_requests: 10,
+        timeout: Duration::from_millis(300),
+        throughput_only: false,
+        proxy_protocol: None,
+        tls: None,
+        tls_without_resumption: None,
+        keep_alive: false,
+        retry: Retry {
+            retries: 10,
+            backoff: Duration::from_millis(10),
+        },
+        nagle: false,
+        buffers: BufferSizes::default(),
+        verify_checksums: true,
+        payload: Arc::from([]),
+    }
+}
+
+#[test]
+fn closed_loop_against_threadpool() {
+    let server = Server::threadpool(threadpool_config());
+
+    let counters = Arc::new(Counters::default());
+    let (tx, rx) = unbounded();
+    let sent = closed_loop_config(server.addr).run(tx, &counters);
+    let records = rx.iter().collect::<Vec<_>>();
+    server.stop();
+
+    assert_eq!(sent, 200);
+    assert_eq!(records.len(), sent);
+    assert_eq!(counters.timeouts.load(Ordering::SeqCst), 0);
+    assert_eq!(counters.corrupted.load(Ordering::SeqCst), 0);
+
+    for record in &records {
+        assert!(record.recv_time >= record.send_time, "{record:?}");
+        assert!(record.client_id < 2, "{record:?}");
+
+        // Constant work is answered right away, so over loopback even a slow test machine
+        // answers well within a second, and the server's time is part of the round trip
+        let latency = record.recv_time - record.send_time;
+        assert!(latency < 1_000_000_000, "{record:?}");
+        assert!(record.server_process_ns <= latency, "{record:?}");
+    }
+}
+
+#[test]
+fn max_inflight_bytes_throttles_open_loop() {
+    let server = Server::threadpool(threadpool_config());
+
+    // Every exchange carries a 2 KiB body both ways and a 16 KiB payload back, and the limit
+    // leaves room for three of them
+    let payload = vec![7; 2048];
+    let exchange = (REQUEST_SIZE + RESPONSE_SIZE + 2 * payload.len() + 16 * 1024) as u64;
+    let cfg = open_loop::Config {
+        work: Work::Echo { bytes: 16 * 1024 }.into(),
+        payload: payload.into(),
+        max_inflight_bytes: Some(3 * exchange),
+        ..open_loop_config(server.addr)
+    };
+
+    let counters = Arc::new(Counters::default());
+    let (tx, rx) = unbounded();
+    let sent = cfg.run(tx, &counters);
+    let records = rx.iter().collect::<Vec<_>>();
+    server.stop();
+
+    // Without the limit, the sender gets further ahead of the server
+    assert_eq!(sent, 200);
+    assert_eq!(records.len(), sent);
+    for record in &records {
+        assert!(record.inflight <= 3, "{record:?}");
+    }
+}
+
+#[test]
+fn partial_open_loop_stops_against_stalled_server() {
+    let cfg = partial_open_loop_config(stalled_server());
+
+    let counters = Arc::new(Counters::default());
+    let (tx, rx) = unbounded();
+    let start = Instant::now();
+    cfg.run(tx, &counters);
+
+    // Every thread stops once its batch times out, rather than waiting on the server forever
+    let elapsed = start.elapsed();
+    assert!(elapsed < Duration::from_secs(2), "took {elapsed:?}");
+    assert_eq!(rx.iter().count(), 0);
+    assert_eq!(
+        counters.timeouts.load(Ordering::SeqCst),
+        counters.threads.load(Ordering::SeqCst)
+    );
+}
+
+#[test]
+fn closed_loop_connection_count() {
+    // The threadpool server serves a connection per thread
+    let server = Server::threadpool(threadpool::Config {
+        tp_size: 4,
+        ..threadpool_config()
+    });
+
+    let cfg = closed_loop::Config {
+        connections_per_client: 2,
+        ..closed_loop_config(server.addr)
+    };
+    let counters = Arc::new(Counters::default());
+    let (tx, rx) = unbounded();
+    let sent = cfg.run(tx, &counters);
+    assert_eq!(rx.iter().count(), sent);
+    server.stop();
+
+    // Every client opens all its connections up front and keeps them for the whole run
+    assert_eq!(counters.connections.load(Ordering::SeqCst), 4);
+    assert_eq!(counters.peak_connections.load(Ordering::SeqCst), 4);
+    assert_eq!(counters.open_connections.load(Ordering::SeqCst), 0);
+    assert_eq!(counters.threads.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn recording_waits_for_staggered_connections() {
+    let server = Server::threadpool(threadpool_config());
+
+    // The second client's connection only reaches the server 100 ms after the first's
+    let (addr, relayed) = staggered_relay(server.addr, Duration::from_millis(100));
+    let cfg = closed_loop::Config {
+        warmup_requests: 1,
+        ..closed_loop_config(addr)
+    };
+    let counters = Arc::new(Counters::default());
+    let (tx, rx) = unbounded();
+    let sent = cfg.run(tx, &counters);
+    let records = rx.iter().collect::<Vec<_>>();
+    server.stop();
+
+    // No request is recorded until both connections are established and warmed up
+    assert_eq!(records.len(), sent);
+    let established = relayed.load(Ordering::SeqCst);
+    for record in &records {
+        assert!(record.send_time > established, "{record:?}");
+    }
+}
+
+#[test]
+fn closed_mid_request_is_counted_and_truncates() {
+    let cfg = closed_loop::Config {
+        num_clients: 1,
+        ..closed_loop_config(closing_server())
+    };
+    let counters = Arc::new(Counters::default());
+    let (tx, rx) = unbounded();
+    cfg.run(tx, &counters);
+
+    // The request the server closed the connection under failed, cutting the run short
+    assert_eq!(rx.iter().count(), 0);
+    assert_eq!(counters.errors.load(Ordering::SeqCst), 1);
+    assert!(counters.truncated.load(Ordering::SeqCst));
+}
+
+#[test]
+fn threadpool_survives_clients_disconnecting_mid_response() {
+    // A single thread, so a connection that took it down would leave the next one unserved
+    let server = Server::threadpool(threadpool::Config {
+        tp_size: 1,
+        ..threadpool_config()
+    });
+
+    let errors = errors_logged();
+
+    // Each client waits for the start of a response far larger than the socket buffers, then
+    // resets the connection (closing with a zero linger sends an RST), so the server is still
+    // writing the rest when the reset arrives
+    for req_id in 0..20 {
+        let mut stream = connect(server.addr);
+        Request::new(get_time(), req_id, Work::Echo { bytes: 8 << 20 }, true)
+            .serialize(&mut stream)
+            .unwrap();
+        stream.read_exact(&mut [0; RESPONSE_SIZE]).unwrap();
+        SockRef::from(&stream)
+            .set_linger(Some(Duration::ZERO))
+            .unwrap();
+    }
+
+    let mut stream = connect(server.addr);
+    assert_eq!(exchange(&mut stream, 0).unwrap().req_id, 0);
+    drop(stream);
+    server.stop();
+
+    // Resets are ordinary disconnects, not errors
+    assert_eq!(errors_logged(), errors);
+}
+
+#[test]
+fn throughput_only_counts_without_records() {
+    let server = Server::threadpool(threadpool_config());
+
+    // Both generators count every response but send no latency records
+    let check = |sent: usize, counters: &Counters, rx: Receiver<_>| {
+        assert_eq!(sent, 200);
+        assert_eq!(counters.completed.load(Ordering::SeqCst), sent);
+        assert_eq!(rx.iter().count(), 0);
+    };
+
+    let cfg = closed_loop::Config {
+        throughput_only: true,
+        ..closed_loop_config(server.addr)
+    };
+    let counters = Arc::new(Counters::default());
+    let (tx, rx) = unbounded();
+    check(cfg.run(tx, &counters), &counters, rx);
+
+    let cfg = open_loop::Config {
+        throughput_only: true,
+        ..open_loop_config(server.addr)
+    };
+    let counters = Arc::new(Counters::default());
+    let (tx, rx) = unbounded();
+    check(cfg.run(tx, &counters), &counters, rx);
+
+    server.stop();
+}
+
+#[test]
+fn epoll_reads_one_byte_at_a_time() {
+    let server = Server::epoll(epoll::Config {
+        read_chunk_size: Some(1),
+        ..epoll_config()
+    });
+
+    // Requests with a body take many reads each, and are still answered intact
+    let cfg = closed_loop::Config {
+        payload: Arc::from(&b"every byte read on its own"[..]),
+        ..closed_loop_config(server.addr)
+    };
+    let counters = Arc::new(Counters::default());
+    let (tx, rx) = unbounded();
+    let sent = cfg.run(tx, &counters);
+    let records = rx.iter().count();
+    server.stop();
+
+    assert_eq!(sent, 200);
+    assert_eq!(records, sent);
+    assert_eq!(counters.corrupted.load(Ordering::SeqCst), 0);
+    assert_eq!(counters.errors.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn epoll_survives_malformed_requests() {
+    let server = Server::epoll(epoll_config());
+
+    // A frame whose body is too short to be a request closes its connection
+    let mut stream = connect(server.addr);
+    framing::write_header(&mut stream, 3).unwrap();
+    stream.write_all(b"abc").unwrap();
+    assert!(is_closed(&mut stream));
+
+    // So does a request for an echo too large to buffer
+    let mut stream = connect(server.addr);
+    let work = Work::Echo {
+        bytes: MAX_ECHO_BYTES + 1,
+    };
+    Request::new(get_time(), 0, work, true)
+        .serialize(&mut stream)
+        .unwrap();
+    assert!(is_closed(&mut stream));
+
+    // The thread that served them keeps serving
+    for _ in 0..4 {
+        let mut stream = connect(server.addr);
+        assert_eq!(exchange(&mut stream, 0).unwrap().req_id, 0);
+    }
+    server.stop();
+}
+
+#[test]
+fn epoll_shuts_down_on_wait_timeout() {
+    let server = Server::epoll(epoll_config());
+    let mut stream = connect(server.addr);
+    exchange(&mut stream, 0).unwrap();
+
+    // Nothing wakes the epoll thread up, so it only sees the shutdown once its wait times out,
+    // and then closes the connection that's waiting for its next request
+    let start = Instant::now();
+    server.shutdown.store(true, Ordering::SeqCst);
+    assert!(is_closed(&mut stream));
+    let elapsed = start.elapsed();
+    assert!(elapsed < Duration::from_secs(1), "took {elapsed:?}");
+
+    server.stop();
+}
+
+/// Checks that the server behind `addr` closes a connection once it's been idle for about
+/// `timeout` after a request.
+fn assert_reaped(addr: SocketAddr, timeout: Duration) {
+    let mut stream = connect(addr);
+    exchange(&mut stream, 0).unwrap();
+
+    let start = Instant::now();
+    assert!(is_closed(&mut stream));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= timeout / 2, "closed after {elapsed:?}");
+    assert!(
+        elapsed < timeout + Duration::from_secs(1),
+        "closed after {elapsed:?}"
+    );
+}
+
+#[test]
+fn idle_connections_are_reaped() {
+    let timeout = Duration::from_millis(200);
+
+    let server = Server::epoll(epoll::Config {
+        idle_timeout: Some(timeout),
+        ..epoll_config()
+    });
+    assert_reaped(server.addr, timeout);
+    server.stop();
+
+    let server = Server::threadpool(threadpool::Config {
+        idle_timeout: Some(timeout),
+        ..threadpool_config()
+    });
+    assert_reaped(server.addr, timeout);
+    server.stop();
+}
+
+/// Checks that the server behind `addr` drops a connection that sends a request a byte every
+/// 50 ms, taking longer than `deadline` to finish it.
+fn assert_slow_request_dropped(addr: SocketAddr, deadline: Duration) {
+    let mut request = Vec::new();
+    Request::new(get_time(), 0, Work::Constant, true)
+        .serialize(&mut request)
+        .unwrap();
+    assert!(Duration::from_millis(50) * request.len() as u32 > 2 * deadline);
+
+    // Writing fails soon after the server closes the connection
+    let mut stream = connect(addr);
+    let mut sent = 0;
+    for byte in &request {
+        if stream.write_all(&[*byte]).is_err() {
+            break;
+        }
+        sent += 1;
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    assert!(sent < request.len(), "sent the whole request");
+    assert!(is_closed(&mut stream));
+}
+
+#[test]
+fn slow_requests_are_dropped() {
+    let deadline = Duration::from_millis(300);
+
+    let server = Server::epoll(epoll::Config {
+        request_deadline: Some(deadline),
+        ..epoll_config()
+    });
+    assert_slow_request_dropped(server.addr, deadline);
+    server.stop();
+
+    let server = Server::threadpool(threadpool::Config {
+        request_deadline: Some(deadline),
+        ..threadpool_config()
+    });
+    assert_slow_request_dropped(server.addr, deadline);
+    server.stop();
+}
+
+#[test]
+fn threadpool_reports_queue_depth() {
+    // With a single thread, connections queue up while another one is being served
+    let server = Server::threadpool(threadpool::Config {
+        tp_size: 1,
+        ..threadpool_config()
+    });
+    let mut served = connect(server.addr);
+    assert_eq!(exchange(&mut served, 0).unwrap().queue_depth, 0);
+
+    let queued = [connect(server.addr), connect(server.addr)];
+    let mut depth = 0;
+    for req_id in 1..100 {
+        depth = exchange(&mut served, req_id).unwrap().queue_depth;
+        if depth == 2 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(depth, 2);
+
+    drop((served, queued));
+    server.stop();
+}
+
+#[test]
+fn open_loop_idle_timeouts_are_not_failures() {
+    let server = Server::threadpool(threadpool_config());
+
+    // Requests are sent further apart than the timeout, so the receiver waits longer than it
+    // between them with nothing outstanding. The timeout leaves responses room to be slow while
+    // other tests run.
+    let cfg = open_loop::Config {
+        delay: Duration::from_millis(200),
+        request_timeout: Some(Duration::from_millis(100)),
+        total_requests: Some(5),
+        ..open_loop_config(server.addr)
+    };
+    let counters = Arc::new(Counters::default());
+    let (tx, rx) = unbounded();
+    let sent = cfg.run(tx, &counters);
+    assert_eq!(rx.iter().count(), sent);
+    server.stop();
+
+    assert_eq!(sent, 5);
+    assert_eq!(counters.timeouts.load(Ordering::SeqCst), 0);
+}
+
+#[test]
+fn open_loop_fractional_delay() {
+    let server = Server::threadpool(threadpool_config());
+
+    // Correcting for coordinated omission, requests are timed from when they were scheduled,
+    // 1.5 us apart
+    let cfg = open_loop::Config {
+        delay: rate::from_micros(1.5),
+        correct_co: true,
+        ..open_loop_config(server.addr)
+    };
+    let counters = Arc::new(Counters::default());
+    let (tx, rx) = unbounded();
+    let sent = cfg.run(tx, &counters);
+    let records = rx.iter().collect::<Vec<_>>();
+    server.stop();
+
+    assert_eq!(records.len(), sent);
+    for pair in records.windows(2) {
+        assert_eq!(pair[1].send_time - pair[0].send_time, 1500, "{pair:?}");
+    }
+}
+
+#[test]
+fn resumed_tls_handshakes_are_faster() {
+    let server = Server::threadpool(threadpool::Config {
+        tls: Some(tls::server_config().unwrap()),
+        ..threadpool_config()
+    });
+
+    // A single thread opens a connection for one request every 5 ms, alternating between full
+    // and resumed handshakes. TLS 1.2 resumption skips the key exchange, so it's clearly faster.
+    let tls = tls::client_config(true);
+    let cfg = partial_open_loop::Config {
+        runtime: Duration::from_millis(500),
+        delay: Duration::from_millis(5),
+        max_threads: 1,
+        num_requests: 1,
+        timeout: Duration::from_secs(5),
+        tls_without_resumption: Some(tls::without_resumption(&tls)),
+        tls: Some(tls),
+        ..partial_open_loop_config(server.addr)
+    };
+    let counters = Arc::new(Counters::default());
+    let (tx, rx) = unbounded();
+    let sent = cfg.run(tx, &counters);
+    assert_eq!(rx.iter().count(), sent);
+    server.stop();
+
+    // Only the first connection with resumption enabled has no session to resume
+    let full = counters.full_handshakes.lock().unwrap();
+    let resumed = counters.resumed_handshakes.lock().unwrap();
+    let connections = counters.connections.load(Ordering::SeqCst) as u64;
+    assert_eq!(full.len() + resumed.len(), connections);
+    assert_eq!(resumed.len(), connections / 2 - 1);
+
+    let (full_50, resumed_50) = (
+        full.percentile(0.5).unwrap(),
+        resumed.percentile(0.5).unwrap(),
+    );
+    assert!(
+        resumed_50 < full_50 / 2,
+        "resumed in {resumed_50} ns, full in {full_50} ns"
+    );
+}
+
+/// Checks that the server at `addr` strips a v1 PROXY header sent ahead of the first request.
+fn assert_proxy_header_stripped(addr: SocketAddr) {
+    let mut stream = connect(addr);
+    let (src, dst) = (stream.local_addr().unwrap(), stream.peer_addr().unwrap());
+    proxy::write_header(&mut stream, proxy::Version::V1, src, dst).unwrap();
+    for req_id in 0..3 {
+        assert_eq!(exchange(&mut stream, req_id).unwrap().req_id, req_id);
+    }
+}
+
+#[test]
+fn proxy_headers_are_stripped() {
+    let server = Server::threadpool(threadpool::Config {
+        accept_proxy_protocol: true,
+        ..threadpool_config()
+    });
+    assert_proxy_header_stripped(server.addr);
+    server.stop();
+
+    let server = Server::epoll(epoll::Config {
+        accept_proxy_protocol: true,
+        ..epoll_config()
+    });
+    assert_proxy_header_stripped(server.addr);
+    server.stop();
+}
+
+#[test]
+fn epoll_drops_connections_without_proxy_header() {
+    let server = Server::epoll(epoll::Config {
+        accept_proxy_protocol: true,
+        ..epoll_config()
+    });
+
+    // A client that never sends its header doesn't hold up the next connection for long
+    let mut silent = connect(server.addr);
+    let start = Instant::now();
+    assert_proxy_header_stripped(server.addr);
+    let elapsed = start.elapsed();
+    assert!(elapsed < Duration::from_secs(1), "took {elapsed:?}");
+    assert!(is_closed(&mut silent));
+    server.stop();
+}